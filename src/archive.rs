@@ -0,0 +1,333 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    os::unix::fs::PermissionsExt,
+    path::{Component, Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Deserialize, Serialize)]
+pub enum ArchiveFormat {
+    #[serde(rename = "tar")]
+    Tar,
+    #[serde(rename = "tar.gz", alias = "tgz")]
+    TarGz,
+    #[serde(rename = "tar.xz", alias = "txz")]
+    TarXz,
+    #[serde(rename = "tar.zst", alias = "tzst")]
+    TarZst,
+    #[serde(rename = "zip")]
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Guess the archive format from the extension of a file name or URL
+    pub fn detect(file_name: &str) -> Option<Self> {
+        let file_name = file_name.to_lowercase();
+
+        [
+            (".tar.gz", Self::TarGz),
+            (".tgz", Self::TarGz),
+            (".tar.xz", Self::TarXz),
+            (".txz", Self::TarXz),
+            (".tar.zst", Self::TarZst),
+            (".tzst", Self::TarZst),
+            (".tar", Self::Tar),
+            (".zip", Self::Zip),
+        ]
+        .into_iter()
+        .find(|(ext, _)| file_name.ends_with(ext))
+        .map(|(_, format)| format)
+    }
+}
+
+/// How to get the installable file(s) out of an archived download
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Archive {
+    /// Detected from the download's file name if not given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<ArchiveFormat>,
+    /// Number of leading path components to drop from every member, like `tar --strip-components`
+    #[serde(skip_serializing_if = "is_zero")]
+    pub strip_components: usize,
+    /// Glob patterns of members to install, relative to the (stripped) archive root
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub members: Vec<String>,
+}
+
+impl Archive {
+    /// Extract `archive` into `dest`, returning the relative paths of every extracted file
+    pub fn extract(
+        &self,
+        format: ArchiveFormat,
+        archive: &Path,
+        dest: &Path,
+    ) -> io::Result<Vec<PathBuf>> {
+        let file = File::open(archive)?;
+
+        match format {
+            ArchiveFormat::Tar => self.extract_tar(file, dest),
+            ArchiveFormat::TarGz => self.extract_tar(flate2::read::GzDecoder::new(file), dest),
+            ArchiveFormat::TarXz => self.extract_tar(xz2::read::XzDecoder::new(file), dest),
+            ArchiveFormat::TarZst => self.extract_tar(zstd::Decoder::new(file)?, dest),
+            ArchiveFormat::Zip => self.extract_zip(file, dest),
+        }
+    }
+
+    /// Pick the extracted files to install, falling back to any file called `default_name`
+    /// if no member patterns were configured
+    pub fn select<'a>(
+        &self,
+        files: &'a [PathBuf],
+        default_name: &str,
+    ) -> io::Result<Vec<&'a PathBuf>> {
+        let patterns = self
+            .members
+            .iter()
+            .map(|m| glob::Pattern::new(m))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        let selected: Vec<_> = files
+            .iter()
+            .filter(|f| {
+                if patterns.is_empty() {
+                    f.file_name().is_some_and(|n| n == default_name)
+                } else {
+                    patterns.iter().any(|p| p.matches_path(f))
+                }
+            })
+            .collect();
+
+        if selected.is_empty() {
+            let wanted = if self.members.is_empty() {
+                default_name.to_string()
+            } else {
+                self.members.join(", ")
+            };
+
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no archive members matched {wanted}"),
+            ));
+        }
+
+        Ok(selected)
+    }
+
+    fn extract_tar(&self, reader: impl Read, dest: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut archive = tar::Archive::new(reader);
+        let mut extracted = Vec::new();
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let Some(path) = self.strip(&entry.path()?) else {
+                continue;
+            };
+
+            let entry_type = entry.header().entry_type();
+            if !(entry_type.is_file() || entry_type.is_dir()) {
+                continue;
+            }
+
+            let out = dest.join(&path);
+            if let Some(parent) = out.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            entry.unpack(&out)?;
+
+            if entry_type.is_file() {
+                extracted.push(path);
+            }
+        }
+
+        Ok(extracted)
+    }
+
+    fn extract_zip(&self, file: File, dest: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut archive = zip::ZipArchive::new(file).map_err(io::Error::other)?;
+        let mut extracted = Vec::new();
+
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i).map_err(io::Error::other)?;
+            let Some(path) = entry.enclosed_name().and_then(|p| self.strip(&p)) else {
+                continue;
+            };
+
+            let out = dest.join(&path);
+            if entry.is_dir() {
+                std::fs::create_dir_all(&out)?;
+                continue;
+            }
+
+            if let Some(parent) = out.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let mut f = File::create(&out)?;
+            io::copy(&mut entry, &mut f)?;
+
+            if let Some(mode) = entry.unix_mode() {
+                std::fs::set_permissions(&out, std::fs::Permissions::from_mode(mode))?;
+            }
+
+            extracted.push(path);
+        }
+
+        Ok(extracted)
+    }
+
+    /// Drop `strip_components` leading components from `path`, refusing anything that would escape the destination
+    fn strip(&self, path: &Path) -> Option<PathBuf> {
+        let mut components = Vec::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(c) => components.push(c),
+                Component::CurDir => {}
+                _ => return None,
+            }
+        }
+
+        let stripped: PathBuf = components.into_iter().skip(self.strip_components).collect();
+        if stripped.as_os_str().is_empty() {
+            None
+        } else {
+            Some(stripped)
+        }
+    }
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive(strip_components: usize, members: &[&str]) -> Archive {
+        Archive {
+            format: None,
+            strip_components,
+            members: members.iter().map(|m| m.to_string()).collect(),
+        }
+    }
+
+    /// Write a tar with a file for every `(path, contents)`, paths are written as is so they can escape
+    fn write_tar(path: &Path, files: &[(&str, &str)]) {
+        let mut builder = tar::Builder::new(File::create(path).unwrap());
+        for (name, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o755);
+            header.set_cksum();
+            builder.append(&header, contents.as_bytes()).unwrap();
+        }
+        builder.finish().unwrap();
+    }
+
+    #[test]
+    fn detects_formats_from_the_extension() {
+        assert_eq!(
+            ArchiveFormat::detect("tool-1.0-linux.TAR.GZ"),
+            Some(ArchiveFormat::TarGz)
+        );
+        assert_eq!(
+            ArchiveFormat::detect("tool.tzst"),
+            Some(ArchiveFormat::TarZst)
+        );
+        assert_eq!(ArchiveFormat::detect("tool.zip"), Some(ArchiveFormat::Zip));
+        assert_eq!(ArchiveFormat::detect("tool.gz"), None);
+    }
+
+    #[test]
+    fn strips_leading_components() {
+        let archive = archive(1, &[]);
+        assert_eq!(
+            archive.strip(Path::new("./tool-1.0/bin/tool")),
+            Some(PathBuf::from("bin/tool"))
+        );
+        assert_eq!(archive.strip(Path::new("tool-1.0")), None);
+    }
+
+    #[test]
+    fn refuses_paths_that_escape_the_destination() {
+        let archive = archive(0, &[]);
+        assert_eq!(archive.strip(Path::new("../tool")), None);
+        assert_eq!(archive.strip(Path::new("bin/../../tool")), None);
+        assert_eq!(archive.strip(Path::new("/usr/bin/tool")), None);
+    }
+
+    #[test]
+    fn selects_the_default_name_without_members() {
+        let files = [PathBuf::from("README.md"), PathBuf::from("bin/tool")];
+        assert_eq!(
+            archive(0, &[]).select(&files, "tool").unwrap(),
+            vec![&files[1]]
+        );
+        assert!(archive(0, &[]).select(&files, "other").is_err());
+    }
+
+    #[test]
+    fn selects_members_by_glob() {
+        let files = [
+            PathBuf::from("bin/tool"),
+            PathBuf::from("bin/toolctl"),
+            PathBuf::from("doc/tool.1"),
+        ];
+        assert_eq!(
+            archive(0, &["bin/*"]).select(&files, "tool").unwrap(),
+            vec![&files[0], &files[1]]
+        );
+    }
+
+    #[test]
+    fn extracts_tars_without_escaping() {
+        let dir = tempfile::tempdir().unwrap();
+        let tar = dir.path().join("tool.tar");
+        write_tar(
+            &tar,
+            &[
+                ("tool-1.0/bin/tool", "binary"),
+                ("tool-1.0/../../evil", "evil"),
+            ],
+        );
+
+        let dest = dir.path().join("out");
+        let extracted = archive(1, &[])
+            .extract(ArchiveFormat::Tar, &tar, &dest)
+            .unwrap();
+
+        assert_eq!(extracted, vec![PathBuf::from("bin/tool")]);
+        assert_eq!(
+            std::fs::read_to_string(dest.join("bin/tool")).unwrap(),
+            "binary"
+        );
+        assert!(!dir.path().join("evil").exists());
+    }
+
+    #[test]
+    fn extracts_zips() {
+        let dir = tempfile::tempdir().unwrap();
+        let zip_path = dir.path().join("tool.zip");
+        let mut writer = zip::ZipWriter::new(File::create(&zip_path).unwrap());
+        let options = zip::write::SimpleFileOptions::default().unix_permissions(0o755);
+        writer.start_file("tool-1.0/tool", options).unwrap();
+        std::io::Write::write_all(&mut writer, b"binary").unwrap();
+        writer.finish().unwrap();
+
+        let dest = dir.path().join("out");
+        let extracted = archive(1, &[])
+            .extract(ArchiveFormat::Zip, &zip_path, &dest)
+            .unwrap();
+
+        assert_eq!(extracted, vec![PathBuf::from("tool")]);
+        let mode = std::fs::metadata(dest.join("tool"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o755);
+    }
+}
@@ -0,0 +1,315 @@
+use std::fmt;
+
+use serde::{Serialize, Serializer};
+use sha2::{Digest, Sha256, Sha512};
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Algorithm {
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    fn from_prefix(prefix: &str) -> Option<Self> {
+        match prefix.to_lowercase().as_str() {
+            "sha256" => Some(Self::Sha256),
+            "sha512" => Some(Self::Sha512),
+            _ => None,
+        }
+    }
+
+    /// Guess the algorithm of a bare hex digest from its length
+    fn from_hex_len(len: usize) -> Option<Self> {
+        match len {
+            64 => Some(Self::Sha256),
+            128 => Some(Self::Sha512),
+            _ => None,
+        }
+    }
+
+    pub fn digest(&self, bytes: &[u8]) -> String {
+        match self {
+            Self::Sha256 => to_hex(&Sha256::digest(bytes)),
+            Self::Sha512 => to_hex(&Sha512::digest(bytes)),
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Sha256 => f.write_str("sha256"),
+            Self::Sha512 => f.write_str("sha512"),
+        }
+    }
+}
+
+/// A known hex digest along with the algorithm that produced it
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct Checksum {
+    pub algorithm: Algorithm,
+    pub digest: String,
+}
+
+impl Checksum {
+    /// Parse an inline `<algorithm>:<hex>` checksum, e.g. `sha256:abcd...`
+    pub fn parse_inline(s: &str) -> Option<Self> {
+        let (prefix, digest) = s.split_once(':')?;
+        let algorithm = Algorithm::from_prefix(prefix)?;
+        Self::from_hex(algorithm, digest)
+    }
+
+    fn from_hex(algorithm: Algorithm, digest: &str) -> Option<Self> {
+        let digest = digest.trim().to_lowercase();
+        let expected_len = match algorithm {
+            Algorithm::Sha256 => 64,
+            Algorithm::Sha512 => 128,
+        };
+
+        if digest.len() == expected_len && digest.chars().all(|c| c.is_ascii_hexdigit()) {
+            Some(Self { algorithm, digest })
+        } else {
+            None
+        }
+    }
+
+    fn from_bare_hex(digest: &str) -> Option<Self> {
+        let algorithm = Algorithm::from_hex_len(digest.len())?;
+        Self::from_hex(algorithm, digest)
+    }
+
+    /// Parse the contents of a checksum file, which may be any of
+    ///   - a bare hex digest
+    ///   - `sha256sum`-style `<hex>  <filename>` lines (optionally with a `*` binary marker)
+    ///   - BSD-style `SHA256 (<filename>) = <hex>` lines
+    ///
+    /// The entry for `file_name` is picked, a single entry without a file name is taken as is
+    pub fn parse_file(contents: &str, file_name: &str) -> Option<Self> {
+        let mut entries = Vec::new();
+
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some((prefix, rest)) = line.split_once(" (")
+                && let Some((name, digest)) = rest.split_once(") = ")
+            {
+                if let Some(algorithm) = Algorithm::from_prefix(prefix)
+                    && let Some(sum) = Self::from_hex(algorithm, digest)
+                {
+                    entries.push((Some(name.to_string()), sum));
+                }
+                continue;
+            }
+
+            let mut parts = line.splitn(2, char::is_whitespace);
+            let digest = parts.next().unwrap_or_default();
+            let name = parts
+                .next()
+                .map(|n| n.trim().trim_start_matches('*').to_string())
+                .filter(|n| !n.is_empty());
+
+            if let Some(sum) = Self::from_bare_hex(digest) {
+                entries.push((name, sum));
+            }
+        }
+
+        if let [(None, _)] = entries.as_slice() {
+            return entries.pop().map(|(_, sum)| sum);
+        }
+
+        entries
+            .into_iter()
+            .find(|(name, _)| {
+                name.as_deref()
+                    .is_some_and(|n| n == file_name || n.rsplit('/').next() == Some(file_name))
+            })
+            .map(|(_, sum)| sum)
+    }
+
+    pub fn verify(&self, bytes: &[u8]) -> Result<(), String> {
+        let actual = self.algorithm.digest(bytes);
+        if actual == self.digest {
+            Ok(())
+        } else {
+            Err(actual)
+        }
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.digest)
+    }
+}
+
+/// Where to get the expected checksum of a download from
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum ChecksumSource {
+    Inline(Checksum),
+    Url(String),
+}
+
+impl ChecksumSource {
+    /// Parse an inline `<algorithm>:<hex>` checksum, or else the URL of a checksum file
+    pub fn parse(s: &str) -> Result<Self, String> {
+        if let Some(sum) = Checksum::parse_inline(s) {
+            return Ok(Self::Inline(sum));
+        }
+
+        // `sha256:` with a bad digest would otherwise only fail once it's fetched as a URL
+        if !s.contains("://")
+            && let Some((prefix, _)) = s.split_once(':')
+            && let Some(algorithm) = Algorithm::from_prefix(prefix)
+        {
+            return Err(format!(
+                "invalid {algorithm} checksum `{s}`, expected {algorithm}:<hex digest>"
+            ));
+        }
+
+        Ok(Self::Url(s.to_string()))
+    }
+
+    /// Resolve this source into a concrete checksum, fetching and parsing the checksum file if needed
+    pub fn resolve(&self, file_name: &str) -> crate::Result<Checksum> {
+        match self {
+            Self::Inline(sum) => Ok(sum.clone()),
+            Self::Url(url) => {
+                let response = reqwest::blocking::get(url)?.error_for_status()?;
+                let contents = response.text()?;
+
+                Checksum::parse_file(&contents, file_name).ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("no checksum for {file_name} found at {url}"),
+                    )
+                    .into()
+                })
+            }
+        }
+    }
+}
+
+impl fmt::Display for ChecksumSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Inline(sum) => sum.fmt(f),
+            Self::Url(url) => f.write_str(url),
+        }
+    }
+}
+
+impl Serialize for ChecksumSource {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256_EMPTY: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    fn sha256(digest: &str) -> Checksum {
+        Checksum {
+            algorithm: Algorithm::Sha256,
+            digest: digest.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_inline_checksums() {
+        assert_eq!(
+            Checksum::parse_inline(&format!("SHA256:{}", SHA256_EMPTY.to_uppercase())),
+            Some(sha256(SHA256_EMPTY))
+        );
+        assert_eq!(Checksum::parse_inline("sha256:abcd"), None);
+        assert_eq!(Checksum::parse_inline(&format!("md5:{SHA256_EMPTY}")), None);
+        assert_eq!(
+            Checksum::parse_inline(&format!("sha512:{SHA256_EMPTY}")),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_a_bare_digest() {
+        let contents = format!("{SHA256_EMPTY}\n");
+        assert_eq!(
+            Checksum::parse_file(&contents, "tool"),
+            Some(sha256(SHA256_EMPTY))
+        );
+    }
+
+    #[test]
+    fn picks_the_entry_for_the_file_name() {
+        let other = "a".repeat(64);
+        let contents =
+            format!("# checksums\n{other}  tool-arm64.tar.gz\n{SHA256_EMPTY} *tool-amd64.tar.gz\n");
+        assert_eq!(
+            Checksum::parse_file(&contents, "tool-amd64.tar.gz"),
+            Some(sha256(SHA256_EMPTY))
+        );
+        assert_eq!(Checksum::parse_file(&contents, "tool.zip"), None);
+    }
+
+    #[test]
+    fn matches_entries_by_their_base_name() {
+        let contents = format!("{SHA256_EMPTY}  ./dist/tool\n");
+        assert_eq!(
+            Checksum::parse_file(&contents, "tool"),
+            Some(sha256(SHA256_EMPTY))
+        );
+    }
+
+    #[test]
+    fn a_single_entry_must_still_match_the_file_name() {
+        let contents = format!("{SHA256_EMPTY}  other-tool\n");
+        assert_eq!(Checksum::parse_file(&contents, "tool"), None);
+    }
+
+    #[test]
+    fn parses_bsd_style_lines() {
+        let digest = "b".repeat(128);
+        let contents = format!("SHA512 (tool) = {digest}\n");
+        assert_eq!(
+            Checksum::parse_file(&contents, "tool"),
+            Some(Checksum {
+                algorithm: Algorithm::Sha512,
+                digest,
+            })
+        );
+    }
+
+    #[test]
+    fn parses_checksum_sources() {
+        assert_eq!(
+            ChecksumSource::parse(&format!("sha256:{SHA256_EMPTY}")),
+            Ok(ChecksumSource::Inline(sha256(SHA256_EMPTY)))
+        );
+        assert_eq!(
+            ChecksumSource::parse("https://example.com/sha256:SUMS"),
+            Ok(ChecksumSource::Url(String::from(
+                "https://example.com/sha256:SUMS"
+            )))
+        );
+        assert!(ChecksumSource::parse("sha256:not-hex").is_err());
+    }
+
+    #[test]
+    fn verifies_contents() {
+        assert_eq!(sha256(SHA256_EMPTY).verify(b""), Ok(()));
+        assert_eq!(
+            sha256(SHA256_EMPTY).verify(b"x"),
+            Err(Algorithm::Sha256.digest(b"x"))
+        );
+    }
+}
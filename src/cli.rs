@@ -0,0 +1,199 @@
+use std::path::PathBuf;
+
+use clap::{ArgAction, Args, Parser, Subcommand};
+
+use crate::{RunMode, package_types::ProviderKind, stow::Strategy};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Stow on steroids")]
+pub struct Cli {
+    /// Path to the config file, defaults to $XDG_CONFIG_HOME/bow/bow.yaml and then ./bow.yaml
+    #[arg(short, long, global = true, env = "BOW_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Where bow keeps track of what it installed, defaults to $XDG_STATE_HOME/bow
+    #[arg(long, global = true, env = "BOW_STATE_DIR")]
+    pub state_dir: Option<PathBuf>,
+
+    /// Answer yes to every prompt
+    #[arg(short, long, global = true)]
+    pub yes: bool,
+
+    /// Override the mode set in the config file
+    #[arg(long, global = true, value_enum)]
+    pub mode: Option<RunMode>,
+
+    /// What to do with files in the way of dotfiles, overriding the config
+    #[arg(long, global = true, value_enum)]
+    pub on_conflict: Option<Strategy>,
+
+    /// Only act on the given provider(s)
+    #[arg(long, global = true, value_enum, value_delimiter = ',')]
+    pub only: Vec<ProviderKind>,
+
+    /// Print more output, can be repeated
+    #[arg(short, long, global = true, action = ArgAction::Count, conflicts_with = "quiet")]
+    pub verbose: u8,
+
+    /// Only print errors and prompts
+    #[arg(short, long, global = true)]
+    pub quiet: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Install (and in idempotent mode, remove) packages so the system matches the config
+    Apply {
+        /// Path to the config file, takes precedence over --config
+        config: Option<PathBuf>,
+        /// Only print what would change, like `bow plan`
+        #[arg(long)]
+        dry_run: bool,
+        /// Refuse to install anything that differs from bow.lock, and leave bow.lock untouched
+        #[arg(long)]
+        locked: bool,
+    },
+    /// Show what `apply` would change without changing anything
+    Plan {
+        /// Print the plan as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show the state of every declared package
+    Status,
+    /// List the packages currently installed by each provider
+    List,
+    /// Inspect and return to what previous applies installed
+    Generations {
+        #[command(subcommand)]
+        command: GenerationsCommand,
+    },
+    /// Restore the previously installed version of a binary
+    Rollback {
+        /// The name of the binary
+        name: String,
+    },
+    /// Link packages from a stow directory into a target directory, taking the same flags as GNU Stow.
+    /// This is also what runs when bow is invoked through a symlink named `stow`
+    Stow(StowArgs),
+    /// Write a starter config file
+    Init {
+        /// Where to write the config, defaults to ./bow.yaml
+        path: Option<PathBuf>,
+        /// Overwrite an existing file
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct StowArgs {
+    /// Packages to stow
+    #[arg(value_name = "PACKAGE")]
+    pub packages: Vec<String>,
+
+    /// Stow the packages that follow
+    #[arg(short = 'S', long = "stow", value_name = "PACKAGE", num_args = 1..)]
+    pub stow: Vec<String>,
+
+    /// Unstow the packages that follow
+    #[arg(short = 'D', long = "delete", value_name = "PACKAGE", num_args = 1..)]
+    pub delete: Vec<String>,
+
+    /// Unstow and then stow the packages that follow again, cleaning up links to files they no longer have
+    #[arg(short = 'R', long = "restow", value_name = "PACKAGE", num_args = 1..)]
+    pub restow: Vec<String>,
+
+    /// The stow directory, defaults to the current directory
+    #[arg(short = 'd', long = "dir", env = "STOW_DIR")]
+    pub dir: Option<PathBuf>,
+
+    /// The target directory, defaults to the parent of the stow directory
+    #[arg(short = 't', long = "target")]
+    pub target: Option<PathBuf>,
+
+    /// Only show what would change
+    #[arg(short = 'n', long = "no", visible_alias = "simulate")]
+    pub simulate: bool,
+
+    /// Move files that are in the way into the package and link them, the same as `--on-conflict adopt`
+    #[arg(long)]
+    pub adopt: bool,
+
+    /// Don't stow files whose path ends in a match of this regex, can be repeated
+    #[arg(long, value_name = "REGEX")]
+    pub ignore: Vec<String>,
+
+    /// Leave files whose path starts with a match of this regex to the package that already stowed them
+    #[arg(long, value_name = "REGEX")]
+    pub defer: Vec<String>,
+
+    /// Take over files whose path starts with a match of this regex from the package that stowed them
+    #[arg(long = "override", value_name = "REGEX")]
+    pub overrides: Vec<String>,
+
+    /// Link files named `dot-foo` in packages as `.foo`
+    #[arg(long)]
+    pub dotfiles: bool,
+
+    /// Create every directory instead of linking directories only one package has files in
+    #[arg(long)]
+    pub no_folding: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum GenerationsCommand {
+    /// List every generation and what changed in it
+    List,
+    /// Install and remove packages until the system matches generation N
+    Switch {
+        /// The generation to switch to
+        number: u32,
+    },
+}
+
+impl Cli {
+    /// Find the config file to use, in order of
+    ///   1. the path given to `apply`
+    ///   2. --config or $BOW_CONFIG
+    ///   3. $XDG_CONFIG_HOME/bow/bow.yaml (or ~/.config/bow/bow.yaml)
+    ///   4. ./bow.yaml
+    pub fn config_path(&self) -> Option<PathBuf> {
+        if let Some(Command::Apply {
+            config: Some(config),
+            ..
+        }) = &self.command
+        {
+            return Some(config.clone());
+        }
+
+        if let Some(config) = &self.config {
+            return Some(config.clone());
+        }
+
+        crate::paths::xdg("XDG_CONFIG_HOME")
+            .map(|dir| dir.join("bow").join("bow.yaml"))
+            .into_iter()
+            .chain([PathBuf::from("bow.yaml")])
+            .find(|path| path.exists())
+    }
+}
+
+pub const INIT_TEMPLATE: &str = r#"# `imperative` or `idempotent`
+#   imperative will simply attempt to install the packages declared in this file
+#   idempotent will prompt to remove any packages bow installed that are *not* declared in this file
+mode: imperative
+
+# define your packages here under their respective provider
+packages:
+  # a list of flatpak IDs
+  flatpak: []
+
+  # raw binaries, define the default install folder and then binaries follow
+  binary:
+    install_folder: $HOME/.local/bin
+    packages: []
+"#;
@@ -1,14 +1,180 @@
-use strum::Display;
+use std::{fmt, path::PathBuf, process::ExitStatus};
 
 pub type Result<T> = std::result::Result<T, self::Error>;
 
-#[derive(Debug, Display)]
+#[derive(Debug)]
 pub enum Error {
     Io(std::io::Error),
     Reqwest(reqwest::Error),
+    ConfigNotFound,
+    ConfigParse {
+        path: PathBuf,
+        line: Option<usize>,
+        column: Option<usize>,
+        message: String,
+    },
+    ChecksumMismatch {
+        expected: String,
+        actual: String,
+    },
+    LockMismatch {
+        expected: String,
+        actual: String,
+    },
+    NotLocked,
+    /// There is no previous version to roll back to
+    NoBackup,
+    NoMatchingRelease {
+        repo: String,
+        version: String,
+    },
+    NoMatchingAsset {
+        tag: String,
+        pattern: String,
+    },
+    CommandFailed {
+        command: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+    MissingDependency {
+        program: String,
+    },
+    StateCorrupted {
+        path: PathBuf,
+        message: String,
+    },
+    /// Existing files in the target stop dotfiles from being linked
+    Conflicts(Vec<crate::stow::Conflict>),
+    /// Something went wrong while handling a single package
+    Package {
+        name: String,
+        source: Box<Error>,
+    },
+    /// Several packages failed independently of each other
+    Multiple(Vec<Error>),
+    /// Something went wrong inside a provider
+    Provider {
+        name: &'static str,
+        source: Box<Error>,
+    },
 }
 
-impl std::error::Error for self::Error {}
+impl Error {
+    /// Turn a failure to spawn `program` into [`Error::MissingDependency`] if it isn't installed
+    pub fn from_spawn(program: &str, e: std::io::Error) -> Self {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            Self::MissingDependency {
+                program: program.to_string(),
+            }
+        } else {
+            Self::Io(e)
+        }
+    }
+
+    /// The process exit code to use when bow fails with this error
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::Io(_) | Self::NoBackup => 1,
+            Self::ConfigNotFound | Self::ConfigParse { .. } => 2,
+            Self::ChecksumMismatch { .. } | Self::LockMismatch { .. } | Self::NotLocked => 3,
+            Self::CommandFailed { .. } => 4,
+            Self::MissingDependency { .. } => 5,
+            Self::StateCorrupted { .. } => 6,
+            Self::Reqwest(_) => 7,
+            Self::NoMatchingRelease { .. } | Self::NoMatchingAsset { .. } => 8,
+            Self::Conflicts(_) => 9,
+            Self::Package { source, .. } | Self::Provider { source, .. } => source.exit_code(),
+            Self::Multiple(errors) => errors.first().map_or(1, Self::exit_code),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{e}"),
+            Self::Reqwest(e) => write!(f, "{e}"),
+            Self::ConfigNotFound => write!(
+                f,
+                "no config file found, create one with `bow init` or pass one with --config"
+            ),
+            Self::ConfigParse {
+                path,
+                line: Some(line),
+                column: Some(column),
+                message,
+            } => write!(
+                f,
+                "invalid config {}:{line}:{column}: {message}",
+                path.display()
+            ),
+            Self::ConfigParse { path, message, .. } => {
+                write!(f, "invalid config {}: {message}", path.display())
+            }
+            Self::ChecksumMismatch { expected, actual } => {
+                write!(f, "checksum mismatch, expected {expected} but got {actual}")
+            }
+            Self::LockMismatch { expected, actual } => write!(
+                f,
+                "differs from the lockfile, expected {expected} but got {actual}"
+            ),
+            Self::NotLocked => write!(f, "not in the lockfile"),
+            Self::NoBackup => write!(f, "no previous version to roll back to"),
+            Self::NoMatchingRelease { repo, version } => {
+                write!(f, "no release of {repo} matches {version}")
+            }
+            Self::NoMatchingAsset { tag, pattern } => {
+                write!(f, "no asset of release {tag} matches {pattern}")
+            }
+            Self::CommandFailed {
+                command,
+                status,
+                stderr,
+            } => {
+                write!(f, "`{command}` failed ({status})")?;
+                if !stderr.trim().is_empty() {
+                    write!(f, ": {}", stderr.trim())?;
+                }
+                Ok(())
+            }
+            Self::MissingDependency { program } => {
+                write!(f, "`{program}` is required but was not found in $PATH")
+            }
+            Self::StateCorrupted { path, message } => {
+                write!(f, "state file {} is corrupted: {message}", path.display())
+            }
+            Self::Conflicts(conflicts) => {
+                write!(f, "{} conflict(s), nothing was changed", conflicts.len())?;
+                for conflict in conflicts {
+                    write!(f, "\n    {conflict}")?;
+                }
+                Ok(())
+            }
+            Self::Package { name, source } => write!(f, "{name}: {source}"),
+            Self::Multiple(errors) => {
+                write!(f, "{} package(s) failed", errors.len())?;
+                for e in errors {
+                    write!(f, "\n    {e}")?;
+                }
+                Ok(())
+            }
+            Self::Provider { name, source } => write!(f, "[{name}] {source}"),
+        }
+    }
+}
+
+impl std::error::Error for self::Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            // These already include their source in their own message
+            Self::Io(e) => e.source(),
+            Self::Reqwest(e) => std::error::Error::source(e),
+            Self::Package { source, .. } | Self::Provider { source, .. } => source.source(),
+            _ => None,
+        }
+    }
+}
 
 impl From<std::io::Error> for self::Error {
     fn from(e: std::io::Error) -> Self {
@@ -21,3 +187,25 @@ impl From<reqwest::Error> for self::Error {
         Self::Reqwest(e)
     }
 }
+
+/// Attach the provider or package that failed to an error
+pub trait Context<T> {
+    fn for_package(self, name: &str) -> Result<T>;
+    fn for_provider(self, name: &'static str) -> Result<T>;
+}
+
+impl<T> Context<T> for Result<T> {
+    fn for_package(self, name: &str) -> Result<T> {
+        self.map_err(|e| Error::Package {
+            name: name.to_string(),
+            source: Box::new(e),
+        })
+    }
+
+    fn for_provider(self, name: &'static str) -> Result<T> {
+        self.map_err(|e| Error::Provider {
+            name,
+            source: Box::new(e),
+        })
+    }
+}
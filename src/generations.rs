@@ -0,0 +1,300 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_yaml_bw::Value;
+
+use crate::{
+    error::Error,
+    lockfile,
+    package_types::{PackageProvider, PackagesConfig},
+    state,
+};
+
+pub const GENERATIONS_DIR_NAME: &str = "generations";
+
+/// Everything bow had installed after a single `apply`
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct Generation {
+    #[serde(skip)]
+    pub number: u32,
+    /// Seconds since the unix epoch
+    pub created_at: u64,
+    /// The config file that was applied
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<PathBuf>,
+    /// provider -> package key -> item, as the provider serializes it
+    #[serde(default)]
+    pub packages: BTreeMap<String, BTreeMap<String, Value>>,
+}
+
+impl Generation {
+    fn dir() -> PathBuf {
+        state::state_dir().join(GENERATIONS_DIR_NAME)
+    }
+
+    fn path_of(number: u32) -> PathBuf {
+        Self::dir().join(format!("{number}.yaml"))
+    }
+
+    fn read(number: u32, path: &Path) -> crate::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut generation: Self =
+            serde_yaml_bw::from_str(&contents).map_err(|e| Error::StateCorrupted {
+                path: path.to_path_buf(),
+                message: e.to_string(),
+            })?;
+        generation.number = number;
+
+        Ok(generation)
+    }
+
+    /// Every generation, oldest first
+    pub fn list() -> crate::Result<Vec<Self>> {
+        let entries = match std::fs::read_dir(Self::dir()) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut generations = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let Some(number) = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.strip_suffix(".yaml"))
+                .and_then(|n| n.parse().ok())
+            else {
+                continue;
+            };
+
+            generations.push(Self::read(number, &path)?);
+        }
+
+        generations.sort_by_key(|g| g.number);
+        Ok(generations)
+    }
+
+    pub fn load(number: u32) -> crate::Result<Self> {
+        let path = Self::path_of(number);
+        if !path.exists() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("generation {number} does not exist, see `bow generations list`"),
+            )
+            .into());
+        }
+
+        Self::read(number, &path)
+    }
+
+    pub fn latest() -> crate::Result<Option<Self>> {
+        Ok(Self::list()?.pop())
+    }
+
+    /// Write this as the next generation, numbering it after the latest one
+    fn save(&mut self, latest: Option<&Self>) -> crate::Result<()> {
+        self.number = latest.map_or(1, |g| g.number + 1);
+
+        let path = Self::path_of(self.number);
+        let contents = serde_yaml_bw::to_string(self).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("failed to write {}: {e}", path.display()),
+            )
+        })?;
+
+        std::fs::create_dir_all(Self::dir())?;
+        let tmp_path = path.with_extension("yaml.tmp");
+        std::fs::write(&tmp_path, contents)?;
+        std::fs::rename(&tmp_path, &path)?;
+
+        Ok(())
+    }
+
+    /// The items recorded for provider `P`, or `None` if it wasn't configured at the time
+    pub fn items<P: PackageProvider>(&self) -> crate::Result<Option<Vec<P::Item>>> {
+        let Some(section) = self.packages.get(P::LOG_PREFIX) else {
+            return Ok(None);
+        };
+
+        section
+            .values()
+            .map(|value| {
+                serde_yaml_bw::from_value(value.clone()).map_err(|e| Error::StateCorrupted {
+                    path: Self::path_of(self.number),
+                    message: e.to_string(),
+                })
+            })
+            .collect::<crate::Result<_>>()
+            .map(Some)
+    }
+}
+
+/// Record what `packages` has installed as a new generation, unless nothing changed since the latest one.
+/// Providers that aren't configured right now keep what the latest generation recorded for them
+pub fn record(packages: &PackagesConfig) -> crate::Result<()> {
+    record_snapshot(packages.snapshot()?)
+}
+
+/// Record `snapshot`, which holds a section for each configured provider, on top of the latest generation
+fn record_snapshot(snapshot: BTreeMap<String, BTreeMap<String, Value>>) -> crate::Result<()> {
+    let latest = Generation::latest()?;
+
+    let mut sections = latest
+        .as_ref()
+        .map(|g| g.packages.clone())
+        .unwrap_or_default();
+    sections.extend(snapshot);
+
+    if let Some(latest) = &latest
+        && latest.packages == sections
+    {
+        println!("Nothing changed since generation {}", latest.number);
+        return Ok(());
+    }
+
+    let mut generation = Generation {
+        created_at: lockfile::now(),
+        config: state::config_path().map(Path::to_path_buf),
+        packages: sections,
+        ..Default::default()
+    };
+    generation.save(latest.as_ref())?;
+    println!("Created generation {}", generation.number);
+
+    Ok(())
+}
+
+/// Print every generation along with what changed since the one before it
+pub fn print_list(verbose: bool) -> crate::Result<()> {
+    let generations = Generation::list()?;
+    if generations.is_empty() {
+        println!("No generations yet, they are created by `bow apply`");
+        return Ok(());
+    }
+
+    let empty = Generation::default();
+    let mut previous = &empty;
+    for generation in generations.iter() {
+        let mut header = format!(
+            "Generation {} ({})",
+            generation.number,
+            format_timestamp(generation.created_at)
+        );
+        if let Some(config) = &generation.config {
+            let _ = write!(header, " from {}", config.display());
+        }
+        if generation.number == generations.last().map_or(0, |g| g.number) {
+            header.push_str(" [current]");
+        }
+        println!("{header}");
+
+        let actions = PackagesConfig::diff_generations(previous, generation)?;
+        crate::package_types::print_plan(&actions, verbose);
+        previous = generation;
+    }
+
+    Ok(())
+}
+
+/// Format seconds since the unix epoch as `YYYY-MM-DD HH:MM UTC`
+fn format_timestamp(secs: u64) -> String {
+    // Howard Hinnant's days-to-civil algorithm
+    let days = (secs / 86400) as i64;
+    let (hours, minutes) = ((secs % 86400) / 3600, (secs % 3600) / 60);
+
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02} {hours:02}:{minutes:02} UTC")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn section(items: &[&str]) -> BTreeMap<String, Value> {
+        items
+            .iter()
+            .map(|item| (item.to_string(), Value::from(*item)))
+            .collect()
+    }
+
+    fn snapshot(sections: &[(&str, &[&str])]) -> BTreeMap<String, BTreeMap<String, Value>> {
+        sections
+            .iter()
+            .map(|(provider, items)| (provider.to_string(), section(items)))
+            .collect()
+    }
+
+    #[test]
+    fn formats_timestamps_as_utc_dates() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00 UTC");
+        assert_eq!(format_timestamp(1735689599), "2024-12-31 23:59 UTC");
+        assert_eq!(format_timestamp(1709164800), "2024-02-29 00:00 UTC");
+        assert_eq!(format_timestamp(951868799), "2000-02-29 23:59 UTC");
+        assert_eq!(format_timestamp(4107456000), "2100-02-28 00:00 UTC");
+        assert_eq!(format_timestamp(4107542400), "2100-03-01 00:00 UTC");
+    }
+
+    #[test]
+    fn keeps_what_unconfigured_providers_had_in_the_latest_generation() {
+        state::with_state_dir(|_| {
+            record_snapshot(snapshot(&[
+                ("binary", &["rg"]),
+                ("flatpak", &["org.gnome.Maps"]),
+            ]))
+            .unwrap();
+            record_snapshot(snapshot(&[("binary", &["fd"])])).unwrap();
+
+            let generations = Generation::list().unwrap();
+            assert_eq!(
+                generations.iter().map(|g| g.number).collect::<Vec<_>>(),
+                [1, 2]
+            );
+            assert_eq!(
+                generations[1].packages,
+                snapshot(&[("binary", &["fd"]), ("flatpak", &["org.gnome.Maps"])])
+            );
+        });
+    }
+
+    #[test]
+    fn only_records_a_generation_when_something_changed() {
+        state::with_state_dir(|_| {
+            record_snapshot(snapshot(&[
+                ("binary", &["rg"]),
+                ("flatpak", &["org.gnome.Maps"]),
+            ]))
+            .unwrap();
+            record_snapshot(snapshot(&[("binary", &["rg"])])).unwrap();
+            record_snapshot(snapshot(&[
+                ("binary", &["rg"]),
+                ("flatpak", &["org.gnome.Maps"]),
+            ]))
+            .unwrap();
+
+            assert_eq!(Generation::list().unwrap().len(), 1);
+
+            record_snapshot(snapshot(&[("binary", &[])])).unwrap();
+            let latest = Generation::latest().unwrap().unwrap();
+            assert_eq!(latest.number, 2);
+            assert_eq!(
+                latest.packages,
+                snapshot(&[("binary", &[]), ("flatpak", &["org.gnome.Maps"])])
+            );
+        });
+    }
+}
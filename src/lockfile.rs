@@ -0,0 +1,197 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+pub const LOCKFILE_NAME: &str = "bow.lock";
+
+/// What was actually installed for a single package
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LockEntry {
+    /// The URL the artifact was downloaded from, after templating
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// The version the artifact was resolved to, e.g. the tag of a release
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// `<algorithm>:<hex>` hash of the downloaded artifact
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+    /// Seconds since the unix epoch
+    pub installed_at: u64,
+}
+
+impl LockEntry {
+    /// Whether `self` and `other` describe the same artifact, ignoring when they were installed
+    fn same_artifact(&self, other: &Self) -> bool {
+        self.url == other.url
+            && self.version == other.version
+            && self.hash == other.hash
+            && self.commit == other.commit
+            && self.branch == other.branch
+    }
+}
+
+/// `bow.lock`, which lives next to the config and records the resolved artifacts of every provider
+#[derive(Debug, Default)]
+pub struct Lockfile {
+    path: PathBuf,
+    /// provider -> package key -> entry
+    packages: BTreeMap<String, BTreeMap<String, LockEntry>>,
+}
+
+impl Lockfile {
+    /// Load the lockfile that belongs to `config_path`, or an empty one if it doesn't exist yet
+    pub fn load_for(config_path: &Path) -> crate::Result<Self> {
+        let path = config_path.with_file_name(LOCKFILE_NAME);
+
+        let packages = match std::fs::read_to_string(&path) {
+            Ok(contents) if contents.trim().is_empty() => BTreeMap::new(),
+            Ok(contents) => serde_yaml_bw::from_str(&contents).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("failed to parse {}: {e}", path.display()),
+                )
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self { path, packages })
+    }
+
+    /// Write the lockfile the same way as the state file, so a killed apply never leaves half of it behind
+    pub fn save(&self) -> crate::Result<()> {
+        let contents = serde_yaml_bw::to_string(&self.packages).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("failed to write {}: {e}", self.path.display()),
+            )
+        })?;
+
+        crate::state::write_atomically(&self.path, &contents)?;
+
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn section(&self, provider: &str) -> BTreeMap<String, LockEntry> {
+        self.packages.get(provider).cloned().unwrap_or_default()
+    }
+
+    /// Replace the section for `provider` with `entries`,
+    /// keeping the install time of anything that didn't change
+    pub fn update(&mut self, provider: &str, entries: Vec<(String, LockEntry)>) {
+        let old = self.packages.remove(provider).unwrap_or_default();
+
+        let section = entries
+            .into_iter()
+            .map(|(key, mut entry)| {
+                if let Some(old_entry) = old.get(&key)
+                    && old_entry.same_artifact(&entry)
+                {
+                    entry.installed_at = old_entry.installed_at;
+                }
+                (key, entry)
+            })
+            .collect();
+
+        self.packages.insert(provider.to_string(), section);
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(version: &str, installed_at: u64) -> LockEntry {
+        LockEntry {
+            version: Some(version.to_string()),
+            installed_at,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn update_keeps_the_install_time_of_unchanged_artifacts() {
+        let mut lock = Lockfile::default();
+        lock.update(
+            "binary",
+            vec![
+                ("rg".to_string(), entry("14.1.0", 100)),
+                ("fd".to_string(), entry("10.1.0", 100)),
+            ],
+        );
+
+        lock.update(
+            "binary",
+            vec![
+                ("rg".to_string(), entry("14.1.0", 200)),
+                ("fd".to_string(), entry("10.2.0", 200)),
+                ("bat".to_string(), entry("0.24.0", 200)),
+            ],
+        );
+
+        assert_eq!(
+            lock.section("binary"),
+            BTreeMap::from([
+                ("rg".to_string(), entry("14.1.0", 100)),
+                ("fd".to_string(), entry("10.2.0", 200)),
+                ("bat".to_string(), entry("0.24.0", 200)),
+            ])
+        );
+    }
+
+    #[test]
+    fn update_replaces_the_whole_section() {
+        let mut lock = Lockfile::default();
+        lock.update("binary", vec![("rg".to_string(), entry("14.1.0", 100))]);
+        lock.update(
+            "flatpak",
+            vec![("org.gnome.Maps".to_string(), LockEntry::default())],
+        );
+
+        lock.update("binary", Vec::new());
+
+        assert!(lock.section("binary").is_empty());
+        assert_eq!(lock.section("flatpak").len(), 1);
+    }
+
+    #[test]
+    fn saves_through_a_temporary_file_next_to_the_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("bow.yaml");
+        let tmp = dir.path().join(format!("{LOCKFILE_NAME}.tmp"));
+        // What a save that was killed halfway leaves behind
+        std::fs::write(&tmp, "binary:\n  rg:\n    vers").unwrap();
+
+        let mut lock = Lockfile::load_for(&config).unwrap();
+        assert_eq!(lock.path(), dir.path().join(LOCKFILE_NAME));
+        lock.update("binary", vec![("rg".to_string(), entry("14.1.0", 100))]);
+        lock.save().unwrap();
+
+        assert!(!tmp.exists());
+        assert_eq!(
+            Lockfile::load_for(&config).unwrap().section("binary"),
+            BTreeMap::from([("rg".to_string(), entry("14.1.0", 100))])
+        );
+    }
+}
@@ -1,15 +1,36 @@
+mod archive;
+mod checksum;
+mod cli;
 mod error;
+mod generations;
+mod lockfile;
 mod package_types;
+mod paths;
+mod release;
+mod state;
+mod stow;
+mod template;
 
-use error::Result;
+use error::{Error, Result};
 
+use clap::{Parser, ValueEnum};
 use serde::Deserialize;
 
-use std::fs;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
 
-use crate::package_types::PackagesConfig;
+use crate::{
+    cli::{Cli, Command, GenerationsCommand, StowArgs},
+    error::Context,
+    lockfile::Lockfile,
+    package_types::{BinaryProvider, DotfilesProvider, PackageProvider, PackagesConfig},
+};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, Deserialize, ValueEnum)]
 enum RunMode {
     #[serde(rename = "idempotent")]
     Idempotent,
@@ -20,20 +41,257 @@ enum RunMode {
 #[derive(Debug, Deserialize)]
 struct Config {
     mode: RunMode,
+    /// Where bow keeps track of what it installed, see [`state::default_state_dir`]
+    state_dir: Option<String>,
     packages: Option<PackagesConfig>,
 }
 
-fn main() -> crate::Result<()> {
-    let f = fs::read_to_string("./bow.yaml").unwrap();
+fn main() -> ExitCode {
+    let mut args: Vec<_> = std::env::args_os().collect();
+    // Scripts written for GNU Stow can run bow through a symlink named `stow`
+    if args
+        .first()
+        .and_then(|arg0| Path::new(arg0).file_name())
+        .is_some_and(|name| name == "stow")
+    {
+        args.insert(1, "stow".into());
+    }
+    let cli = Cli::parse_from(args);
+
+    package_types::set_assume_yes(cli.yes);
+    package_types::set_verbosity(if cli.quiet { 0 } else { cli.verbose + 1 });
+
+    match run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+
+            let mut source = std::error::Error::source(&e);
+            while let Some(cause) = source {
+                eprintln!("  caused by: {cause}");
+                source = cause.source();
+            }
+
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+fn run(cli: Cli) -> crate::Result<()> {
+    if let Some(Command::Init { path, force }) = &cli.command {
+        let path = path.clone().unwrap_or_else(|| "bow.yaml".into());
+        if path.exists() && !force {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!(
+                    "{} already exists, use --force to overwrite it",
+                    path.display()
+                ),
+            )
+            .into());
+        }
+
+        fs::write(&path, cli::INIT_TEMPLATE)?;
+        println!("Wrote starter config to {}", path.display());
+        return Ok(());
+    }
+
+    if let Some(Command::Stow(args)) = &cli.command {
+        // Stowed packages don't come from the config, but share the state of the dotfiles it declares
+        let state_dir = cli
+            .state_dir
+            .clone()
+            .or_else(|| cli.config_path().as_deref().and_then(configured_state_dir));
+        state::init(state_dir, None)?;
+        return stow(args, cli.on_conflict).for_provider(DotfilesProvider::LOG_PREFIX);
+    }
+
+    // Going back to what was installed before has to work even when the config no longer parses
+    let verbose = cli.verbose > 0;
+    if let Some(
+        command @ (Command::Rollback { .. }
+        | Command::Generations {
+            command: GenerationsCommand::List,
+        }),
+    ) = &cli.command
+    {
+        let config_path = cli.config_path();
+        let state_dir = cli
+            .state_dir
+            .clone()
+            .or_else(|| config_path.as_deref().and_then(configured_state_dir));
+        state::init(state_dir, config_path.as_deref())?;
+
+        return match command {
+            Command::Rollback { name } => BinaryProvider::rollback(name)
+                .for_package(name)
+                .for_provider(BinaryProvider::LOG_PREFIX),
+            _ => generations::print_list(verbose),
+        };
+    }
+
+    let config_path = cli.config_path().ok_or(Error::ConfigNotFound)?;
+    let f = fs::read_to_string(&config_path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => Error::ConfigNotFound,
+        _ => e.into(),
+    })?;
+
+    let parse_error = |e: serde_yaml_bw::Error| Error::ConfigParse {
+        path: config_path.clone(),
+        line: e.location().map(|l| l.line()),
+        column: e.location().map(|l| l.column()),
+        message: e.to_string(),
+    };
+
+    // Packages are templated while they're parsed, so `vars` has to be known before then
+    #[derive(Deserialize)]
+    struct Vars {
+        /// Variables available to every template, see [`template::set_vars`]
+        #[serde(default)]
+        vars: BTreeMap<String, String>,
+    }
+    let Vars { vars } = serde_yaml_bw::from_str(&f).map_err(parse_error)?;
+    template::set_vars(vars);
+
+    let Config {
+        mode,
+        state_dir,
+        packages,
+    } = serde_yaml_bw::from_str(&f).map_err(parse_error)?;
+    let mode = cli.mode.unwrap_or(mode);
+
+    let state_dir = match (cli.state_dir.clone(), state_dir) {
+        (Some(dir), _) => Some(dir),
+        (None, Some(dir)) => Some(paths::expand(&dir).map_err(|message| Error::ConfigParse {
+            path: config_path.clone(),
+            line: None,
+            column: None,
+            message: format!("state_dir: {message}"),
+        })?),
+        (None, None) => None,
+    };
+    state::init(state_dir, Some(&config_path))?;
+
+    let Some(mut packages) = packages else {
+        return Ok(());
+    };
+    packages.retain_only(&cli.only);
+    if let Some(strategy) = cli.on_conflict {
+        packages.set_on_conflict(strategy);
+    }
+
+    let command = cli.command.unwrap_or(Command::Apply {
+        config: None,
+        dry_run: false,
+        locked: false,
+    });
+
+    // Releases have to be resolved to what's locked, rather than whatever is newest,
+    // which a dry run has to show as well
+    if let Command::Apply { locked: true, .. } = command {
+        packages.set_locked(&Lockfile::load_for(&config_path)?)?;
+    }
+    // Reporting on what's installed shouldn't need the network, unlike working out what to install
+    if matches!(command, Command::Status | Command::List) {
+        packages.resolve_offline()?;
+    } else {
+        packages.resolve()?;
+    }
+
+    match command {
+        Command::Apply { dry_run: true, .. } => {
+            package_types::print_plan(&packages.plan(mode)?, verbose)
+        }
+        Command::Apply { locked, .. } => {
+            packages.install(mode)?;
 
-    match serde_yaml_bw::from_str(&f) {
-        Ok(Config { mode, packages }) => {
-            if let Some(packages) = packages {
-                packages.install(mode)?
+            if !locked {
+                let mut lock = Lockfile::load_for(&config_path)?;
+                packages.update_lock(&mut lock)?;
+                lock.save()?;
+                println!("Wrote {}", lock.path().display());
             }
+
+            generations::record(&packages)?;
+        }
+        Command::Generations {
+            command: GenerationsCommand::Switch { number },
+        } => {
+            let generation = generations::Generation::load(number)?;
+            package_types::print_plan(&packages.switch_plan(&generation)?, verbose);
+            packages.switch(&generation)?;
+            generations::record(&packages)?;
+            println!("The next `bow apply` will go back to what the config declares");
         }
-        Err(e) => eprintln!("{e}"),
+        Command::Plan { json: true } => {
+            let plan = serde_json::to_string_pretty(&packages.plan(mode)?)
+                .map_err(std::io::Error::other)?;
+            println!("{plan}");
+        }
+        Command::Plan { json: false } => package_types::print_plan(&packages.plan(mode)?, verbose),
+        Command::Status => packages.status()?,
+        Command::List => packages.list()?,
+        Command::Init { .. }
+        | Command::Rollback { .. }
+        | Command::Stow(_)
+        | Command::Generations {
+            command: GenerationsCommand::List,
+        } => unreachable!("handled above"),
     }
 
     Ok(())
 }
+
+/// Best effort at finding the `state_dir` a config points at, without needing the rest of it to parse
+fn configured_state_dir(config_path: &Path) -> Option<PathBuf> {
+    #[derive(Deserialize)]
+    struct StateDir {
+        state_dir: Option<String>,
+    }
+
+    let f = fs::read_to_string(config_path).ok()?;
+    let StateDir { state_dir } = serde_yaml_bw::from_str(&f).ok()?;
+    paths::expand(&state_dir?).ok()
+}
+
+/// Map Stow's command line onto the dotfiles provider
+fn stow(args: &StowArgs, on_conflict: Option<stow::Strategy>) -> crate::Result<()> {
+    let unstow = &args.delete;
+    // Stowing a package always restows it, cleaning up links to files it no longer has
+    let mut stow = args.packages.clone();
+    stow.extend(args.stow.iter().cloned());
+    stow.extend(args.restow.iter().cloned());
+    if stow.is_empty() && unstow.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "no packages to stow or unstow",
+        )
+        .into());
+    }
+
+    let invalid = |e: regex::Error| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+    let options = stow::Options {
+        folding: !args.no_folding,
+        ignore: stow::Options::suffix_patterns(&args.ignore).map_err(invalid)?,
+        defer: stow::Options::prefix_patterns(&args.defer).map_err(invalid)?,
+        overrides: stow::Options::prefix_patterns(&args.overrides).map_err(invalid)?,
+        on_conflict: match args.adopt {
+            true => stow::Strategy::Adopt,
+            false => on_conflict.unwrap_or_default(),
+        },
+        dotfiles: args.dotfiles,
+        ..Default::default()
+    };
+
+    let dir = match &args.dir {
+        Some(dir) => dir.clone(),
+        None => std::env::current_dir()?,
+    };
+    let dir = std::path::absolute(dir)?;
+    let target = match &args.target {
+        Some(target) => target.clone(),
+        None => dir.parent().map(Into::into).unwrap_or_else(|| dir.clone()),
+    };
+
+    DotfilesProvider::for_stow(dir, target, options, args.simulate)?.stow_packages(unstow, &stow)
+}
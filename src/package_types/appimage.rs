@@ -0,0 +1,532 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize, de};
+
+use crate::{
+    error::{Context, Error},
+    lockfile::LockEntry,
+    package_types::{
+        BinaryProvider, PackageProvider,
+        binary::{self, Binary, Download},
+    },
+    paths,
+    state::{self, State, StateEntry},
+};
+
+const DEFAULT_INSTALL_FOLDER: &str = "~/Applications";
+
+#[derive(Debug)]
+pub struct AppImageProvider {
+    install_folder: PathBuf,
+    pub appimages: Vec<AppImage>,
+    locked: Option<BTreeMap<String, LockEntry>>,
+}
+
+impl AppImageProvider {
+    fn new(install_folder: PathBuf, appimages: Vec<AppImage>) -> Self {
+        Self {
+            install_folder,
+            appimages,
+            locked: None,
+        }
+    }
+
+    fn install_path_of(&self, appimage: &AppImage) -> PathBuf {
+        appimage.source.install_path.clone().unwrap_or_else(|| {
+            self.install_folder
+                .join(format!("{}.AppImage", appimage.source.name))
+        })
+    }
+
+    fn install_one(&self, appimage: &AppImage) -> crate::Result<()> {
+        let name = &appimage.source.name;
+        let locked = match &self.locked {
+            Some(locked) => Some(appimage.source.locked_entry(locked)?),
+            None => None,
+        };
+
+        let Download { dir, file, hash } = appimage.source.download::<Self>(locked)?;
+        let install_path = self.install_path_of(appimage);
+        binary::place_executable::<Self>(&file, &install_path)?;
+
+        let mut files = vec![install_path.clone()];
+        if appimage.desktop {
+            match Self::integrate(name, &install_path, dir.path()) {
+                Ok(integrated) => files.extend(integrated),
+                // The app itself is installed fine, it just won't show up in launchers
+                Err(e) => Self::log_err(format!(
+                    "Failed to install the desktop entry of {name}: {e}"
+                )),
+            }
+        }
+
+        if let Some(old) = State::load()?.get(Self::LOG_PREFIX, name) {
+            binary::remove_stale::<Self>(&old.files, &files);
+        }
+
+        let data = serde_yaml_bw::to_value(appimage).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("failed to record installed AppImage: {e}"),
+            )
+        })?;
+        let entry = StateEntry {
+            hash: Some(hash.to_string()),
+            files,
+            data: Some(data),
+            ..StateEntry::now()
+        };
+        state::record(Self::LOG_PREFIX, name, entry)?;
+
+        Self::log_msg(&format!("Successfully installed {name}"));
+
+        Ok(())
+    }
+
+    /// Extract the desktop entry and icon embedded in an AppImage and install them
+    /// into `$XDG_DATA_HOME`, pointing them at the installed AppImage
+    fn integrate(name: &str, appimage: &Path, tmp_dir: &Path) -> crate::Result<Vec<PathBuf>> {
+        let root = tmp_dir.join("squashfs-root");
+
+        Self::extract(appimage, "*.desktop", tmp_dir)?;
+        let desktop_file = std::fs::read_dir(&root)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .find(|path| path.extension().is_some_and(|ext| ext == "desktop"))
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "the AppImage doesn't contain a desktop entry",
+                )
+            })?;
+        let desktop_entry = std::fs::read_to_string(&desktop_file)?;
+
+        let data_home = paths::xdg("XDG_DATA_HOME").ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "failed to determine $XDG_DATA_HOME",
+            )
+        })?;
+        let mut files = Vec::new();
+
+        let icon = match Self::extract_icon(appimage, &desktop_entry, tmp_dir) {
+            Ok(Some(icon)) => {
+                let ext = icon.extension().and_then(|e| e.to_str()).unwrap_or("png");
+                let dest = data_home.join("icons").join(format!("bow-{name}.{ext}"));
+                std::fs::create_dir_all(data_home.join("icons"))?;
+                std::fs::copy(&icon, &dest)?;
+                Self::log_debug(&format!("Installed icon {}", dest.display()));
+                files.push(dest.clone());
+                Some(dest)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                Self::log_err(format!("Failed to extract the icon of {name}: {e}"));
+                None
+            }
+        };
+
+        let dest = data_home
+            .join("applications")
+            .join(format!("bow-{name}.desktop"));
+        std::fs::create_dir_all(data_home.join("applications"))?;
+        std::fs::write(
+            &dest,
+            rewrite_desktop_entry(&desktop_entry, appimage, icon.as_deref()),
+        )?;
+        Self::log_msg(&format!("Installed desktop entry {}", dest.display()));
+        files.push(dest);
+
+        Ok(files)
+    }
+
+    /// Find the icon a desktop entry refers to, falling back to the AppImage's `.DirIcon`
+    fn extract_icon(
+        appimage: &Path,
+        desktop_entry: &str,
+        tmp_dir: &Path,
+    ) -> crate::Result<Option<PathBuf>> {
+        let root = tmp_dir.join("squashfs-root");
+
+        if let Some(icon) = desktop_entry
+            .lines()
+            .find_map(|line| line.strip_prefix("Icon="))
+            .map(str::trim)
+        {
+            for ext in ["svg", "png"] {
+                let file_name = format!("{icon}.{ext}");
+                Self::extract(appimage, &file_name, tmp_dir)?;
+                if root.join(&file_name).is_file() {
+                    return Ok(Some(root.join(file_name)));
+                }
+            }
+        }
+
+        Self::extract(appimage, ".DirIcon", tmp_dir)?;
+        let dir_icon = root.join(".DirIcon");
+        // `.DirIcon` is usually a symlink to an icon that wasn't extracted
+        if dir_icon.is_file() {
+            let ext = if std::fs::read(&dir_icon)?.starts_with(b"<") {
+                "svg"
+            } else {
+                "png"
+            };
+            let icon = tmp_dir.join(format!("icon.{ext}"));
+            std::fs::copy(&dir_icon, &icon)?;
+            return Ok(Some(icon));
+        }
+
+        Ok(None)
+    }
+
+    /// Extract the files matching `pattern` from an AppImage into `dir/squashfs-root`,
+    /// which AppImages can do by themselves without FUSE
+    fn extract(appimage: &Path, pattern: &str, dir: &Path) -> crate::Result<()> {
+        let output = std::process::Command::new(appimage)
+            .arg("--appimage-extract")
+            .arg(pattern)
+            .current_dir(dir)
+            .output()
+            .map_err(|e| Error::from_spawn(&appimage.display().to_string(), e))?;
+
+        if !output.status.success() {
+            return Err(Error::CommandFailed {
+                command: format!("{} --appimage-extract {pattern}", appimage.display()),
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
+
+        Ok(())
+    }
+
+    fn remove_one(&self, appimage: &AppImage) -> crate::Result<()> {
+        let name = &appimage.source.name;
+        let files = State::load()?
+            .get(Self::LOG_PREFIX, name)
+            .map(|entry| entry.files.clone())
+            .filter(|files| !files.is_empty())
+            .unwrap_or_else(|| vec![self.install_path_of(appimage)]);
+
+        for file in files {
+            Self::log_msg(&format!("Removing {}", file.display()));
+            match std::fs::remove_file(&file) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    Self::log_err(format!("{} was already removed", file.display()))
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        state::forget(Self::LOG_PREFIX, name)?;
+        Self::log_msg(&format!("Successfully removed {name}"));
+
+        Ok(())
+    }
+
+    fn installed_entries() -> crate::Result<Vec<(AppImage, StateEntry)>> {
+        state::installed(Self::LOG_PREFIX)
+    }
+}
+
+impl PackageProvider for AppImageProvider {
+    type Item = AppImage;
+    type Key = String;
+
+    const LOG_PREFIX: &'static str = "appimage";
+
+    fn key(item: &Self::Item) -> Self::Key {
+        item.source.name.clone()
+    }
+
+    fn describe_upgrade(old: &Self::Item, new: &Self::Item) -> String {
+        if old.source == new.source && old.desktop != new.desktop {
+            return String::from("desktop integration changed");
+        }
+
+        BinaryProvider::describe_upgrade(&old.source, &new.source)
+    }
+
+    fn install_items(&self, items: &[Self::Item]) -> crate::Result<()> {
+        for appimage in items {
+            self.install_one(appimage)
+                .for_package(&appimage.source.name)?;
+        }
+
+        Self::log_msg(&format!(
+            "Successfully installed {} AppImage(s)",
+            items.len()
+        ));
+
+        Ok(())
+    }
+
+    fn remove_items(&self, items: &[Self::Item]) -> crate::Result<()> {
+        for appimage in items {
+            self.remove_one(appimage)
+                .for_package(&appimage.source.name)?;
+        }
+
+        Self::log_msg(&format!("Successfully removed {} AppImage(s)", items.len()));
+
+        Ok(())
+    }
+
+    fn ensure(&self) -> crate::Result<()> {
+        self.keyed_ensure()
+    }
+
+    fn declared(&self) -> &[Self::Item] {
+        &self.appimages
+    }
+
+    fn lock_entries(&self, items: &[Self::Item]) -> crate::Result<Vec<(String, LockEntry)>> {
+        let installed = Self::installed_entries()?;
+
+        Ok(items
+            .iter()
+            .filter_map(|appimage| {
+                installed
+                    .iter()
+                    .find(|(a, _)| a.source.name == appimage.source.name)
+            })
+            .map(|(appimage, state)| {
+                let entry = LockEntry {
+                    url: Some(appimage.source.url.clone()),
+                    version: appimage.source.version.clone(),
+                    hash: state.hash.clone(),
+                    installed_at: state.installed_at,
+                    ..Default::default()
+                };
+                (appimage.source.name.clone(), entry)
+            })
+            .collect())
+    }
+
+    fn set_locked(&mut self, locked: BTreeMap<String, LockEntry>) {
+        self.locked = Some(locked);
+    }
+
+    /// Look up the URL, version and checksum of every AppImage published as a release
+    fn resolve(&mut self) -> crate::Result<()> {
+        for appimage in self.appimages.iter_mut() {
+            appimage.source.resolve::<Self>(self.locked.as_ref())?;
+        }
+
+        Ok(())
+    }
+
+    /// Resolve releases to the installed version where it still satisfies the declared one
+    fn resolve_offline(&mut self) -> crate::Result<()> {
+        let installed = self.get_installed()?;
+        for appimage in self.appimages.iter_mut() {
+            if let Some(old) = installed
+                .iter()
+                .find(|a| a.source.name == appimage.source.name)
+            {
+                appimage.source.resolve_from(&old.source);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_installed(&self) -> crate::Result<Vec<Self::Item>> {
+        Ok(Self::installed_entries()?
+            .into_iter()
+            .map(|(appimage, _)| appimage)
+            .collect())
+    }
+}
+
+impl<'de> Deserialize<'de> for AppImageProvider {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        pub struct Fields {
+            install_folder: Option<String>,
+            #[serde(rename = "packages")]
+            appimages: Vec<AppImage>,
+        }
+
+        let f = Fields::deserialize(deserializer)?;
+        // An AppImage is the executable itself, so there's nothing to extract it from
+        if let Some(appimage) = f.appimages.iter().find(|a| a.source.archive.is_some()) {
+            return Err(de::Error::custom(format!(
+                "{}: AppImages are installed as downloaded, `archive` isn't supported",
+                appimage.source.name
+            )));
+        }
+
+        let install_folder = paths::expand(
+            f.install_folder
+                .as_deref()
+                .unwrap_or(DEFAULT_INSTALL_FOLDER),
+        )
+        .map_err(de::Error::custom)?;
+
+        Ok(Self::new(install_folder, f.appimages))
+    }
+}
+
+/// An AppImage, which is declared just like a binary
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Deserialize, Serialize)]
+pub struct AppImage {
+    #[serde(flatten)]
+    source: Binary,
+    /// Install the embedded desktop entry and icon so the app shows up in launchers
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    desktop: bool,
+}
+
+/// Point every `Exec`, `TryExec` and `Icon` key of a desktop entry at the installed files
+fn rewrite_desktop_entry(desktop_entry: &str, appimage: &Path, icon: Option<&Path>) -> String {
+    let exec = appimage.display().to_string();
+    let exec = if exec.contains(' ') {
+        format!("\"{exec}\"")
+    } else {
+        exec
+    };
+
+    let mut rewritten = String::with_capacity(desktop_entry.len());
+    for line in desktop_entry.lines() {
+        if let Some(command) = line.strip_prefix("Exec=") {
+            // Keep the arguments, like `%U`, but replace the program
+            let args = command
+                .split_once(' ')
+                .map(|(_, args)| format!(" {args}"))
+                .unwrap_or_default();
+            rewritten.push_str(&format!("Exec={exec}{args}"));
+        } else if line.starts_with("TryExec=") {
+            rewritten.push_str(&format!("TryExec={exec}"));
+        } else if let Some(icon) = icon
+            && line.starts_with("Icon=")
+        {
+            rewritten.push_str(&format!("Icon={}", icon.display()));
+        } else {
+            rewritten.push_str(line);
+        }
+        rewritten.push('\n');
+    }
+
+    rewritten
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_archives() {
+        let err = serde_yaml_bw::from_str::<AppImageProvider>(
+            r#"
+packages:
+  - name: obsidian
+    url: https://example.com/obsidian.tar.gz
+    archive:
+      member: Obsidian.AppImage
+"#,
+        )
+        .unwrap_err();
+
+        assert!(
+            err.to_string()
+                .contains("obsidian: AppImages are installed as downloaded"),
+            "{err}"
+        );
+    }
+
+    const DESKTOP_ENTRY: &str = "\
+[Desktop Entry]
+Name=Obsidian
+Exec=AppRun --no-sandbox %U
+TryExec=AppRun
+Icon=obsidian
+Categories=Office;
+
+[Desktop Action new-window]
+Name=New Window
+Exec=AppRun --new-window
+
+[Desktop Action vault]
+Name=Open Vault
+Exec=AppRun
+";
+
+    #[test]
+    fn points_desktop_entries_at_the_installed_files() {
+        let rewritten = rewrite_desktop_entry(
+            DESKTOP_ENTRY,
+            Path::new("/home/user/Applications/obsidian.AppImage"),
+            Some(Path::new("/home/user/.local/share/icons/bow-obsidian.png")),
+        );
+
+        assert_eq!(
+            rewritten,
+            "\
+[Desktop Entry]
+Name=Obsidian
+Exec=/home/user/Applications/obsidian.AppImage --no-sandbox %U
+TryExec=/home/user/Applications/obsidian.AppImage
+Icon=/home/user/.local/share/icons/bow-obsidian.png
+Categories=Office;
+
+[Desktop Action new-window]
+Name=New Window
+Exec=/home/user/Applications/obsidian.AppImage --new-window
+
+[Desktop Action vault]
+Name=Open Vault
+Exec=/home/user/Applications/obsidian.AppImage
+"
+        );
+    }
+
+    #[test]
+    fn keeps_the_icon_name_without_an_extracted_icon() {
+        let rewritten = rewrite_desktop_entry(
+            "[Desktop Entry]\nExec=AppRun\nIcon=obsidian\n",
+            Path::new("/home/user/My Apps/obsidian.AppImage"),
+            None,
+        );
+
+        assert_eq!(
+            rewritten,
+            "[Desktop Entry]\nExec=\"/home/user/My Apps/obsidian.AppImage\"\nIcon=obsidian\n"
+        );
+    }
+
+    #[test]
+    fn installs_to_the_install_folder_unless_given_a_path() {
+        let provider: AppImageProvider = serde_yaml_bw::from_str(
+            r#"
+install_folder: /opt/apps
+packages:
+  - name: obsidian
+    url: https://example.com/Obsidian.AppImage
+  - name: krita
+    url: https://example.com/krita.appimage
+    install_path: /usr/local/bin/krita
+"#,
+        )
+        .unwrap();
+
+        let paths: Vec<_> = provider
+            .appimages
+            .iter()
+            .map(|appimage| provider.install_path_of(appimage))
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                PathBuf::from("/opt/apps/obsidian.AppImage"),
+                PathBuf::from("/usr/local/bin/krita"),
+            ]
+        );
+    }
+}
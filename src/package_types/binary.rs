@@ -1,114 +1,324 @@
-use std::{fmt, io::Write, os::unix::fs::PermissionsExt, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::Write,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
 
 use serde::{
     Deserialize, Serialize,
     de::{self, Visitor},
 };
 
-use crate::package_types::PackageProvider;
-
-// We kind of have to use a sidecar-esque file here,
-// otherwise it's impossible to get e.g. the install URL of present binaries
-// this is also probably a bit hacky rn
-const INSTALLED_BINARIES_INFO_FILE: &'static str =
-    concat!(env!("HOME"), "/.local/share/bow-binaries.yaml");
+use crate::{
+    archive::{Archive, ArchiveFormat},
+    checksum::{Algorithm, Checksum, ChecksumSource},
+    error::{Context, Error},
+    lockfile::LockEntry,
+    package_types::PackageProvider,
+    paths,
+    release::{self, Forge, Release},
+    state::{self, State, StateEntry},
+    template,
+};
 
 #[derive(Debug)]
 pub struct BinaryProvider {
     install_folder: PathBuf,
     pub binaries: Vec<Binary>,
+    locked: Option<BTreeMap<String, LockEntry>>,
 }
 
 impl BinaryProvider {
-    fn new(install_path: &str, binaries: Vec<Binary>) -> Self {
-        // TODO: this is really hacky, I need to move it into the deserialization step
-
+    fn new(install_folder: PathBuf, binaries: Vec<Binary>) -> Self {
         Self {
-            install_folder: PathBuf::from(install_path),
+            install_folder,
             binaries,
+            locked: None,
         }
     }
 
-    fn new_with_binaries(binaries: Vec<Binary>) -> Self {
-        Self {
-            install_folder: PathBuf::new(),
-            binaries,
+    fn install_one(&self, binary: &Binary) -> crate::Result<()> {
+        let locked = match &self.locked {
+            Some(locked) => Some(binary.locked_entry(locked)?),
+            None => None,
+        };
+
+        let Download {
+            dir: tmp_dir,
+            file: tmp_file,
+            hash,
+        } = binary.download::<Self>(locked)?;
+        let install_path = &self.install_path_of(binary);
+
+        let files = match &binary.archive {
+            Some(archive) => Self::extract_members(
+                binary,
+                archive,
+                &tmp_file,
+                tmp_dir.path(),
+                install_path,
+                &self.install_folder,
+            )?,
+            None => vec![(tmp_file, install_path.clone())],
+        };
+
+        let data = serde_yaml_bw::to_value(binary).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("failed to record installed binary: {e}"),
+            )
+        })?;
+        let hash = hash.to_string();
+
+        // Reinstalling the same download keeps the backup of whatever was installed before it
+        let old = State::load()?.get(Self::LOG_PREFIX, &binary.name).cloned();
+        let reinstall = old.as_ref().is_some_and(|old| {
+            old.hash.as_ref() == Some(&hash) && old.data.as_ref() == Some(&data)
+        });
+        if let Some(old) = &old
+            && !reinstall
+        {
+            Self::backup(&binary.name, old)?;
         }
-    }
-}
 
-impl PackageProvider for BinaryProvider {
-    type Item = Binary;
+        let mut installed_files = Vec::new();
+        for (src, dest) in files {
+            place_executable::<Self>(&src, &dest)?;
+            installed_files.push(dest);
+        }
 
-    const LOG_PREFIX: &'static str = "binary";
+        Self::log_msg(&format!("Successfully installed {}", &binary.name));
 
-    fn install_items(&self, items: &[Self::Item]) -> crate::Result<()> {
-        let tmp_info_filepath = PathBuf::from([INSTALLED_BINARIES_INFO_FILE, ".tmp"].join(""));
-        let mut info_file = std::fs::File::create(&tmp_info_filepath)?;
+        // An upgrade may install fewer archive members than the previous version did
+        if let Some(old) = &old {
+            remove_stale::<Self>(&old.files, &installed_files);
+        }
 
-        for binary in items {
-            let tmp_dir = tempfile::Builder::new().prefix("bowbinary-").tempdir()?;
-            let response = reqwest::blocking::get(&binary.url)?;
+        let previous = match old {
+            Some(old) if reinstall => old.previous,
+            // Only a single version is ever kept around to roll back to
+            Some(old) => Some(Box::new(StateEntry {
+                previous: None,
+                ..old
+            })),
+            None => None,
+        };
+        let entry = StateEntry {
+            hash: Some(hash),
+            files: installed_files,
+            data: Some(data),
+            previous,
+            ..StateEntry::now()
+        };
+        state::record(Self::LOG_PREFIX, &binary.name, entry)?;
+
+        Ok(())
+    }
 
-            if let Err(e) = response.error_for_status_ref() {
-                return Err(e.into());
+    fn remove_one(&self, binary: &Binary) -> crate::Result<()> {
+        let files = State::load()?
+            .get(Self::LOG_PREFIX, &binary.name)
+            .map(|entry| entry.files.clone())
+            .filter(|files| !files.is_empty())
+            .unwrap_or_else(|| vec![self.install_path_of(binary)]);
+
+        for file in files {
+            Self::log_msg(&format!("Removing {}", file.display()));
+            match std::fs::remove_file(&file) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    Self::log_err(format!("{} was already removed", file.display()))
+                }
+                Err(e) => return Err(e.into()),
             }
+        }
 
-            let install_path = &binary
-                .install_path
-                .clone()
-                .unwrap_or(self.install_folder.join(&binary.name));
-            let tmp_file = tmp_dir.path().join(&binary.name);
-            Self::log_msg(&format!(
-                "Downloading {} to {}",
-                &binary.name,
-                tmp_file.display()
+        state::forget(Self::LOG_PREFIX, &binary.name)?;
+
+        let backup_dir = state::backup_dir(Self::LOG_PREFIX, &binary.name);
+        if backup_dir.exists() {
+            std::fs::remove_dir_all(&backup_dir)?;
+        }
+
+        Self::log_msg(&format!("Successfully removed {}", &binary.name));
+
+        Ok(())
+    }
+
+    /// Copy the currently installed files of `name` into its backup directory,
+    /// replacing any older backup
+    fn backup(name: &str, installed: &StateEntry) -> crate::Result<()> {
+        let backup_dir = state::backup_dir(Self::LOG_PREFIX, name);
+        if backup_dir.exists() {
+            std::fs::remove_dir_all(&backup_dir)?;
+        }
+        std::fs::create_dir_all(&backup_dir)?;
+
+        for file in installed.files.iter().filter(|f| f.exists()) {
+            let backup = Self::backup_path(&backup_dir, file);
+            Self::log_debug(&format!(
+                "Backing up {} to {}",
+                file.display(),
+                backup.display()
             ));
+            std::fs::copy(file, &backup)?;
+        }
 
-            let mut dest = std::fs::File::create(&tmp_file)?;
-            let content = response.bytes()?;
-            dest.write_all(&content)?;
+        Ok(())
+    }
+
+    fn backup_path(backup_dir: &Path, file: &Path) -> PathBuf {
+        backup_dir.join(file.file_name().unwrap_or(file.as_os_str()))
+    }
 
-            Self::log_msg(&format!("Succesfully downloaded {}", &binary.name));
+    /// Restore the version of `name` that was installed before its last upgrade, along with its state entry
+    pub fn rollback(name: &str) -> crate::Result<()> {
+        let mut state = State::load()?;
+        let current = state
+            .get(Self::LOG_PREFIX, name)
+            .cloned()
+            .ok_or(Error::NoBackup)?;
+        let previous = *current.previous.ok_or(Error::NoBackup)?;
+
+        // Restoring only some of the files would leave a mix of both versions behind
+        let backup_dir = state::backup_dir(Self::LOG_PREFIX, name);
+        let missing: Vec<_> = previous
+            .files
+            .iter()
+            .map(|file| Self::backup_path(&backup_dir, file))
+            .filter(|backup| !backup.exists())
+            .map(|backup| backup.display().to_string())
+            .collect();
+        if !missing.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!(
+                    "backups are missing, nothing was rolled back: {}",
+                    missing.join(", ")
+                ),
+            )
+            .into());
+        }
 
+        for file in previous.files.iter() {
+            let backup = Self::backup_path(&backup_dir, file);
+            let staged = staging_path(file);
             Self::log_msg(&format!(
-                "Copying {} to {}",
-                tmp_file.display(),
-                install_path.display()
+                "Restoring {} from {}",
+                file.display(),
+                backup.display()
             ));
-            let dest = binary
-                .install_path
-                .clone()
-                .unwrap_or(self.install_folder.join(&binary.name));
-            std::fs::copy(tmp_file, &dest)?;
-
-            Self::log_msg(&format!("Succesfully copied {}", &binary.name));
-            Self::log_msg(&format!("Setting {} to be executable", &binary.name));
-
-            let mut perms = std::fs::metadata(&dest)?.permissions();
-            perms.set_mode(755);
-            std::fs::set_permissions(&dest, perms)?;
-
-            Self::log_msg(&format!("Successfully installed {}", &binary.name));
-
-            let info = serde_yaml_bw::to_string(&[&binary]).or_else(|e| {
-                Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    format!("failed to write info yaml for {}: {e}", &binary.name),
-                ))
-            })?;
-            info_file.write_all(&info.as_bytes())?;
+
+            // The copy keeps the backup's permissions, so it stays executable
+            std::fs::copy(&backup, &staged)?;
+            std::fs::rename(&staged, file)?;
+        }
+
+        remove_stale::<Self>(&current.files, &previous.files);
+
+        let version = previous
+            .data
+            .clone()
+            .and_then(|data| serde_yaml_bw::from_value::<Binary>(data).ok())
+            .and_then(|binary| binary.version);
+
+        state.insert(Self::LOG_PREFIX, name, previous);
+        state.save()?;
+        std::fs::remove_dir_all(&backup_dir)?;
+
+        match version {
+            Some(version) => Self::log_msg(&format!("Rolled back {name} to {version}")),
+            None => Self::log_msg(&format!("Rolled back {name}")),
         }
 
-        std::fs::copy(
-            &tmp_info_filepath,
-            PathBuf::from(INSTALLED_BINARIES_INFO_FILE),
-        )?;
-        std::fs::remove_file(&tmp_info_filepath)?;
+        Ok(())
+    }
+
+    fn install_path_of(&self, binary: &Binary) -> PathBuf {
+        binary
+            .install_path
+            .clone()
+            .unwrap_or_else(|| self.install_folder.join(&binary.name))
+    }
+
+    /// Every binary bow has installed, along with its state entry
+    fn installed_entries() -> crate::Result<Vec<(Binary, StateEntry)>> {
+        state::installed(Self::LOG_PREFIX)
+    }
+
+    /// Extract an archived download and work out where each selected member should be installed to
+    ///
+    /// A single selected member is installed as the binary itself, multiple members keep their file names
+    fn extract_members(
+        binary: &Binary,
+        archive: &Archive,
+        archive_path: &Path,
+        tmp_dir: &Path,
+        install_path: &Path,
+        install_folder: &Path,
+    ) -> crate::Result<Vec<(PathBuf, PathBuf)>> {
+        let format = archive
+            .format
+            .or_else(|| ArchiveFormat::detect(binary.file_name()))
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "could not detect archive format of {}, please set `archive.format`",
+                        binary.file_name()
+                    ),
+                )
+            })?;
+
+        let extract_dir = tmp_dir.join("extracted");
         Self::log_msg(&format!(
-            "Wrote installed binary info to {}",
-            INSTALLED_BINARIES_INFO_FILE
+            "Extracting {} to {}",
+            archive_path.display(),
+            extract_dir.display()
         ));
+        let extracted = archive.extract(format, archive_path, &extract_dir)?;
+        let selected = archive.select(&extracted, &binary.name)?;
+
+        if let [member] = selected.as_slice() {
+            return Ok(vec![(extract_dir.join(member), install_path.to_path_buf())]);
+        }
+
+        Ok(selected
+            .into_iter()
+            .filter_map(|member| {
+                let file_name = member.file_name()?;
+                Some((extract_dir.join(member), install_folder.join(file_name)))
+            })
+            .collect())
+    }
+}
+
+impl PackageProvider for BinaryProvider {
+    type Item = Binary;
+    type Key = String;
+
+    const LOG_PREFIX: &'static str = "binary";
+
+    fn key(item: &Self::Item) -> Self::Key {
+        item.name.clone()
+    }
+
+    fn describe_upgrade(old: &Self::Item, new: &Self::Item) -> String {
+        match (&old.version, &new.version) {
+            (Some(old_version), Some(new_version)) if old_version != new_version => {
+                format!("{old_version} -> {new_version}")
+            }
+            _ if old.url != new.url => format!("{} -> {}", old.url, new.url),
+            _ => String::from("configuration changed"),
+        }
+    }
+
+    fn install_items(&self, items: &[Self::Item]) -> crate::Result<()> {
+        for binary in items {
+            self.install_one(binary).for_package(&binary.name)?;
+        }
 
         Self::log_msg(&format!("Successfully installed {} binaries", items.len()));
 
@@ -116,41 +326,73 @@ impl PackageProvider for BinaryProvider {
     }
 
     fn remove_items(&self, items: &[Self::Item]) -> crate::Result<()> {
-        todo!()
+        for binary in items {
+            self.remove_one(binary).for_package(&binary.name)?;
+        }
+
+        Self::log_msg(&format!("Successfully removed {} binaries", items.len()));
+
+        Ok(())
     }
 
     fn ensure(&self) -> crate::Result<()> {
-        todo!()
+        self.keyed_ensure()
     }
 
-    fn get_installed(&self) -> crate::Result<Vec<Self::Item>> {
-        let info_file = PathBuf::from(INSTALLED_BINARIES_INFO_FILE);
+    fn declared(&self) -> &[Self::Item] {
+        &self.binaries
+    }
 
-        if !info_file.exists() {
-            Self::log_err(&format!(
-                "Info file does not exist at {}, assuming first run. Creating...",
-                info_file.display()
-            ));
+    fn lock_entries(&self, items: &[Self::Item]) -> crate::Result<Vec<(String, LockEntry)>> {
+        let installed = Self::installed_entries()?;
+
+        Ok(items
+            .iter()
+            .filter_map(|binary| installed.iter().find(|(b, _)| b.name == binary.name))
+            .map(|(binary, state)| {
+                let entry = LockEntry {
+                    url: Some(binary.url.clone()),
+                    version: binary.release.as_ref().and(binary.version.clone()),
+                    hash: state.hash.clone(),
+                    installed_at: state.installed_at,
+                    ..Default::default()
+                };
+                (binary.name.clone(), entry)
+            })
+            .collect())
+    }
 
-            match std::fs::File::create(&info_file) {
-                Ok(_) => Self::log_msg(&format!("Created info file at {}", info_file.display())),
-                Err(e) => {
-                    Self::log_err(&format!("Failed to create info file: {e}"));
-                    panic!()
-                }
-            }
+    fn set_locked(&mut self, locked: BTreeMap<String, LockEntry>) {
+        self.locked = Some(locked);
+    }
 
-            return Ok(Vec::new());
+    /// Look up the URL, version and checksum of every binary published as a release,
+    /// using what's locked instead when installing from the lockfile
+    fn resolve(&mut self) -> crate::Result<()> {
+        for binary in self.binaries.iter_mut() {
+            binary.resolve::<Self>(self.locked.as_ref())?;
         }
 
-        let info = std::fs::read_to_string(info_file)?;
-        match serde_yaml_bw::from_str::<Vec<Binary>>(&info) {
-            Ok(d) => Ok(d),
-            Err(e) => {
-                Self::log_err(e);
-                panic!()
+        Ok(())
+    }
+
+    /// Resolve releases to the installed version where it still satisfies the declared one
+    fn resolve_offline(&mut self) -> crate::Result<()> {
+        let installed = self.get_installed()?;
+        for binary in self.binaries.iter_mut() {
+            if let Some(old) = installed.iter().find(|b| b.name == binary.name) {
+                binary.resolve_from(old);
             }
         }
+
+        Ok(())
+    }
+
+    fn get_installed(&self) -> crate::Result<Vec<Self::Item>> {
+        Ok(Self::installed_entries()?
+            .into_iter()
+            .map(|(binary, _)| binary)
+            .collect())
     }
 }
 
@@ -167,28 +409,237 @@ impl<'de> Deserialize<'de> for BinaryProvider {
         }
 
         let f = Fields::deserialize(deserializer)?;
-        // TODO: fix duplicated code
-        let home_dir = std::env::home_dir()
-            .ok_or_else(|| de::Error::custom("Failed to retrieve user home directory"))?
-            .into_os_string()
-            .into_string()
-            .unwrap();
-        let install_folder = f.install_folder.replace("$HOME", &home_dir);
+        let install_folder = paths::expand(&f.install_folder).map_err(de::Error::custom)?;
 
-        Ok(Self::new(&install_folder, f.binaries))
+        Ok(Self::new(install_folder, f.binaries))
     }
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize)]
 pub struct Binary {
-    name: String,
-    url: String,
+    pub(super) name: String,
+    pub(super) url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) version: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    version: Option<String>,
+    sum: Option<ChecksumSource>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    sum: Option<String>,
+    pub(super) install_path: Option<PathBuf>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    install_path: Option<PathBuf>,
+    pub(super) archive: Option<Archive>,
+    /// Where to look up `url` from, if the binary is published as a release on a forge
+    #[serde(flatten)]
+    release: Option<Release>,
+}
+
+fn staging_path(dest: &Path) -> PathBuf {
+    let file_name = dest
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    dest.with_file_name(format!(".{file_name}.bow-new"))
+}
+
+/// Copy `src` to `dest` and make it executable. It's staged next to `dest` and renamed over it,
+/// so an interrupted upgrade never leaves a half-written executable behind
+pub(super) fn place_executable<P: PackageProvider>(src: &Path, dest: &Path) -> crate::Result<()> {
+    if let Some(dir) = dest.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let staged = staging_path(dest);
+    P::log_msg(&format!(
+        "Copying {} to {}",
+        src.display(),
+        staged.display()
+    ));
+    std::fs::copy(src, &staged)?;
+
+    P::log_debug(&format!("Succesfully copied {}", staged.display()));
+    P::log_debug(&format!("Setting {} to be executable", staged.display()));
+
+    let mut perms = std::fs::metadata(&staged)?.permissions();
+    perms.set_mode(0o755);
+    std::fs::set_permissions(&staged, perms)?;
+
+    P::log_msg(&format!("Moving {} into place", dest.display()));
+    std::fs::rename(&staged, dest)?;
+
+    Ok(())
+}
+
+/// Remove every file in `old` that isn't also in `new`
+pub(super) fn remove_stale<P: PackageProvider>(old: &[PathBuf], new: &[PathBuf]) {
+    for stale in old.iter().filter(|f| !new.contains(f)) {
+        P::log_msg(&format!("Removing stale file {}", stale.display()));
+        if let Err(e) = std::fs::remove_file(stale) {
+            P::log_err(format!("Failed to remove {}: {e}", stale.display()));
+        }
+    }
+}
+
+/// A verified download, which lives as long as `dir` does
+pub(super) struct Download {
+    pub dir: tempfile::TempDir,
+    pub file: PathBuf,
+    /// sha256 of the downloaded file
+    pub hash: Checksum,
+}
+
+impl Binary {
+    /// The file name of the download, as it would appear in a checksum file
+    fn file_name(&self) -> &str {
+        self.url
+            .rsplit('/')
+            .next()
+            .filter(|n| !n.is_empty())
+            .unwrap_or(&self.name)
+    }
+
+    /// Find the lock entry for this binary, making sure it still points at the same URL
+    pub(super) fn locked_entry<'a>(
+        &self,
+        locked: &'a BTreeMap<String, LockEntry>,
+    ) -> crate::Result<&'a LockEntry> {
+        let entry = locked.get(&self.name).ok_or(Error::NotLocked)?;
+
+        match &entry.url {
+            Some(url) if *url != self.url => Err(Error::LockMismatch {
+                expected: url.clone(),
+                actual: self.url.clone(),
+            }),
+            _ => Ok(entry),
+        }
+    }
+
+    /// Look up the URL, version and checksum of a binary published as a release,
+    /// using what's `locked` instead when installing from the lockfile
+    pub(super) fn resolve<P: PackageProvider>(
+        &mut self,
+        locked: Option<&BTreeMap<String, LockEntry>>,
+    ) -> crate::Result<()> {
+        let Some(release) = &self.release else {
+            return Ok(());
+        };
+
+        if let Some(locked) = locked {
+            if let Some(entry) = locked.get(&self.name)
+                && let Some(url) = &entry.url
+            {
+                self.url = url.clone();
+                self.version = entry.version.clone();
+            }
+            return self.render_sum();
+        }
+
+        let constraint = self.version.as_deref().unwrap_or("latest");
+        let resolved = release
+            .resolve(&self.name, constraint)
+            .for_package(&self.name)?;
+        P::log_debug(&format!(
+            "Resolved {} {constraint} to {} ({})",
+            self.name, resolved.tag, resolved.url
+        ));
+
+        self.url = resolved.url;
+        self.version = Some(resolved.tag);
+        self.render_sum()?;
+        if self.sum.is_none() {
+            self.sum = resolved.checksum_url.map(ChecksumSource::Url);
+        }
+
+        Ok(())
+    }
+
+    /// Render the checksum URL of a release with the version it resolved to
+    fn render_sum(&mut self) -> crate::Result<()> {
+        if let Some(ChecksumSource::Url(url)) = &mut self.sum {
+            let cx = template::Context {
+                name: &self.name,
+                version: self.version.as_deref(),
+            };
+            *url = template::render(url, cx)
+                .map_err(|e| Error::from(std::io::Error::new(std::io::ErrorKind::InvalidInput, e)))
+                .for_package(&self.name)?;
+        }
+
+        Ok(())
+    }
+
+    /// Take the URL, version and checksum of the `installed` release if its version still satisfies this one,
+    /// which resolves a release without looking it up
+    pub(super) fn resolve_from(&mut self, installed: &Binary) {
+        let Some(release) = &self.release else {
+            return;
+        };
+
+        let constraint = self.version.as_deref().unwrap_or("latest");
+        if installed.release.as_ref() == Some(release)
+            && let Some(tag) = &installed.version
+            && release::satisfies(constraint, tag)
+        {
+            self.url = installed.url.clone();
+            self.version = installed.version.clone();
+            self.sum = installed.sum.clone();
+        }
+    }
+
+    /// Download this into a temporary directory, verifying it against its checksum and `locked`
+    pub(super) fn download<P: PackageProvider>(
+        &self,
+        locked: Option<&LockEntry>,
+    ) -> crate::Result<Download> {
+        let dir = tempfile::Builder::new()
+            .prefix(&format!("bow{}-", P::LOG_PREFIX))
+            .tempdir()?;
+        let response = reqwest::blocking::get(&self.url)?;
+
+        if let Err(e) = response.error_for_status_ref() {
+            return Err(e.into());
+        }
+
+        let file = dir.path().join(self.file_name());
+        P::log_msg(&format!("Downloading {} to {}", &self.name, file.display()));
+
+        let content = response.bytes()?;
+        P::log_msg(&format!("Succesfully downloaded {}", &self.name));
+
+        if let Some(sum) = &self.sum {
+            let expected = sum.resolve(self.file_name())?;
+            if let Err(actual) = expected.verify(&content) {
+                return Err(Error::ChecksumMismatch {
+                    expected: expected.digest,
+                    actual,
+                });
+            }
+
+            P::log_msg(&format!(
+                "Verified {} checksum of {}",
+                expected.algorithm, &self.name
+            ));
+        }
+
+        let hash = Checksum {
+            algorithm: Algorithm::Sha256,
+            digest: Algorithm::Sha256.digest(&content),
+        };
+
+        if let Some(expected) = locked
+            .and_then(|entry| entry.hash.as_deref())
+            .and_then(Checksum::parse_inline)
+            && let Err(actual) = expected.verify(&content)
+        {
+            return Err(Error::LockMismatch {
+                expected: expected.to_string(),
+                actual: format!("{}:{actual}", expected.algorithm),
+            });
+        }
+
+        let mut dest = std::fs::File::create(&file)?;
+        dest.write_all(&content)?;
+
+        Ok(Download { dir, file, hash })
+    }
 }
 
 impl<'de> Deserialize<'de> for Binary {
@@ -205,6 +656,14 @@ impl<'de> Deserialize<'de> for Binary {
             Sum,
             #[serde(rename = "install_path")]
             InstallPath,
+            Archive,
+            Github,
+            Gitlab,
+            Gitea,
+            Asset,
+            #[serde(rename = "checksum_asset")]
+            ChecksumAsset,
+            Api,
         }
 
         struct BinaryVisitor;
@@ -225,6 +684,11 @@ impl<'de> Deserialize<'de> for Binary {
                 let mut version: Option<String> = None;
                 let mut sum: Option<String> = None;
                 let mut install_path: Option<String> = None;
+                let mut archive: Option<Archive> = None;
+                let mut repo: Option<(Forge, String)> = None;
+                let mut asset: Option<String> = None;
+                let mut checksum_asset: Option<String> = None;
+                let mut api: Option<String> = None;
 
                 while let Some(key) = map.next_key()? {
                     match key {
@@ -263,61 +727,274 @@ impl<'de> Deserialize<'de> for Binary {
                                 install_path = Some(map.next_value()?);
                             }
                         }
+                        Field::Archive => {
+                            if archive.is_some() {
+                                return Err(de::Error::duplicate_field("archive"));
+                            } else {
+                                archive = Some(map.next_value()?);
+                            }
+                        }
+                        Field::Github | Field::Gitlab | Field::Gitea => {
+                            let forge = match key {
+                                Field::Github => Forge::Github,
+                                Field::Gitlab => Forge::Gitlab,
+                                _ => Forge::Gitea,
+                            };
+                            if repo.is_some() {
+                                return Err(de::Error::custom(
+                                    "only one of `github`, `gitlab` or `gitea` can be set",
+                                ));
+                            } else {
+                                repo = Some((forge, map.next_value()?));
+                            }
+                        }
+                        Field::Asset => {
+                            if asset.is_some() {
+                                return Err(de::Error::duplicate_field("asset"));
+                            } else {
+                                asset = Some(map.next_value()?);
+                            }
+                        }
+                        Field::ChecksumAsset => {
+                            if checksum_asset.is_some() {
+                                return Err(de::Error::duplicate_field("checksum_asset"));
+                            } else {
+                                checksum_asset = Some(map.next_value()?);
+                            }
+                        }
+                        Field::Api => {
+                            if api.is_some() {
+                                return Err(de::Error::duplicate_field("api"));
+                            } else {
+                                api = Some(map.next_value()?);
+                            }
+                        }
                     }
                 }
 
                 let name = name.ok_or_else(|| de::Error::missing_field("name"))?;
-                let mut url = url.ok_or_else(|| de::Error::missing_field("url"))?;
-                let install_path = {
-                    if let Some(install_path) = install_path {
-                        if install_path.contains("$HOME") {
-                            let home_dir = std::env::home_dir()
-                                .ok_or_else(|| {
-                                    de::Error::custom("Failed to retrieve user home directory")
-                                })?
-                                .into_os_string()
-                                .into_string()
-                                .unwrap();
-                            let install_path = install_path.replace("$HOME", &home_dir);
-                            Some(PathBuf::from(install_path))
-                        } else {
-                            Some(PathBuf::from(install_path))
-                        }
-                    } else {
-                        None
+
+                let release = match repo {
+                    Some((forge, repo)) => Some(Release {
+                        forge,
+                        repo,
+                        asset: asset.ok_or_else(|| de::Error::missing_field("asset"))?,
+                        checksum_asset,
+                        api,
+                    }),
+                    None if asset.is_some() || checksum_asset.is_some() || api.is_some() => {
+                        return Err(de::Error::custom(
+                            "`asset`, `checksum_asset` and `api` need one of `github`, `gitlab` or `gitea`",
+                        ));
                     }
+                    None => None,
                 };
 
-                const VERSION_REPLACEMENT_STR: &str = "{{ version }}";
-                if url.contains(VERSION_REPLACEMENT_STR) {
-                    let version = version
-                        .as_ref()
-                        .ok_or_else(|| de::Error::missing_field("version"))?;
-                    url = url.replace(VERSION_REPLACEMENT_STR, &version);
-                    sum = sum.map(|s| {
-                        if s.contains(VERSION_REPLACEMENT_STR) {
-                            s.replace(VERSION_REPLACEMENT_STR, &version)
-                        } else {
-                            BinaryProvider::log_err(&format!(
-                                "WARN: You're using {VERSION_REPLACEMENT_STR} in the URL for {name}, but not for its checksum"
-                            ));
-                            s
-                        }
-                    });
+                // Releases are resolved to a URL later on, unless this was already resolved before
+                let mut url = match (url, &release) {
+                    (Some(url), _) => url,
+                    (None, Some(_)) => String::new(),
+                    (None, None) => return Err(de::Error::missing_field("url")),
+                };
+                if release.is_some() && version.is_none() {
+                    version = Some(String::from("latest"));
                 }
+                let cx = template::Context {
+                    name: &name,
+                    version: version.as_deref(),
+                };
+                let install_path = install_path
+                    .map(|p| paths::expand(&template::render(&p, cx)?))
+                    .transpose()
+                    .map_err(de::Error::custom)?;
+
+                // A release's version is only known once it's resolved, so its checksum is rendered then
+                if release.is_none() {
+                    if let Some(s) = &sum
+                        && template::uses(&url, "version")
+                        && !template::uses(s, "version")
+                        && Checksum::parse_inline(s).is_none()
+                    {
+                        BinaryProvider::log_err(format!(
+                            "WARN: You're using {{{{ version }}}} in the URL for {name}, but not for its checksum"
+                        ));
+                    }
+
+                    url = template::render(&url, cx).map_err(de::Error::custom)?;
+                    sum = sum
+                        .map(|s| template::render(&s, cx))
+                        .transpose()
+                        .map_err(de::Error::custom)?;
+                }
+
+                let sum = sum
+                    .as_deref()
+                    .map(ChecksumSource::parse)
+                    .transpose()
+                    .map_err(de::Error::custom)?;
 
-                // TODO: we should probably save the actual sum instead of a url pointing to it
                 Ok(Binary {
                     name,
                     url,
                     sum,
                     install_path,
                     version,
+                    archive,
+                    release,
                 })
             }
         }
 
-        const FIELDS: &[&str] = &["name", "url", "version", "sum"];
+        const FIELDS: &[&str] = &[
+            "name",
+            "url",
+            "version",
+            "sum",
+            "install_path",
+            "archive",
+            "github",
+            "gitlab",
+            "gitea",
+            "asset",
+            "checksum_asset",
+            "api",
+        ];
         deserializer.deserialize_struct("Binary", FIELDS, BinaryVisitor)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locked_releases_render_their_checksum_url() {
+        let mut binary: Binary = serde_yaml_bw::from_str(
+            r#"
+name: tool
+github: owner/tool
+asset: tool-*-linux.tar.gz
+version: ^1
+sum: https://example.com/tool/{{ version }}/SHA256SUMS
+"#,
+        )
+        .unwrap();
+        let locked = BTreeMap::from([(
+            "tool".to_string(),
+            LockEntry {
+                url: Some("https://example.com/tool/v1.2.0/tool-v1.2.0-linux.tar.gz".to_string()),
+                version: Some("v1.2.0".to_string()),
+                ..LockEntry::default()
+            },
+        )]);
+
+        binary.resolve::<BinaryProvider>(Some(&locked)).unwrap();
+
+        assert_eq!(binary.version.as_deref(), Some("v1.2.0"));
+        assert_eq!(
+            binary.sum,
+            Some(ChecksumSource::Url(
+                "https://example.com/tool/v1.2.0/SHA256SUMS".to_string()
+            ))
+        );
+    }
+
+    /// Record `files` in `dir` as the installed version of `tool`, `previous` as the version before it
+    /// and `backups` as the files its backup holds
+    fn installed_with_backup(
+        dir: &Path,
+        files: &[&str],
+        previous: &[&str],
+        backups: &[&str],
+    ) -> (Vec<PathBuf>, Vec<PathBuf>) {
+        let current: Vec<_> = files.iter().map(|file| dir.join(file)).collect();
+        for file in &current {
+            std::fs::write(file, "new").unwrap();
+        }
+
+        let backup_dir = state::backup_dir(BinaryProvider::LOG_PREFIX, "tool");
+        std::fs::create_dir_all(&backup_dir).unwrap();
+        for file in backups {
+            std::fs::write(backup_dir.join(file), "old").unwrap();
+        }
+
+        let previous: Vec<_> = previous.iter().map(|file| dir.join(file)).collect();
+        let entry = StateEntry {
+            files: current.clone(),
+            previous: Some(Box::new(StateEntry {
+                installed_at: 1,
+                files: previous.clone(),
+                ..StateEntry::default()
+            })),
+            ..StateEntry::default()
+        };
+        state::record(BinaryProvider::LOG_PREFIX, "tool", entry).unwrap();
+
+        (current, previous)
+    }
+
+    #[test]
+    fn rolls_back_to_the_backup_of_the_previous_version() {
+        state::with_state_dir(|_| {
+            let dir = tempfile::tempdir().unwrap();
+            let (_, previous) = installed_with_backup(
+                dir.path(),
+                &["tool", "tool-extra"],
+                &["tool", "helper"],
+                &["tool", "helper"],
+            );
+
+            BinaryProvider::rollback("tool").unwrap();
+
+            for file in &previous {
+                assert_eq!(std::fs::read_to_string(file).unwrap(), "old");
+            }
+            assert!(!dir.path().join("tool-extra").exists());
+            let entry = State::load()
+                .unwrap()
+                .get("binary", "tool")
+                .cloned()
+                .unwrap();
+            assert_eq!(entry.installed_at, 1);
+            assert_eq!(entry.files, previous);
+            assert!(!state::backup_dir(BinaryProvider::LOG_PREFIX, "tool").exists());
+        });
+    }
+
+    #[test]
+    fn does_not_roll_back_with_missing_backups() {
+        state::with_state_dir(|_| {
+            let dir = tempfile::tempdir().unwrap();
+            let (current, _) =
+                installed_with_backup(dir.path(), &["tool"], &["tool", "helper"], &["tool"]);
+
+            let err = BinaryProvider::rollback("tool").unwrap_err();
+
+            assert!(err.to_string().contains("helper"), "{err}");
+            assert_eq!(std::fs::read_to_string(&current[0]).unwrap(), "new");
+            assert!(!dir.path().join("helper").exists());
+            let entry = State::load()
+                .unwrap()
+                .get("binary", "tool")
+                .cloned()
+                .unwrap();
+            assert_eq!(entry.files, current);
+            assert!(entry.previous.is_some());
+        });
+    }
+
+    #[test]
+    fn places_executables_in_folders_that_do_not_exist_yet() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("download");
+        std::fs::write(&src, "#!/bin/sh\n").unwrap();
+        let dest = dir.path().join("new/bin/tool");
+
+        place_executable::<BinaryProvider>(&src, &dest).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "#!/bin/sh\n");
+        let mode = std::fs::metadata(&dest).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+        assert!(!staging_path(&dest).exists());
+    }
+}
@@ -0,0 +1,873 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use serde::{
+    Deserialize, Serialize,
+    de::{self, Visitor},
+};
+
+use crate::{
+    error::{Context, Error},
+    lockfile::LockEntry,
+    package_types::PackageProvider,
+    paths,
+    state::{self, State, StateEntry},
+    stow::{self, Farm, FileRules, IgnoreList, Mode, Options, Planner, Rules, Strategy, Task},
+};
+
+const DEFAULT_TARGET: &str = "~";
+
+#[derive(Debug)]
+pub struct DotfilesProvider {
+    /// The stow directory, defaults to the directory holding the config
+    dir: Option<PathBuf>,
+    target: PathBuf,
+    options: Options,
+    /// Only print what would change, like `stow -n`
+    dry_run: bool,
+    pub packages: Vec<Dotfiles>,
+}
+
+impl DotfilesProvider {
+    fn new(
+        dir: Option<PathBuf>,
+        target: PathBuf,
+        options: Options,
+        packages: Vec<Dotfiles>,
+    ) -> Self {
+        Self {
+            dir,
+            target,
+            options,
+            dry_run: false,
+            packages,
+        }
+    }
+
+    /// A provider for a single `bow stow` run, outside of any config
+    pub fn for_stow(
+        dir: PathBuf,
+        target: PathBuf,
+        options: Options,
+        dry_run: bool,
+    ) -> crate::Result<Self> {
+        let mut provider = Self::new(Some(dir), target, options, Vec::new());
+        provider.dry_run = dry_run;
+        provider.resolve()?;
+
+        Ok(provider)
+    }
+
+    /// Unstow and then stow packages by name, the way a single `stow` invocation does
+    pub fn stow_packages(&self, unstow: &[String], stow: &[String]) -> crate::Result<()> {
+        let dir = self
+            .dir
+            .as_deref()
+            .expect("`for_stow` resolves the stow directory");
+        let packages = |names: &[String]| {
+            names
+                .iter()
+                .map(|name| {
+                    Dotfiles::new(name, dir, &self.target).map_err(|message| {
+                        std::io::Error::new(std::io::ErrorKind::InvalidInput, message).into()
+                    })
+                })
+                .collect::<crate::Result<Vec<_>>>()
+        };
+
+        if !unstow.is_empty() {
+            self.remove_items(&packages(unstow)?)?;
+        }
+        if !stow.is_empty() {
+            self.install_items(&packages(stow)?)?;
+        }
+        if self.dry_run {
+            println!("WARNING: in simulation mode so not modifying filesystem.");
+        }
+
+        Ok(())
+    }
+
+    fn recorded() -> crate::Result<Vec<(Dotfiles, StateEntry)>> {
+        state::installed(Self::LOG_PREFIX)
+    }
+
+    /// Every directory bow created in a target, which it's free to remove or fold back into a link
+    fn created_dirs(recorded: &[(Dotfiles, StateEntry)]) -> BTreeSet<PathBuf> {
+        recorded
+            .iter()
+            .flat_map(|(_, entry)| entry.files.iter())
+            .filter(|path| stow::is_real_dir(path))
+            .cloned()
+            .collect()
+    }
+
+    /// Set how files in the way of links are dealt with, overriding the config
+    pub fn set_on_conflict(&mut self, strategy: Strategy) {
+        self.options.on_conflict = strategy;
+    }
+
+    /// What was recorded about the files of `entry` besides its links
+    fn extras(entry: &StateEntry) -> Extras {
+        entry
+            .data
+            .clone()
+            .and_then(|data| serde_yaml_bw::from_value(data).ok())
+            .unwrap_or_default()
+    }
+
+    /// A planner that knows which directories and written files in the target belong to bow,
+    /// and how the files of every package are put into it
+    fn planner(&self, recorded: &[(Dotfiles, StateEntry)]) -> Planner {
+        let known = recorded
+            .iter()
+            .flat_map(|(_, entry)| Self::extras(entry).written)
+            .collect();
+
+        let mut options = self.options.clone();
+        let packages = recorded.iter().map(|(p, _)| p).chain(self.packages.iter());
+        options.rules = packages
+            .map(|p| (p.name.clone(), p.rules.clone()))
+            .collect();
+
+        Planner::new(options, Self::created_dirs(recorded), known)
+    }
+
+    /// Whether every link and written file of `package` is in place
+    fn in_sync(
+        &self,
+        package: &Dotfiles,
+        entry: &StateEntry,
+        recorded: &[(Dotfiles, StateEntry)],
+    ) -> bool {
+        let farm = package.farm();
+        let mut planner = self.planner(recorded);
+        if planner
+            .unlink_stale(&farm, &package.name, &entry.files)
+            .is_err()
+            || planner.stow(&farm, &package.name).is_err()
+        {
+            return false;
+        }
+
+        let (tasks, conflicts) = planner.finish();
+        tasks.is_empty() && conflicts.is_empty()
+    }
+
+    /// Run the planned tasks unless anything conflicts, returning the tasks that ran.
+    /// In a dry run the tasks are only printed
+    fn run(&self, planner: Planner) -> crate::Result<Vec<Task>> {
+        let (tasks, conflicts) = planner.finish();
+        if !conflicts.is_empty() {
+            return Err(Error::Conflicts(conflicts));
+        }
+
+        if self.dry_run {
+            for task in tasks {
+                println!("{task}");
+            }
+            return Ok(Vec::new());
+        }
+
+        for task in tasks.iter() {
+            Self::log_debug(&task.to_string());
+            task.execute()?;
+        }
+
+        Ok(tasks)
+    }
+
+    /// Plan restowing `package`, starting from where it was recorded to be stowed from if it moved
+    fn plan_install(
+        &self,
+        package: &Dotfiles,
+        recorded: &[(Dotfiles, StateEntry)],
+    ) -> crate::Result<Planner> {
+        let mut planner = self.planner(recorded);
+
+        let old = recorded.iter().find(|(p, _)| p.name == package.name);
+        if let Some((old, entry)) = old {
+            let farm = old.farm();
+            planner.unstow(&farm, &old.name)?;
+            planner.unlink_stale(&farm, &old.name, &entry.files)?;
+        }
+        planner.unstow(&package.farm(), &package.name)?;
+        planner.stow(&package.farm(), &package.name)?;
+
+        Ok(planner)
+    }
+
+    /// Stow a package, restowing it if it's already stowed so that links to files it no longer has are removed.
+    /// Unstowing first also covers packages that were stowed without bow, e.g. by GNU Stow itself
+    fn install_one(&self, package: &Dotfiles) -> crate::Result<()> {
+        let recorded = Self::recorded()?;
+        let planner = self.plan_install(package, &recorded)?;
+        let written = planner.written().clone();
+
+        let tasks = self.run(planner)?;
+        if self.dry_run {
+            return Ok(());
+        }
+
+        let old = recorded
+            .iter()
+            .find(|(p, _)| p.name == package.name)
+            .map(|(_, entry)| entry);
+        let mut dirs: Vec<PathBuf> = old.into_iter().flat_map(|e| e.files.clone()).collect();
+        let mut backups: Vec<Backup> = old
+            .map(|entry| Self::extras(entry).backups)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|b| b.backup.exists())
+            .collect();
+        for task in tasks {
+            match task {
+                Task::Mkdir { path } => dirs.push(path),
+                Task::Backup { path, backup } => backups.push(Backup { path, backup }),
+                _ => {}
+            }
+        }
+
+        let mut files = package.farm().links_of(&package.name, &self.options);
+        files.extend(dirs.into_iter().filter(|dir| stow::is_real_dir(dir)));
+        files.extend(written.keys().cloned());
+        files.sort();
+        files.dedup();
+
+        let recorded = Recorded {
+            package,
+            backups,
+            written,
+        };
+        let data = serde_yaml_bw::to_value(recorded).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("failed to record stowed package: {e}"),
+            )
+        })?;
+        let entry = StateEntry {
+            files,
+            data: Some(data),
+            ..StateEntry::now()
+        };
+        state::record(Self::LOG_PREFIX, &package.name, entry)?;
+
+        Self::log_msg(&format!("Stowed {}", package.name));
+
+        Ok(())
+    }
+
+    /// Unstow a package, returning the directories it created that are still needed by other packages
+    fn remove_one(&self, package: &Dotfiles) -> crate::Result<Vec<PathBuf>> {
+        let recorded = Self::recorded()?;
+        let mut planner = self.planner(&recorded);
+
+        let farm = package.farm();
+        planner.unstow(&farm, &package.name)?;
+        let entry = recorded.iter().find(|(p, _)| p.name == package.name);
+        if let Some((_, entry)) = entry {
+            planner.unlink_stale(&farm, &package.name, &entry.files)?;
+            for backup in Self::extras(entry).backups {
+                planner.restore(&backup.path, &backup.backup);
+            }
+        }
+        self.run(planner)?;
+        if self.dry_run {
+            return Ok(Vec::new());
+        }
+
+        state::forget(Self::LOG_PREFIX, &package.name)?;
+        Self::log_msg(&format!("Unstowed {}", package.name));
+
+        Ok(entry
+            .into_iter()
+            .flat_map(|(_, entry)| entry.files.iter())
+            .filter(|path| stow::is_real_dir(path))
+            .cloned()
+            .collect())
+    }
+
+    /// Stowing one package can fold or unfold the directories of another, so re-record the links of every package.
+    /// Directories left behind by unstowed packages are handed to a package that still has links in them
+    fn refresh(&self, orphans: Vec<PathBuf>) -> crate::Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+
+        let mut state = State::load()?;
+        let mut orphans = orphans;
+
+        for (package, mut entry) in Self::recorded()? {
+            let mut files = package.farm().links_of(&package.name, &self.options);
+            files.extend(
+                entry
+                    .files
+                    .iter()
+                    .filter(|path| stow::is_real_dir(path))
+                    .cloned(),
+            );
+            files.extend(
+                Self::extras(&entry)
+                    .written
+                    .into_keys()
+                    .filter(|path| path.is_file()),
+            );
+            orphans.retain(|dir| {
+                let adopted = files.iter().any(|f| f.starts_with(dir) && f != dir);
+                if adopted {
+                    files.push(dir.clone());
+                }
+                !adopted
+            });
+            files.sort();
+            files.dedup();
+
+            if files != entry.files {
+                entry.files = files;
+                state.insert(Self::LOG_PREFIX, &package.name, entry);
+            }
+        }
+
+        state.save()
+    }
+}
+
+fn canonicalize(path: &Path, what: &str) -> crate::Result<PathBuf> {
+    std::fs::canonicalize(path).map_err(|e| {
+        std::io::Error::new(e.kind(), format!("{what} {}: {e}", path.display())).into()
+    })
+}
+
+impl<'de> Deserialize<'de> for DotfilesProvider {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        pub struct Fields {
+            dir: Option<String>,
+            target: Option<String>,
+            #[serde(default = "folding_default")]
+            folding: bool,
+            #[serde(default)]
+            ignore: Vec<String>,
+            #[serde(default)]
+            on_conflict: Strategy,
+            #[serde(default)]
+            dotfiles: bool,
+            packages: Vec<Declared>,
+        }
+
+        fn folding_default() -> bool {
+            true
+        }
+
+        let f = Fields::deserialize(deserializer)?;
+        let dir = f
+            .dir
+            .as_deref()
+            .map(paths::expand)
+            .transpose()
+            .map_err(de::Error::custom)?;
+        let target = paths::expand(f.target.as_deref().unwrap_or(DEFAULT_TARGET))
+            .map_err(de::Error::custom)?;
+
+        let options = Options {
+            folding: f.folding,
+            ignore_list: IgnoreList::new(f.ignore.iter().map(String::as_str))
+                .map_err(de::Error::custom)?,
+            on_conflict: f.on_conflict,
+            templates: true,
+            dotfiles: f.dotfiles,
+            ..Default::default()
+        };
+
+        let packages = f.packages.into_iter().map(|Declared(p)| p).collect();
+
+        Ok(Self::new(dir, target, options, packages))
+    }
+}
+
+impl PackageProvider for DotfilesProvider {
+    type Item = Dotfiles;
+    type Key = String;
+    const LOG_PREFIX: &'static str = "dotfiles";
+
+    fn key(item: &Self::Item) -> Self::Key {
+        item.name.clone()
+    }
+
+    fn describe_upgrade(old: &Self::Item, new: &Self::Item) -> String {
+        if old.dir != new.dir {
+            format!("moved from {}", old.dir.display())
+        } else if old.target != new.target {
+            format!("target changed from {}", old.target.display())
+        } else if old.rules != new.rules {
+            String::from("mode or permissions changed")
+        } else {
+            String::from("files out of date")
+        }
+    }
+
+    /// What stowing `package` does to files in the way of its links, or which of them conflict
+    fn describe_install(&self, package: &Self::Item) -> crate::Result<Option<String>> {
+        let (tasks, conflicts) = self.plan_install(package, &Self::recorded()?)?.finish();
+
+        let mut details: Vec<String> = tasks
+            .iter()
+            .filter_map(|task| match task {
+                Task::Adopt { path, .. } => Some(format!("adopts {}", path.display())),
+                Task::Backup { path, .. } => Some(format!("backs up {}", path.display())),
+                Task::Remove { path } => Some(format!("overwrites {}", path.display())),
+                _ => None,
+            })
+            .collect();
+        details.extend(
+            conflicts
+                .iter()
+                .map(|c| format!("conflicts with {}", c.path.display())),
+        );
+
+        Ok((!details.is_empty()).then(|| details.join(", ")))
+    }
+
+    fn install_items(&self, items: &[Self::Item]) -> crate::Result<()> {
+        for package in items {
+            self.install_one(package).for_package(&package.name)?;
+        }
+        self.refresh(Vec::new())?;
+
+        if !self.dry_run {
+            Self::log_msg(&format!("Successfully stowed {} package(s)", items.len()));
+        }
+
+        Ok(())
+    }
+
+    fn remove_items(&self, items: &[Self::Item]) -> crate::Result<()> {
+        let mut orphans = Vec::new();
+        for package in items {
+            orphans.extend(self.remove_one(package).for_package(&package.name)?);
+        }
+        self.refresh(orphans)?;
+
+        if !self.dry_run {
+            Self::log_msg(&format!("Successfully unstowed {} package(s)", items.len()));
+        }
+
+        Ok(())
+    }
+
+    fn ensure(&self) -> crate::Result<()> {
+        self.keyed_ensure()
+    }
+
+    fn declared(&self) -> &[Self::Item] {
+        &self.packages
+    }
+
+    /// Dotfiles come straight from the stow directory, so there's nothing to lock
+    fn lock_entries(&self, _items: &[Self::Item]) -> crate::Result<Vec<(String, LockEntry)>> {
+        Ok(Vec::new())
+    }
+
+    fn set_locked(&mut self, _locked: BTreeMap<String, LockEntry>) {}
+
+    /// Settle the stow directory and target of every package, which links are made relative to
+    fn resolve(&mut self) -> crate::Result<()> {
+        let dir = match &self.dir {
+            Some(dir) => dir.clone(),
+            None => state::config_path()
+                .and_then(Path::parent)
+                .map(Path::to_path_buf)
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        "failed to determine the stow directory, please set `dir`",
+                    )
+                })?,
+        };
+        let dir = canonicalize(&dir, "stow directory")?;
+        let target = canonicalize(&self.target, "target")?;
+
+        for package in self.packages.iter_mut() {
+            package.dir = dir.clone();
+            package.target = target.clone();
+        }
+        self.dir = Some(dir);
+        self.target = target;
+
+        Ok(())
+    }
+
+    /// Every package bow stowed
+    fn get_installed(&self) -> crate::Result<Vec<Self::Item>> {
+        Ok(Self::recorded()?
+            .into_iter()
+            .map(|(package, _)| package)
+            .collect())
+    }
+
+    /// A stowed package is restowed if any of its links are missing or stale
+    fn out_of_sync(&self, package: &Self::Item) -> crate::Result<bool> {
+        let recorded = Self::recorded()?;
+
+        Ok(recorded
+            .iter()
+            .find(|(p, _)| p.name == package.name)
+            .is_some_and(|(_, entry)| !self.in_sync(package, entry, &recorded)))
+    }
+}
+
+/// A file that was in the way of a link, and where it was moved to until the package is unstowed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Backup {
+    path: PathBuf,
+    backup: PathBuf,
+}
+
+/// What's recorded in the state for a stowed package
+#[derive(Serialize)]
+struct Recorded<'a> {
+    #[serde(flatten)]
+    package: &'a Dotfiles,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    backups: Vec<Backup>,
+    /// Every file rendered from a template or copied and the hash of what was written, to tell whether it was
+    /// edited since
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    written: BTreeMap<PathBuf, String>,
+}
+
+/// The parts of [`Recorded`] that aren't the package itself
+#[derive(Debug, Default, Deserialize)]
+struct Extras {
+    #[serde(default)]
+    backups: Vec<Backup>,
+    #[serde(default, alias = "rendered")]
+    written: BTreeMap<PathBuf, String>,
+}
+
+/// A stow package, a directory in the stow directory whose contents are linked into the target
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize)]
+pub struct Dotfiles {
+    name: String,
+    /// Filled in from the provider when the config is resolved
+    dir: PathBuf,
+    target: PathBuf,
+    #[serde(flatten)]
+    rules: Rules,
+}
+
+impl Dotfiles {
+    fn new(name: &str, dir: &Path, target: &Path) -> Result<Self, String> {
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err(format!(
+                "invalid package name `{name}`, it must be a directory directly inside the stow directory"
+            ));
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            dir: dir.to_path_buf(),
+            target: target.to_path_buf(),
+            rules: Rules::default(),
+        })
+    }
+
+    fn farm(&self) -> Farm {
+        Farm {
+            stow_dir: self.dir.clone(),
+            target: self.target.clone(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Dotfiles {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        /// The full package, the way it's recorded in the state and generations
+        #[derive(Deserialize)]
+        struct Repr {
+            name: String,
+            dir: PathBuf,
+            target: PathBuf,
+            #[serde(flatten)]
+            rules: Rules,
+        }
+
+        let repr = Repr::deserialize(deserializer)?;
+        Ok(Self {
+            rules: repr.rules,
+            ..Self::new(&repr.name, &repr.dir, &repr.target).map_err(de::Error::custom)?
+        })
+    }
+}
+
+/// A package as declared in the config, just its name or its name along with how its files are put into the target.
+/// Its stow directory and target are the provider's
+struct Declared(Dotfiles);
+
+impl<'de> Deserialize<'de> for Declared {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        /// `Rules` can't be flattened into a struct that denies unknown fields, so its fields are repeated here
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Full {
+            name: String,
+            #[serde(default)]
+            mode: Mode,
+            #[serde(default)]
+            files: BTreeMap<PathBuf, FileRules>,
+        }
+
+        struct DeclaredVisitor;
+
+        impl<'de> Visitor<'de> for DeclaredVisitor {
+            type Value = Declared;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a package name or a map with its name and rules")
+            }
+
+            fn visit_str<E>(self, name: &str) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                Dotfiles::new(name, Path::new(""), Path::new(""))
+                    .map(Declared)
+                    .map_err(E::custom)
+            }
+
+            fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+            where
+                A: de::MapAccess<'de>,
+            {
+                let full = Full::deserialize(de::value::MapAccessDeserializer::new(map))?;
+                let package = Dotfiles::new(&full.name, Path::new(""), Path::new(""))
+                    .map_err(de::Error::custom)?;
+
+                Ok(Declared(Dotfiles {
+                    rules: Rules {
+                        mode: full.mode,
+                        files: full.files,
+                    },
+                    ..package
+                }))
+            }
+        }
+
+        deserializer.deserialize_any(DeclaredVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::with_state_dir;
+
+    /// A stow directory holding `packages`, each with a single `.<name>rc`, and an empty target.
+    /// The empty ignore file of every package keeps `~/.stow-global-ignore` out of the tests
+    fn fixture(packages: &[&str]) -> (tempfile::TempDir, DotfilesProvider) {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("stow");
+        let target = root.path().join("target");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::create_dir_all(&target).unwrap();
+        for name in packages {
+            std::fs::create_dir_all(dir.join(name)).unwrap();
+            std::fs::write(dir.join(name).join(".stow-local-ignore"), "").unwrap();
+            std::fs::write(dir.join(name).join(format!(".{name}rc")), name).unwrap();
+        }
+
+        let provider = DotfilesProvider::for_stow(dir, target, Options::default(), false).unwrap();
+        (root, provider)
+    }
+
+    fn package(provider: &DotfilesProvider, name: &str) -> Dotfiles {
+        Dotfiles::new(name, provider.dir.as_deref().unwrap(), &provider.target).unwrap()
+    }
+
+    #[test]
+    fn package_names_must_be_directly_inside_the_stow_directory() {
+        for name in ["", ".", "..", "a/b", "../a"] {
+            let err = Dotfiles::new(name, Path::new("/stow"), Path::new("/target")).unwrap_err();
+            assert!(err.contains("invalid package name"), "{name}: {err}");
+        }
+
+        assert!(Dotfiles::new("vim", Path::new("/stow"), Path::new("/target")).is_ok());
+    }
+
+    #[test]
+    fn declared_packages_are_a_name_or_a_map() {
+        let packages: Vec<Declared> = serde_yaml_bw::from_str(
+            "- vim\n- name: ssh\n  mode: copy\n  files:\n    .ssh/config:\n      permissions: 0600\n",
+        )
+        .unwrap();
+        let packages: Vec<_> = packages.into_iter().map(|Declared(p)| p).collect();
+
+        assert_eq!(packages[0].name, "vim");
+        assert_eq!(packages[0].rules, Rules::default());
+        assert_eq!(packages[1].name, "ssh");
+        assert_eq!(packages[1].rules.mode, Mode::Copy);
+        assert!(
+            packages[1]
+                .rules
+                .files
+                .contains_key(Path::new(".ssh/config"))
+        );
+    }
+
+    #[test]
+    fn declared_packages_take_their_dir_and_target_from_the_provider() {
+        let err = serde_yaml_bw::from_str::<Vec<Declared>>("- name: vim\n  dir: /elsewhere\n")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("unknown field `dir`"), "{err}");
+
+        let err = serde_yaml_bw::from_str::<Vec<Declared>>("- name: vim\n  target: /elsewhere\n")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("unknown field `target`"), "{err}");
+
+        let err = serde_yaml_bw::from_str::<Vec<Declared>>("- ../vim\n")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("invalid package name"), "{err}");
+    }
+
+    #[test]
+    fn recorded_packages_keep_their_backups_and_written_files() {
+        let package = Dotfiles {
+            rules: Rules {
+                mode: Mode::Copy,
+                ..Default::default()
+            },
+            ..Dotfiles::new("ssh", Path::new("/stow"), Path::new("/target")).unwrap()
+        };
+        let backups = vec![Backup {
+            path: PathBuf::from("/target/.ssh/config"),
+            backup: PathBuf::from("/target/.ssh/config.bow-backup"),
+        }];
+        let written = BTreeMap::from([(PathBuf::from("/target/.ssh/config"), "sha256:00".into())]);
+
+        let data = serde_yaml_bw::to_value(Recorded {
+            package: &package,
+            backups,
+            written: written.clone(),
+        })
+        .unwrap();
+        let entry = StateEntry {
+            data: Some(data.clone()),
+            ..StateEntry::now()
+        };
+
+        assert_eq!(
+            serde_yaml_bw::from_value::<Dotfiles>(data).unwrap(),
+            package
+        );
+        let extras = DotfilesProvider::extras(&entry);
+        assert_eq!(extras.backups.len(), 1);
+        assert_eq!(
+            extras.backups[0].backup,
+            Path::new("/target/.ssh/config.bow-backup")
+        );
+        assert_eq!(extras.written, written);
+    }
+
+    #[test]
+    fn extras_read_rendered_files_and_default_to_nothing() {
+        let data = serde_yaml_bw::from_str(
+            "name: vim\ndir: /stow\ntarget: /target\nrendered:\n  /target/.vimrc: sha256:00\n",
+        )
+        .unwrap();
+        let entry = StateEntry {
+            data: Some(data),
+            ..StateEntry::now()
+        };
+
+        let extras = DotfilesProvider::extras(&entry);
+        assert!(extras.backups.is_empty());
+        assert_eq!(
+            extras.written.keys().collect::<Vec<_>>(),
+            vec![Path::new("/target/.vimrc")]
+        );
+
+        let extras = DotfilesProvider::extras(&StateEntry::now());
+        assert!(extras.backups.is_empty() && extras.written.is_empty());
+    }
+
+    #[test]
+    fn for_stow_resolves_the_stow_directory_and_target() {
+        let (root, provider) = fixture(&[]);
+        let root = std::fs::canonicalize(root.path()).unwrap();
+
+        assert_eq!(provider.dir.as_deref(), Some(root.join("stow").as_path()));
+        assert_eq!(provider.target, root.join("target"));
+
+        let err = DotfilesProvider::for_stow(
+            root.join("stow"),
+            root.join("missing"),
+            Options::default(),
+            false,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("target"), "{err}");
+    }
+
+    #[test]
+    fn stow_packages_records_what_it_stowed() {
+        with_state_dir(|_| {
+            let (_root, provider) = fixture(&["vim", "zsh"]);
+
+            provider
+                .stow_packages(&[], &["vim".to_string(), "zsh".to_string()])
+                .unwrap();
+            assert_eq!(
+                provider.get_installed().unwrap(),
+                vec![package(&provider, "vim"), package(&provider, "zsh")]
+            );
+            assert!(provider.target.join(".vimrc").is_symlink());
+
+            provider.stow_packages(&["zsh".to_string()], &[]).unwrap();
+            assert_eq!(
+                provider.get_installed().unwrap(),
+                vec![package(&provider, "vim")]
+            );
+            assert!(!provider.target.join(".zshrc").exists());
+
+            assert!(provider.stow_packages(&[], &["..".to_string()]).is_err());
+        });
+    }
+
+    #[test]
+    fn packages_with_missing_links_are_restowed() {
+        with_state_dir(|_| {
+            let (_root, provider) = fixture(&["vim"]);
+            let vim = package(&provider, "vim");
+            provider.install_items(std::slice::from_ref(&vim)).unwrap();
+
+            let recorded = DotfilesProvider::recorded().unwrap();
+            assert!(provider.in_sync(&vim, &recorded[0].1, &recorded));
+            let changes = provider.changes(std::slice::from_ref(&vim)).unwrap();
+            assert_eq!(changes.unchanged, vec![vim.clone()]);
+
+            std::fs::remove_file(provider.target.join(".vimrc")).unwrap();
+            assert!(provider.out_of_sync(&vim).unwrap());
+            let changes = provider.changes(std::slice::from_ref(&vim)).unwrap();
+            assert!(changes.unchanged.is_empty());
+            assert_eq!(changes.upgraded, vec![(vim.clone(), vim.clone())]);
+
+            provider.install_items(std::slice::from_ref(&vim)).unwrap();
+            assert!(!provider.out_of_sync(&vim).unwrap());
+        });
+    }
+}
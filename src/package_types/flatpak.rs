@@ -1,87 +1,179 @@
-use std::io::{BufRead, BufReader};
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader},
+};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::package_types::PackageProvider;
+use crate::{
+    error::{Context, Error},
+    lockfile::{self, LockEntry},
+    package_types::{CommandRunner, PackageProvider, ProcessRunner},
+    state::{self, State, StateEntry},
+};
 
 #[derive(Debug)]
 pub struct FlatpakProvider {
     pub flatpaks: Vec<Flatpak>,
+    locked: Option<BTreeMap<String, LockEntry>>,
+    runner: Box<dyn CommandRunner>,
 }
 
 impl FlatpakProvider {
     fn new_with_flatpaks(flatpaks: Vec<Flatpak>) -> Self {
-        Self { flatpaks }
+        Self {
+            flatpaks,
+            locked: None,
+            runner: Box::new(ProcessRunner),
+        }
     }
-}
 
-impl<'de> Deserialize<'de> for FlatpakProvider {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        let flatpaks = Vec::<Flatpak>::deserialize(deserializer)?;
+    /// The `(branch, commit)` of an installed flatpak, or `None` if it isn't installed
+    fn info(&self, id: &str) -> crate::Result<Option<(String, String)>> {
+        let output = self
+            .runner
+            .output(&["flatpak", "info", "--user", id])
+            .map_err(|e| Error::from_spawn("flatpak", e))?;
 
-        Ok(Self::new_with_flatpaks(flatpaks))
+        if !output.status.success() {
+            return Ok(None);
+        }
+
+        let mut branch = None;
+        let mut commit = None;
+        for line in output.stdout.lines() {
+            let line = line?;
+            match line.trim().split_once(':') {
+                Some(("Branch", value)) => branch = Some(value.trim().to_string()),
+                Some(("Commit", value)) => commit = Some(value.trim().to_string()),
+                _ => {}
+            }
+        }
+
+        Ok(branch.zip(commit))
     }
-}
 
-impl PackageProvider for FlatpakProvider {
-    type Item = Flatpak;
-    const LOG_PREFIX: &'static str = "flatpak";
+    /// Run `flatpak <action>` on every item at once, streaming its output.
+    /// If that fails, every item that `done` says wasn't handled is retried on its own,
+    /// so the ones that actually fail can be told apart
+    fn run_for_each(
+        &self,
+        action: &str,
+        items: &[Flatpak],
+        done: impl Fn(&[Flatpak], &Flatpak) -> bool,
+    ) -> crate::Result<Vec<Error>> {
+        if items.is_empty() {
+            return Ok(Vec::new());
+        }
 
-    fn install_items(&self, items: &[Self::Item]) -> crate::Result<()> {
         let ids: Vec<&str> = items.iter().map(|f| f.id.as_str()).collect();
-        let mut cmd = std::process::Command::new("flatpak");
-        cmd.args(["install", "--noninteractive", "--user"]);
-        cmd.args(&ids);
-        cmd.stdout(std::process::Stdio::piped());
-        cmd.stderr(std::process::Stdio::piped());
 
-        let mut child = cmd.spawn()?;
-        let stdout = child.stdout.take().expect("handle present");
-        let stderr = child.stderr.take().expect("handle present");
+        match self.run_flatpak(action, &ids) {
+            Ok(()) => return Ok(Vec::new()),
+            Err(Error::CommandFailed { .. }) if items.len() > 1 => {
+                Self::log_err(format!(
+                    "flatpak {action} failed, retrying packages individually"
+                ));
+            }
+            Err(e @ Error::CommandFailed { .. }) => {
+                return Ok(vec![Error::Package {
+                    name: ids[0].to_string(),
+                    source: Box::new(e),
+                }]);
+            }
+            Err(e) => return Err(e),
+        }
 
-        let stdout_handle = std::thread::spawn(|| {
-            let reader = BufReader::new(stdout);
-            for line in reader.lines() {
-                match line {
-                    Ok(line) => Self::log_msg(&line),
-                    Err(e) => Self::log_err(e),
-                }
+        let installed = self.get_installed()?;
+        let mut failures = Vec::new();
+        for f in items.iter().filter(|f| !done(&installed, f)) {
+            match self.run_flatpak(action, &[f.id.as_str()]) {
+                Ok(()) => {}
+                Err(e @ Error::CommandFailed { .. }) => failures.push(Error::Package {
+                    name: f.id.clone(),
+                    source: Box::new(e),
+                }),
+                Err(e) => return Err(e),
             }
-        });
+        }
 
-        let stderr_handle = std::thread::spawn(|| {
-            let reader = BufReader::new(stderr);
-            for line in reader.lines() {
-                match line {
-                    Ok(line) => Self::log_msg(&line),
-                    Err(e) => Self::log_err(e),
+        Ok(failures)
+    }
+
+    /// Log which items succeeded and which failed, failing if any did.
+    /// Successful removals are forgotten from the state, and successful installs recorded in it
+    /// unless they were already installed `before`, which leaves them to whoever installed them.
+    /// Items bow already recorded keep their install time
+    fn summarize(
+        action: &str,
+        items: &[Flatpak],
+        before: &[Flatpak],
+        failures: Vec<Error>,
+    ) -> crate::Result<()> {
+        let past = match action {
+            "install" => "installed",
+            "remove" => "removed",
+            _ => action,
+        };
+
+        let failed = |f: &Flatpak| {
+            failures
+                .iter()
+                .any(|e| matches!(e, Error::Package { name, .. } if *name == f.id))
+        };
+
+        let succeeded: Vec<_> = items.iter().filter(|f| !failed(f)).collect();
+
+        let mut state = State::load()?;
+        for f in succeeded.iter() {
+            match action {
+                "install" => {
+                    if !before.contains(f) && state.get(Self::LOG_PREFIX, &f.id).is_none() {
+                        state.insert(Self::LOG_PREFIX, &f.id, StateEntry::now());
+                    }
+                }
+                _ => {
+                    state.remove(Self::LOG_PREFIX, &f.id);
                 }
             }
-        });
+        }
+        state.save()?;
+
+        if !succeeded.is_empty() {
+            Self::log_msg(&format!(
+                "Successfully {past} {} package(s)",
+                succeeded.len()
+            ));
+            for f in succeeded {
+                Self::log_msg(&format!("    {}", f.id));
+            }
+        }
 
-        let _ = child.wait()?;
-        stdout_handle.join().unwrap();
-        stderr_handle.join().unwrap();
+        if failures.is_empty() {
+            return Ok(());
+        }
 
-        Ok(())
+        Self::log_err(format!("Failed to {action} {} package(s)", failures.len()));
+        for e in failures.iter() {
+            Self::log_err(format!("    {e}"));
+        }
+
+        Err(Error::Multiple(failures))
     }
 
-    fn remove_items(&self, items: &[Self::Item]) -> crate::Result<()> {
-        let ids: Vec<&str> = items.iter().map(|f| f.id.as_str()).collect();
-        let mut cmd = std::process::Command::new("flatpak");
-        cmd.args(["remove", "--noninteractive", "--user"]);
-        cmd.args(&ids);
-        cmd.stdout(std::process::Stdio::piped());
-        cmd.stderr(std::process::Stdio::piped());
+    /// Run `flatpak <action> --noninteractive --user <ids>`, logging its output as it goes
+    fn run_flatpak(&self, action: &str, ids: &[&str]) -> crate::Result<()> {
+        let mut argv = vec!["flatpak", action, "--noninteractive", "--user"];
+        argv.extend(ids);
 
-        let mut child = cmd.spawn()?;
+        let mut child = self
+            .runner
+            .spawn(&argv)
+            .map_err(|e| Error::from_spawn("flatpak", e))?;
         let stdout = child.stdout.take().expect("handle present");
         let stderr = child.stderr.take().expect("handle present");
 
-        let stdout_handle = std::thread::spawn(move || {
+        let stdout_handle = std::thread::spawn(|| {
             let reader = BufReader::new(stdout);
             for line in reader.lines() {
                 match line {
@@ -93,67 +185,200 @@ impl PackageProvider for FlatpakProvider {
 
         let stderr_handle = std::thread::spawn(|| {
             let reader = BufReader::new(stderr);
+            let mut captured = String::new();
             for line in reader.lines() {
                 match line {
-                    Ok(line) => Self::log_msg(&line),
+                    Ok(line) => {
+                        Self::log_msg(&line);
+                        captured.push_str(&line);
+                        captured.push('\n');
+                    }
                     Err(e) => Self::log_err(e),
                 }
             }
+            captured
         });
 
-        let _ = child.wait()?;
+        let status = child.wait()?;
         stdout_handle.join().unwrap();
-        stderr_handle.join().unwrap();
+        let stderr = stderr_handle.join().unwrap();
 
-        Ok(())
+        if status.success() {
+            Ok(())
+        } else {
+            Err(Error::CommandFailed {
+                command: format!("flatpak {action} {}", ids.join(" ")),
+                status,
+                stderr,
+            })
+        }
     }
 
-    fn ensure(&self) -> crate::Result<()> {
-        let installed = self.get_installed()?;
-
-        if let Some(to_install) = Self::diff(&self.flatpaks, &installed) {
-            Self::log_msg("Found packages to install");
-            for f in to_install.iter() {
-                Self::log_msg(&format!("    {}", f.id));
+    /// Deploy the locked commit of every freshly installed item that isn't already on it.
+    /// flatpak can only install the newest commit, so an item that can't be moved to its locked one
+    /// is uninstalled again rather than left on a commit the lockfile doesn't allow
+    fn pin_locked(
+        &self,
+        items: &[Flatpak],
+        locked: &BTreeMap<String, LockEntry>,
+    ) -> crate::Result<()> {
+        let mut failures = Vec::new();
+        for f in items {
+            let Some(expected) = locked.get(&f.id).and_then(|e| e.commit.as_ref()) else {
+                continue;
+            };
+
+            // Not installed at all means the install failed, which was reported already
+            let Some((_, actual)) = self.info(&f.id)? else {
+                continue;
+            };
+            if actual == *expected {
+                continue;
             }
 
-            if Self::confirm("Install the above packages?")? {
-                self.install_items(&to_install)?;
-            } else {
-                Self::log_msg(&format!(
-                    "Skipping install of {} package(s)",
-                    to_install.len()
-                ));
+            Self::log_msg(&format!("Pinning {} to locked commit {expected}", f.id));
+            let commit = format!("--commit={expected}");
+            let status = self
+                .runner
+                .status(&[
+                    "flatpak",
+                    "update",
+                    "--noninteractive",
+                    "--user",
+                    &commit,
+                    &f.id,
+                ])
+                .map_err(|e| Error::from_spawn("flatpak", e))?;
+
+            if status.success() {
+                continue;
             }
-        } else {
-            Self::log_msg("Nothing to install");
+
+            Self::log_err(format!(
+                "Failed to pin {} to its locked commit, uninstalling it again",
+                f.id
+            ));
+            self.run_flatpak("remove", &[f.id.as_str()])?;
+            state::forget(Self::LOG_PREFIX, &f.id)?;
+
+            failures.push(Error::Package {
+                name: f.id.clone(),
+                source: Box::new(Error::LockMismatch {
+                    expected: expected.clone(),
+                    actual,
+                }),
+            });
         }
 
-        if let Some(to_remove) = Self::diff(&installed, &self.flatpaks) {
-            Self::log_msg("Found packages to remove");
-            for f in to_remove.iter() {
-                Self::log_msg(&format!("    {}", f.id));
-            }
+        match failures.len() {
+            0 => Ok(()),
+            1 => Err(failures.remove(0)),
+            _ => Err(Error::Multiple(failures)),
+        }
+    }
+}
 
-            if Self::confirm("Remove the above packages?")? {
-                self.remove_items(&to_remove)?;
-            } else {
-                Self::log_msg(&format!(
-                    "Skipping removal of {} package(s)",
-                    to_remove.len()
-                ));
+impl<'de> Deserialize<'de> for FlatpakProvider {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let flatpaks = Vec::<Flatpak>::deserialize(deserializer)?;
+
+        Ok(Self::new_with_flatpaks(flatpaks))
+    }
+}
+
+impl PackageProvider for FlatpakProvider {
+    type Item = Flatpak;
+    type Key = String;
+    const LOG_PREFIX: &'static str = "flatpak";
+
+    fn key(item: &Self::Item) -> Self::Key {
+        item.id.clone()
+    }
+
+    fn install_items(&self, items: &[Self::Item]) -> crate::Result<()> {
+        if let Some(locked) = &self.locked
+            && let Some(f) = items.iter().find(|f| !locked.contains_key(&f.id))
+        {
+            return Err(Error::NotLocked).for_package(&f.id);
+        }
+
+        let before = self.get_installed()?;
+        let failures = self.run_for_each("install", items, |installed, f| installed.contains(f))?;
+        let result = Self::summarize("install", items, &before, failures);
+
+        // The ones that did get installed are on the newest commit, even if others failed
+        if let Some(locked) = &self.locked {
+            let fresh: Vec<_> = items
+                .iter()
+                .filter(|f| !before.contains(f))
+                .cloned()
+                .collect();
+            self.pin_locked(&fresh, locked)?;
+        }
+
+        result
+    }
+
+    fn remove_items(&self, items: &[Self::Item]) -> crate::Result<()> {
+        let failures = self.run_for_each("remove", items, |installed, f| !installed.contains(f))?;
+        Self::summarize("remove", items, &[], failures)
+    }
+
+    fn ensure(&self) -> crate::Result<()> {
+        self.keyed_ensure()
+    }
+
+    fn declared(&self) -> &[Self::Item] {
+        &self.flatpaks
+    }
+
+    fn lock_entries(&self, items: &[Self::Item]) -> crate::Result<Vec<(String, LockEntry)>> {
+        let state = State::load()?;
+        let mut entries = Vec::new();
+
+        for f in items {
+            if let Some((branch, commit)) = self.info(&f.id)? {
+                let entry = LockEntry {
+                    commit: Some(commit),
+                    branch: Some(branch),
+                    installed_at: state
+                        .get(Self::LOG_PREFIX, &f.id)
+                        .map_or_else(lockfile::now, |e| e.installed_at),
+                    ..Default::default()
+                };
+                entries.push((f.id.clone(), entry));
             }
-        } else {
-            Self::log_msg("Nothing to remove");
         }
 
-        Ok(())
+        Ok(entries)
+    }
+
+    fn set_locked(&mut self, locked: BTreeMap<String, LockEntry>) {
+        self.locked = Some(locked);
     }
 
     fn get_installed(&self) -> crate::Result<Vec<Self::Item>> {
-        let mut cmd = std::process::Command::new("flatpak");
-        cmd.args(["list", "--user", "--columns=application:f", "--app"]);
-        let output = cmd.output()?;
+        let output = self
+            .runner
+            .output(&[
+                "flatpak",
+                "list",
+                "--user",
+                "--columns=application:f",
+                "--app",
+            ])
+            .map_err(|e| Error::from_spawn("flatpak", e))?;
+
+        if !output.status.success() {
+            return Err(Error::CommandFailed {
+                command: String::from("flatpak list"),
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
 
         let mut installed = Vec::new();
         for line in output.stdout.lines() {
@@ -165,7 +390,8 @@ impl PackageProvider for FlatpakProvider {
     }
 }
 
-#[derive(PartialEq, Eq, Debug, Hash, Clone)]
+#[derive(PartialEq, Eq, Debug, Hash, Clone, Serialize)]
+#[serde(transparent)]
 pub struct Flatpak {
     id: String,
 }
@@ -186,3 +412,273 @@ impl<'de> Deserialize<'de> for Flatpak {
         Ok(Self::new(&id))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        os::unix::process::ExitStatusExt,
+        process::{Child, Command, ExitStatus, Output, Stdio},
+        rc::Rc,
+    };
+
+    use super::*;
+    use crate::state::with_state_dir;
+
+    /// Answers each command with the exit code and stdout of the first response its argv starts with,
+    /// succeeding without output if there is none, and records what would run instead of running it
+    #[derive(Debug, Default)]
+    struct FakeRunner {
+        responses: Vec<(&'static str, i32, &'static str)>,
+        calls: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl FakeRunner {
+        fn respond(&self, argv: &[&str]) -> (i32, &'static str) {
+            let command = argv.join(" ");
+            let response = self
+                .responses
+                .iter()
+                .find(|(prefix, ..)| command.starts_with(prefix))
+                .map_or((0, ""), |(_, code, stdout)| (*code, *stdout));
+            self.calls.borrow_mut().push(command);
+            response
+        }
+    }
+
+    impl CommandRunner for FakeRunner {
+        fn status(&self, argv: &[&str]) -> std::io::Result<ExitStatus> {
+            let (code, _) = self.respond(argv);
+            Ok(ExitStatus::from_raw(code << 8))
+        }
+
+        fn output(&self, argv: &[&str]) -> std::io::Result<Output> {
+            let (code, stdout) = self.respond(argv);
+            Ok(Output {
+                status: ExitStatus::from_raw(code << 8),
+                stdout: stdout.as_bytes().to_vec(),
+                stderr: Vec::new(),
+            })
+        }
+
+        fn spawn(&self, argv: &[&str]) -> std::io::Result<Child> {
+            let (code, stdout) = self.respond(argv);
+            Command::new("sh")
+                .args(["-c", &format!("printf '{stdout}'; exit {code}")])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+        }
+    }
+
+    const MAPS: &str = "org.gnome.Maps";
+    const MAPS_INFO: &str = "     Branch: stable\n     Commit: newest\n";
+
+    fn provider(runner: FakeRunner, locked: Option<&str>) -> FlatpakProvider {
+        let mut provider = FlatpakProvider {
+            runner: Box::new(runner),
+            ..FlatpakProvider::new_with_flatpaks(vec![Flatpak::new(MAPS)])
+        };
+        if let Some(commit) = locked {
+            provider.set_locked(BTreeMap::from([(
+                MAPS.to_string(),
+                LockEntry {
+                    commit: Some(commit.to_string()),
+                    branch: Some("stable".to_string()),
+                    ..Default::default()
+                },
+            )]));
+        }
+        provider
+    }
+
+    #[test]
+    fn pins_locked_installs_to_their_commit() {
+        with_state_dir(|_| {
+            let runner = FakeRunner {
+                responses: vec![("flatpak info", 0, MAPS_INFO)],
+                ..FakeRunner::default()
+            };
+            let calls = runner.calls.clone();
+
+            provider(runner, Some("locked"))
+                .install_items(&[Flatpak::new(MAPS)])
+                .unwrap();
+
+            assert_eq!(
+                *calls.borrow(),
+                [
+                    "flatpak list --user --columns=application:f --app",
+                    "flatpak install --noninteractive --user org.gnome.Maps",
+                    "flatpak info --user org.gnome.Maps",
+                    "flatpak update --noninteractive --user --commit=locked org.gnome.Maps",
+                ]
+            );
+            assert!(State::load().unwrap().get("flatpak", MAPS).is_some());
+        });
+    }
+
+    #[test]
+    fn uninstalls_locked_installs_that_cannot_be_pinned() {
+        with_state_dir(|_| {
+            let runner = FakeRunner {
+                responses: vec![("flatpak info", 0, MAPS_INFO), ("flatpak update", 1, "")],
+                ..FakeRunner::default()
+            };
+            let calls = runner.calls.clone();
+
+            let err = provider(runner, Some("locked"))
+                .install_items(&[Flatpak::new(MAPS)])
+                .unwrap_err();
+
+            assert!(
+                matches!(
+                    &err,
+                    Error::Package { name, source } if name == MAPS && matches!(
+                        source.as_ref(),
+                        Error::LockMismatch { expected, actual } if expected == "locked" && actual == "newest"
+                    )
+                ),
+                "{err}"
+            );
+            assert_eq!(
+                *calls.borrow(),
+                [
+                    "flatpak list --user --columns=application:f --app",
+                    "flatpak install --noninteractive --user org.gnome.Maps",
+                    "flatpak info --user org.gnome.Maps",
+                    "flatpak update --noninteractive --user --commit=locked org.gnome.Maps",
+                    "flatpak remove --noninteractive --user org.gnome.Maps",
+                ]
+            );
+            assert!(State::load().unwrap().get("flatpak", MAPS).is_none());
+        });
+    }
+
+    #[test]
+    fn refuses_to_install_what_is_not_locked() {
+        with_state_dir(|_| {
+            let runner = FakeRunner::default();
+            let calls = runner.calls.clone();
+
+            let mut provider = provider(runner, None);
+            provider.set_locked(BTreeMap::new());
+            let err = provider.install_items(&[Flatpak::new(MAPS)]).unwrap_err();
+
+            assert!(matches!(
+                &err,
+                Error::Package { source, .. } if matches!(source.as_ref(), Error::NotLocked)
+            ));
+            assert!(calls.borrow().is_empty());
+        });
+    }
+
+    const WEATHER: &str = "org.gnome.Weather";
+
+    fn recorded() -> BTreeMap<String, u64> {
+        State::load()
+            .unwrap()
+            .entries("flatpak")
+            .into_iter()
+            .map(|(id, entry)| (id, entry.installed_at))
+            .collect()
+    }
+
+    #[test]
+    fn only_records_what_was_not_installed_before() {
+        with_state_dir(|_| {
+            let runner = FakeRunner {
+                responses: vec![("flatpak list", 0, "org.gnome.Maps\n")],
+                ..FakeRunner::default()
+            };
+
+            provider(runner, None)
+                .install_items(&[Flatpak::new(MAPS), Flatpak::new(WEATHER)])
+                .unwrap();
+
+            assert_eq!(
+                recorded().into_keys().collect::<Vec<_>>(),
+                [WEATHER.to_string()]
+            );
+        });
+    }
+
+    #[test]
+    fn keeps_the_install_time_of_what_it_recorded_already() {
+        with_state_dir(|_| {
+            state::record(
+                "flatpak",
+                MAPS,
+                StateEntry {
+                    installed_at: 5,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+            provider(FakeRunner::default(), None)
+                .install_items(&[Flatpak::new(MAPS)])
+                .unwrap();
+
+            assert_eq!(recorded(), BTreeMap::from([(MAPS.to_string(), 5)]));
+        });
+    }
+
+    #[test]
+    fn only_records_the_installs_that_succeeded() {
+        with_state_dir(|_| {
+            let runner = FakeRunner {
+                responses: vec![
+                    (
+                        "flatpak install --noninteractive --user org.gnome.Weather",
+                        1,
+                        "",
+                    ),
+                    (
+                        "flatpak install --noninteractive --user org.gnome.Maps org",
+                        1,
+                        "",
+                    ),
+                ],
+                ..FakeRunner::default()
+            };
+            let calls = runner.calls.clone();
+
+            let err = provider(runner, None)
+                .install_items(&[Flatpak::new(MAPS), Flatpak::new(WEATHER)])
+                .unwrap_err();
+
+            assert!(
+                matches!(&err, Error::Multiple(errors) if errors.len() == 1),
+                "{err}"
+            );
+            assert_eq!(
+                *calls.borrow(),
+                [
+                    "flatpak list --user --columns=application:f --app",
+                    "flatpak install --noninteractive --user org.gnome.Maps org.gnome.Weather",
+                    "flatpak list --user --columns=application:f --app",
+                    "flatpak install --noninteractive --user org.gnome.Maps",
+                    "flatpak install --noninteractive --user org.gnome.Weather",
+                ]
+            );
+            assert_eq!(
+                recorded().into_keys().collect::<Vec<_>>(),
+                [MAPS.to_string()]
+            );
+        });
+    }
+
+    #[test]
+    fn forgets_what_it_removed() {
+        with_state_dir(|_| {
+            state::record("flatpak", MAPS, StateEntry::now()).unwrap();
+
+            provider(FakeRunner::default(), None)
+                .remove_items(&[Flatpak::new(MAPS)])
+                .unwrap();
+
+            assert!(recorded().is_empty());
+        });
+    }
+}
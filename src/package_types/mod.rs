@@ -1,38 +1,402 @@
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashMap},
     fmt::{Debug, Display},
     hash::Hash,
     io::Write,
+    process::{Child, Command, ExitStatus, Output, Stdio},
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
 };
 
-use serde::Deserialize;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_yaml_bw::Value;
 
+pub mod appimage;
 pub mod binary;
+pub mod dotfiles;
 pub mod flatpak;
+pub mod system;
 
+pub use appimage::AppImageProvider;
 pub use binary::BinaryProvider;
+pub use dotfiles::DotfilesProvider;
 pub use flatpak::FlatpakProvider;
+pub use system::SystemProvider;
 
-use crate::RunMode;
+use crate::{
+    RunMode,
+    error::Context,
+    generations::Generation,
+    lockfile::{LockEntry, Lockfile},
+    state::State,
+    stow::Strategy,
+};
+
+static ASSUME_YES: AtomicBool = AtomicBool::new(false);
+static VERBOSITY: AtomicU8 = AtomicU8::new(1);
+
+/// Make every [`PackageProvider::confirm`] prompt answer yes without asking
+pub fn set_assume_yes(assume_yes: bool) {
+    ASSUME_YES.store(assume_yes, Ordering::Relaxed);
+}
+
+/// 0 only prints errors, 1 is the default and 2+ also prints debug messages
+pub fn set_verbosity(verbosity: u8) {
+    VERBOSITY.store(verbosity, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ProviderKind {
+    Binary,
+    Flatpak,
+    #[value(name = "appimage")]
+    AppImage,
+    System,
+    Dotfiles,
+}
+
+/// The result of comparing declared items against installed ones by their keys
+#[derive(Debug)]
+pub struct Changes<T> {
+    pub new: Vec<T>,
+    pub unchanged: Vec<T>,
+    /// Pairs of `(installed, declared)` items that share a key but otherwise differ
+    pub upgraded: Vec<(T, T)>,
+    pub removed: Vec<T>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ActionKind {
+    Install,
+    Upgrade,
+    Remove,
+    Noop,
+}
+
+/// A single change a provider intends to make
+#[derive(Debug, Serialize)]
+pub struct Action {
+    pub provider: &'static str,
+    #[serde(rename = "action")]
+    pub kind: ActionKind,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let symbol = match self.kind {
+            ActionKind::Install => '+',
+            ActionKind::Upgrade => '~',
+            ActionKind::Remove => '-',
+            ActionKind::Noop => '=',
+        };
+
+        write!(f, "[{}] {symbol} {}", self.provider, self.name)?;
+        if let Some(detail) = &self.detail {
+            write!(f, " ({detail})")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs the programs a provider drives, so what it would run can be checked without running it
+trait CommandRunner: Debug {
+    /// Run `argv` attached to the terminal
+    fn status(&self, argv: &[&str]) -> std::io::Result<ExitStatus>;
+
+    /// Run `argv` and capture its stdout and stderr
+    fn output(&self, argv: &[&str]) -> std::io::Result<Output>;
+
+    /// Start `argv` with its stdout and stderr piped, so they can be logged as they come
+    fn spawn(&self, argv: &[&str]) -> std::io::Result<Child>;
+}
+
+/// Runs programs for real, looking them up in `$PATH`
+#[derive(Debug)]
+struct ProcessRunner;
+
+impl CommandRunner for ProcessRunner {
+    fn status(&self, argv: &[&str]) -> std::io::Result<ExitStatus> {
+        Command::new(argv[0]).args(&argv[1..]).status()
+    }
+
+    fn output(&self, argv: &[&str]) -> std::io::Result<Output> {
+        Command::new(argv[0])
+            .args(&argv[1..])
+            .stderr(Stdio::piped())
+            .output()
+    }
+
+    fn spawn(&self, argv: &[&str]) -> std::io::Result<Child> {
+        Command::new(argv[0])
+            .args(&argv[1..])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+    }
+}
 
 pub trait PackageProvider {
-    type Item: Eq + Hash + Clone;
+    /// Serialized into generations, so it must round-trip through its own `Deserialize`
+    type Item: Eq + Hash + Clone + Serialize + DeserializeOwned;
+    /// Identifies an item independently of its version, source, etc.
+    type Key: Eq + Hash + Display;
     const LOG_PREFIX: &'static str;
 
     fn install_items(&self, items: &[Self::Item]) -> crate::Result<()>;
     fn remove_items(&self, items: &[Self::Item]) -> crate::Result<()>;
     fn ensure(&self) -> crate::Result<()>;
     fn get_installed(&self) -> crate::Result<Vec<Self::Item>>;
+    /// The items declared in the config
+    fn declared(&self) -> &[Self::Item];
+    /// What to record in the lockfile for the installed subset of `items`, keyed by [`PackageProvider::key`]
+    fn lock_entries(&self, items: &[Self::Item]) -> crate::Result<Vec<(String, LockEntry)>>;
+    /// Refuse to install anything that doesn't match `locked` exactly
+    fn set_locked(&mut self, locked: BTreeMap<String, LockEntry>);
+
+    /// Resolve anything declared loosely, like the latest release of a binary, to exactly what to install
+    fn resolve(&mut self) -> crate::Result<()> {
+        Ok(())
+    }
+
+    /// Resolve without going online, for commands that only report on what's installed.
+    /// Only providers whose [`PackageProvider::resolve`] looks things up need to override this
+    fn resolve_offline(&mut self) -> crate::Result<()> {
+        self.resolve()
+    }
+
+    fn key(item: &Self::Item) -> Self::Key;
+
+    /// Describe the change from `old` to `new`, for items that share a key
+    fn describe_upgrade(_old: &Self::Item, _new: &Self::Item) -> String {
+        String::from("configuration changed")
+    }
+
+    /// Anything worth knowing before `item` is installed or upgraded, e.g. what happens to files in its way
+    fn describe_install(&self, _item: &Self::Item) -> crate::Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// Whether the installed `item` drifted from what installing it would leave behind, e.g. its files were edited,
+    /// so it's reinstalled even though it matches what's declared
+    fn out_of_sync(&self, _item: &Self::Item) -> crate::Result<bool> {
+        Ok(false)
+    }
 
-    fn diff(v1: &[Self::Item], v2: &[Self::Item]) -> Option<Vec<Self::Item>> {
-        let s1: HashSet<_> = v1.iter().collect();
-        let s2: HashSet<_> = v2.iter().collect();
-        let diff: Vec<_> = s1.difference(&s2).map(|&entry| entry.to_owned()).collect();
-        if diff.is_empty() { None } else { Some(diff) }
+    /// Compare `declared` against what's installed, upgrading installed items that are out of sync in place
+    fn changes(&self, declared: &[Self::Item]) -> crate::Result<Changes<Self::Item>> {
+        let mut changes = Self::keyed_diff(declared, &self.get_installed()?);
+
+        let mut unchanged = Vec::new();
+        for item in std::mem::take(&mut changes.unchanged) {
+            if self.out_of_sync(&item)? {
+                changes.upgraded.push((item.clone(), item));
+            } else {
+                unchanged.push(item);
+            }
+        }
+        changes.unchanged = unchanged;
+
+        Ok(changes)
+    }
+
+    /// Work out what applying the config in `mode` would do, without changing anything
+    fn plan(&self, mode: RunMode) -> crate::Result<Vec<Action>> {
+        let changes = self.changes(self.declared())?;
+        let mut actions = Self::actions(&changes);
+
+        for action in actions.iter_mut() {
+            if !matches!(action.kind, ActionKind::Install | ActionKind::Upgrade) {
+                continue;
+            }
+            let Some(item) = self
+                .declared()
+                .iter()
+                .find(|item| Self::key(item).to_string() == action.name)
+            else {
+                continue;
+            };
+            if let Some(extra) = self.describe_install(item)? {
+                action.detail = Some(match action.detail.take() {
+                    Some(detail) => format!("{detail}, {extra}"),
+                    None => extra,
+                });
+            }
+        }
+
+        if matches!(mode, RunMode::Idempotent) {
+            let state = State::load()?;
+            for action in actions.iter_mut() {
+                if action.kind == ActionKind::Remove
+                    && state.get(Self::LOG_PREFIX, &action.name).is_none()
+                {
+                    action.kind = ActionKind::Noop;
+                    action.detail = Some(String::from("installed outside of bow, left alone"));
+                }
+            }
+        } else {
+            actions.retain(|action| action.kind != ActionKind::Remove);
+        }
+
+        Ok(actions)
+    }
+
+    /// Turn `changes` into actions, removals included
+    fn actions(changes: &Changes<Self::Item>) -> Vec<Action> {
+        let action = |kind, item: &Self::Item, detail| Action {
+            provider: Self::LOG_PREFIX,
+            kind,
+            name: Self::key(item).to_string(),
+            detail,
+        };
+
+        let mut actions = Vec::new();
+        actions.extend(
+            changes
+                .new
+                .iter()
+                .map(|item| action(ActionKind::Install, item, None)),
+        );
+        actions.extend(changes.upgraded.iter().map(|(old, new)| {
+            action(
+                ActionKind::Upgrade,
+                new,
+                Some(Self::describe_upgrade(old, new)),
+            )
+        }));
+        actions.extend(
+            changes
+                .unchanged
+                .iter()
+                .map(|item| action(ActionKind::Noop, item, None)),
+        );
+        actions.extend(
+            changes
+                .removed
+                .iter()
+                .map(|item| action(ActionKind::Remove, item, None)),
+        );
+
+        actions
+    }
+
+    /// Install, upgrade and remove items by their keys, asking before each step
+    fn keyed_ensure(&self) -> crate::Result<()> {
+        let changes = self.changes(self.declared())?;
+
+        if !changes.new.is_empty() {
+            Self::log_msg("Found packages to install");
+            for b in changes.new.iter() {
+                match self.describe_install(b)? {
+                    Some(extra) => Self::log_msg(&format!("    {} ({extra})", Self::key(b))),
+                    None => Self::log_msg(&format!("    {}", Self::key(b))),
+                }
+            }
+
+            if Self::confirm("Install the above packages?")? {
+                self.install_items(&changes.new)?;
+            } else {
+                Self::log_msg(&format!(
+                    "Skipping install of {} package(s)",
+                    changes.new.len()
+                ));
+            }
+        } else {
+            Self::log_msg("Nothing to install");
+        }
+
+        if !changes.upgraded.is_empty() {
+            Self::log_msg("Found packages to upgrade");
+            for (old, new) in changes.upgraded.iter() {
+                let mut detail = Self::describe_upgrade(old, new);
+                if let Some(extra) = self.describe_install(new)? {
+                    detail = format!("{detail}, {extra}");
+                }
+                Self::log_msg(&format!("    {} {detail}", Self::key(new)));
+            }
+
+            if Self::confirm("Upgrade the above packages?")? {
+                let to_upgrade: Vec<_> = changes.upgraded.into_iter().map(|(_, new)| new).collect();
+                self.install_items(&to_upgrade)?;
+            } else {
+                Self::log_msg(&format!(
+                    "Skipping upgrade of {} package(s)",
+                    changes.upgraded.len()
+                ));
+            }
+        } else {
+            Self::log_msg("Nothing to upgrade");
+        }
+
+        let mut removed = changes.removed;
+        let foreign = take_foreign::<Self>(&mut removed)?;
+        if !foreign.is_empty() {
+            Self::log_msg("Leaving packages installed outside of bow alone");
+            for b in foreign.iter() {
+                Self::log_msg(&format!("    {}", Self::key(b)));
+            }
+        }
+
+        if !removed.is_empty() {
+            Self::log_msg("Found packages to remove");
+            for b in removed.iter() {
+                Self::log_msg(&format!("    {}", Self::key(b)));
+            }
+
+            if Self::confirm("Remove the above packages?")? {
+                self.remove_items(&removed)?;
+            } else {
+                Self::log_msg(&format!("Skipping removal of {} package(s)", removed.len()));
+            }
+        } else {
+            Self::log_msg("Nothing to remove");
+        }
+
+        Ok(())
+    }
+
+    fn keyed_diff(declared: &[Self::Item], installed: &[Self::Item]) -> Changes<Self::Item> {
+        let declared_by_key: HashMap<_, _> = declared.iter().map(|i| (Self::key(i), i)).collect();
+        let installed_by_key: HashMap<_, _> = installed.iter().map(|i| (Self::key(i), i)).collect();
+
+        let mut changes = Changes {
+            new: Vec::new(),
+            unchanged: Vec::new(),
+            upgraded: Vec::new(),
+            removed: Vec::new(),
+        };
+
+        for item in declared {
+            match installed_by_key.get(&Self::key(item)) {
+                None => changes.new.push(item.clone()),
+                Some(&old) if old == item => changes.unchanged.push(item.clone()),
+                Some(&old) => changes.upgraded.push((old.clone(), item.clone())),
+            }
+        }
+
+        for item in installed {
+            if !declared_by_key.contains_key(&Self::key(item)) {
+                changes.removed.push(item.clone());
+            }
+        }
+
+        changes
     }
 
     fn log_msg(msg: &str) {
-        println!("[{}] {}", Self::LOG_PREFIX, msg);
+        if VERBOSITY.load(Ordering::Relaxed) >= 1 {
+            println!("[{}] {}", Self::LOG_PREFIX, msg);
+        }
+    }
+
+    fn log_debug(msg: &str) {
+        if VERBOSITY.load(Ordering::Relaxed) >= 2 {
+            println!("[{}] {}", Self::LOG_PREFIX, msg);
+        }
     }
 
     fn log_err(err: impl Debug + Display) {
@@ -40,6 +404,11 @@ pub trait PackageProvider {
     }
 
     fn confirm(msg: &str) -> crate::Result<bool> {
+        if ASSUME_YES.load(Ordering::Relaxed) {
+            Self::log_msg(&format!("{msg} [y/N]: y"));
+            return Ok(true);
+        }
+
         loop {
             print!("[{}] {} [y/N]: ", Self::LOG_PREFIX, msg);
             std::io::stdout().flush()?;
@@ -58,30 +427,432 @@ pub trait PackageProvider {
     }
 }
 
+/// Run `$body` for every configured provider in [`PackagesConfig`], with `$provider` bound to it and `$ty` to its type,
+/// collecting what each one returns. The first error is returned from the calling function
+macro_rules! for_each_provider {
+    (mut $config:expr, |$provider:ident: $ty:ident| $body:expr) => {
+        for_each_provider!(@fields as_mut, $config, $provider, $ty, $body)
+    };
+    ($config:expr, |$provider:ident: $ty:ident| $body:expr) => {
+        for_each_provider!(@fields as_ref, $config, $provider, $ty, $body)
+    };
+    (@fields $as_ref:ident, $config:expr, $provider:ident, $ty:ident, $body:expr) => {
+        for_each_provider!(@each $as_ref, $config, $provider, $ty, $body;
+            binaries: BinaryProvider,
+            appimages: AppImageProvider,
+            flatpaks: FlatpakProvider,
+            system: SystemProvider,
+            dotfiles: DotfilesProvider,
+        )
+    };
+    (
+        @each $as_ref:ident, $config:expr, $provider:ident, $ty:ident, $body:expr;
+        $($field:ident: $provider_type:ty,)+
+    ) => {{
+        let mut results = Vec::new();
+        $(
+            if let Some($provider) = $config.$field.$as_ref() {
+                type $ty = $provider_type;
+                results.push(in_provider::<$ty, _>(|| $body)?);
+            }
+        )+
+        results
+    }};
+}
+
 #[derive(Debug, Deserialize)]
 pub struct PackagesConfig {
     #[serde(rename = "binary")]
     binaries: Option<BinaryProvider>,
+    #[serde(rename = "appimage")]
+    appimages: Option<AppImageProvider>,
     #[serde(rename = "flatpak")]
     flatpaks: Option<FlatpakProvider>,
+    system: Option<SystemProvider>,
+    dotfiles: Option<DotfilesProvider>,
 }
 
 impl PackagesConfig {
-    pub fn install(&self, mode: RunMode) -> crate::Result<()> {
-        if let Some(binaries) = &self.binaries {
-            match mode {
-                RunMode::Idempotent => binaries.ensure()?,
-                RunMode::Imperative => binaries.install_items(&binaries.binaries)?,
-            }
+    /// Drop every provider not listed in `only`, an empty list keeps them all
+    pub fn retain_only(&mut self, only: &[ProviderKind]) {
+        if only.is_empty() {
+            return;
         }
 
-        if let Some(flatpaks) = &self.flatpaks {
-            match mode {
-                RunMode::Idempotent => flatpaks.ensure()?,
-                RunMode::Imperative => flatpaks.install_items(&flatpaks.flatpaks)?,
-            }
+        if !only.contains(&ProviderKind::Binary) {
+            self.binaries = None;
+        }
+        if !only.contains(&ProviderKind::Flatpak) {
+            self.flatpaks = None;
+        }
+        if !only.contains(&ProviderKind::AppImage) {
+            self.appimages = None;
+        }
+        if !only.contains(&ProviderKind::System) {
+            self.system = None;
         }
+        if !only.contains(&ProviderKind::Dotfiles) {
+            self.dotfiles = None;
+        }
+    }
+
+    /// Deal with files in the way of dotfiles according to `strategy`, rather than what the config says
+    pub fn set_on_conflict(&mut self, strategy: Strategy) {
+        if let Some(dotfiles) = &mut self.dotfiles {
+            dotfiles.set_on_conflict(strategy);
+        }
+    }
+
+    /// Make every provider install exactly what's recorded in `lock`
+    pub fn set_locked(&mut self, lock: &Lockfile) -> crate::Result<()> {
+        for_each_provider!(mut self, |provider: P| {
+            provider.set_locked(lock.section(P::LOG_PREFIX));
+            Ok(())
+        });
 
         Ok(())
     }
+
+    /// Resolve anything declared loosely, like the latest release of a binary, to exactly what to install
+    pub fn resolve(&mut self) -> crate::Result<()> {
+        for_each_provider!(mut self, |provider: P| provider.resolve());
+
+        Ok(())
+    }
+
+    /// Resolve without going online, taking what's installed where it still matches what's declared
+    pub fn resolve_offline(&mut self) -> crate::Result<()> {
+        for_each_provider!(mut self, |provider: P| provider.resolve_offline());
+
+        Ok(())
+    }
+
+    /// Record what every provider resolved its declared packages to
+    pub fn update_lock(&self, lock: &mut Lockfile) -> crate::Result<()> {
+        for_each_provider!(self, |provider: P| {
+            lock.update(P::LOG_PREFIX, provider.lock_entries(provider.declared())?);
+            Ok(())
+        });
+
+        Ok(())
+    }
+
+    pub fn install(&self, mode: RunMode) -> crate::Result<()> {
+        for_each_provider!(self, |provider: P| match mode {
+            RunMode::Idempotent => provider.ensure(),
+            RunMode::Imperative => provider.install_items(provider.declared()),
+        });
+
+        Ok(())
+    }
+
+    pub fn plan(&self, mode: RunMode) -> crate::Result<Vec<Action>> {
+        let actions = for_each_provider!(self, |provider: P| provider.plan(mode));
+
+        Ok(actions.into_iter().flatten().collect())
+    }
+
+    /// What every provider has installed right now, keyed by provider and then by package
+    pub fn snapshot(&self) -> crate::Result<BTreeMap<String, BTreeMap<String, Value>>> {
+        let sections = for_each_provider!(self, |provider: P| {
+            snapshot_of(provider).map(|section| (P::LOG_PREFIX.to_string(), section))
+        });
+
+        Ok(sections.into_iter().collect())
+    }
+
+    /// What changed from generation `old` to generation `new`
+    pub fn diff_generations(old: &Generation, new: &Generation) -> crate::Result<Vec<Action>> {
+        let mut actions = Vec::new();
+        actions.extend(
+            generation_diff::<BinaryProvider>(old, new).for_provider(BinaryProvider::LOG_PREFIX)?,
+        );
+        actions.extend(
+            generation_diff::<AppImageProvider>(old, new)
+                .for_provider(AppImageProvider::LOG_PREFIX)?,
+        );
+        actions.extend(
+            generation_diff::<FlatpakProvider>(old, new)
+                .for_provider(FlatpakProvider::LOG_PREFIX)?,
+        );
+        actions.extend(
+            generation_diff::<SystemProvider>(old, new).for_provider(SystemProvider::LOG_PREFIX)?,
+        );
+        actions.extend(
+            generation_diff::<DotfilesProvider>(old, new)
+                .for_provider(DotfilesProvider::LOG_PREFIX)?,
+        );
+
+        Ok(actions)
+    }
+
+    /// What it would take to return the system to `generation`
+    pub fn switch_plan(&self, generation: &Generation) -> crate::Result<Vec<Action>> {
+        let actions = for_each_provider!(self, |provider: P| {
+            let changes = switch_changes(provider, generation)?;
+            Ok(changes
+                .map(|changes| P::actions(&changes))
+                .unwrap_or_default())
+        });
+
+        Ok(actions.into_iter().flatten().collect())
+    }
+
+    /// Install and remove packages until every provider matches `generation`
+    pub fn switch(&self, generation: &Generation) -> crate::Result<()> {
+        for_each_provider!(self, |provider: P| switch_to(provider, generation));
+
+        Ok(())
+    }
+
+    pub fn status(&self) -> crate::Result<()> {
+        for_each_provider!(self, |provider: P| print_status(provider));
+
+        Ok(())
+    }
+
+    pub fn list(&self) -> crate::Result<()> {
+        for_each_provider!(self, |provider: P| print_installed(provider));
+
+        Ok(())
+    }
+}
+
+/// Take the packages installed outside of bow out of `items` and return them, bow never removes those
+fn take_foreign<P: PackageProvider + ?Sized>(
+    items: &mut Vec<P::Item>,
+) -> crate::Result<Vec<P::Item>> {
+    let state = State::load()?;
+    let (owned, foreign) = std::mem::take(items).into_iter().partition(|item| {
+        state
+            .get(P::LOG_PREFIX, &P::key(item).to_string())
+            .is_some()
+    });
+    *items = owned;
+
+    Ok(foreign)
+}
+
+/// Run a single provider's part of an operation, naming the provider in any error it returns
+fn in_provider<P: PackageProvider, T>(run: impl FnOnce() -> crate::Result<T>) -> crate::Result<T> {
+    run().for_provider(P::LOG_PREFIX)
+}
+
+fn print_status<P: PackageProvider>(provider: &P) -> crate::Result<()> {
+    let changes = provider.changes(provider.declared())?;
+
+    for item in changes.unchanged.iter() {
+        P::log_msg(&format!("{} (installed)", P::key(item)));
+    }
+    for (old, new) in changes.upgraded.iter() {
+        P::log_msg(&format!(
+            "{} (outdated, {})",
+            P::key(new),
+            P::describe_upgrade(old, new)
+        ));
+    }
+    for item in changes.new.iter() {
+        P::log_msg(&format!("{} (missing)", P::key(item)));
+    }
+    for item in changes.removed.iter() {
+        P::log_msg(&format!("{} (not declared)", P::key(item)));
+    }
+
+    Ok(())
+}
+
+fn snapshot_of<P: PackageProvider>(provider: &P) -> crate::Result<BTreeMap<String, Value>> {
+    provider
+        .get_installed()?
+        .iter()
+        .map(|item| {
+            let value = serde_yaml_bw::to_value(item).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("failed to record {}: {e}", P::key(item)),
+                )
+            })?;
+            Ok((P::key(item).to_string(), value))
+        })
+        .collect()
+}
+
+fn generation_diff<P: PackageProvider>(
+    old: &Generation,
+    new: &Generation,
+) -> crate::Result<Vec<Action>> {
+    let old_items = old.items::<P>()?.unwrap_or_default();
+    let new_items = new.items::<P>()?.unwrap_or_default();
+
+    Ok(P::actions(&P::keyed_diff(&new_items, &old_items)))
+}
+
+/// The changes needed for `provider` to match `generation`,
+/// or `None` if the provider wasn't configured when it was created.
+/// Packages installed outside of bow are left alone
+fn switch_changes<P: PackageProvider>(
+    provider: &P,
+    generation: &Generation,
+) -> crate::Result<Option<Changes<P::Item>>> {
+    let Some(target) = generation.items::<P>()? else {
+        return Ok(None);
+    };
+
+    let mut changes = provider.changes(&target)?;
+    take_foreign::<P>(&mut changes.removed)?;
+
+    Ok(Some(changes))
+}
+
+fn switch_to<P: PackageProvider>(provider: &P, generation: &Generation) -> crate::Result<()> {
+    let Some(changes) = switch_changes(provider, generation)? else {
+        P::log_msg(&format!(
+            "Not recorded in generation {}, skipping",
+            generation.number
+        ));
+        return Ok(());
+    };
+
+    let to_install: Vec<_> = changes
+        .new
+        .iter()
+        .cloned()
+        .chain(changes.upgraded.iter().map(|(_, new)| new.clone()))
+        .collect();
+
+    if to_install.is_empty() && changes.removed.is_empty() {
+        P::log_msg(&format!("Already matches generation {}", generation.number));
+        return Ok(());
+    }
+
+    if !P::confirm(&format!("Switch to generation {}?", generation.number))? {
+        P::log_msg("Skipping switch");
+        return Ok(());
+    }
+
+    if !to_install.is_empty() {
+        provider.install_items(&to_install)?;
+    }
+    if !changes.removed.is_empty() {
+        provider.remove_items(&changes.removed)?;
+    }
+
+    Ok(())
+}
+
+fn print_installed<P: PackageProvider>(provider: &P) -> crate::Result<()> {
+    for item in provider.get_installed()? {
+        P::log_msg(&P::key(&item).to_string());
+    }
+
+    Ok(())
+}
+
+/// Print a plan for humans, no-ops are only shown when `verbose` is set
+pub fn print_plan(actions: &[Action], verbose: bool) {
+    let mut changes = 0;
+    for action in actions {
+        if action.kind != ActionKind::Noop {
+            changes += 1;
+        } else if !verbose {
+            continue;
+        }
+
+        println!("{action}");
+    }
+
+    if changes == 0 {
+        println!("No changes");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Items are `(name, version)` pairs keyed by their name
+    struct Fake(Vec<(String, u32)>);
+
+    impl PackageProvider for Fake {
+        type Item = (String, u32);
+        type Key = String;
+        const LOG_PREFIX: &'static str = "fake";
+
+        fn install_items(&self, _items: &[Self::Item]) -> crate::Result<()> {
+            Ok(())
+        }
+
+        fn remove_items(&self, _items: &[Self::Item]) -> crate::Result<()> {
+            Ok(())
+        }
+
+        fn ensure(&self) -> crate::Result<()> {
+            Ok(())
+        }
+
+        fn get_installed(&self) -> crate::Result<Vec<Self::Item>> {
+            Ok(Vec::new())
+        }
+
+        fn declared(&self) -> &[Self::Item] {
+            &self.0
+        }
+
+        fn lock_entries(&self, _items: &[Self::Item]) -> crate::Result<Vec<(String, LockEntry)>> {
+            Ok(Vec::new())
+        }
+
+        fn set_locked(&mut self, _locked: BTreeMap<String, LockEntry>) {}
+
+        fn key(item: &Self::Item) -> Self::Key {
+            item.0.clone()
+        }
+    }
+
+    fn item(name: &str, version: u32) -> (String, u32) {
+        (name.to_string(), version)
+    }
+
+    #[test]
+    fn keyed_diff_sorts_items_by_key() {
+        let declared = [item("new", 1), item("same", 1), item("changed", 2)];
+        let installed = [item("same", 1), item("changed", 1), item("gone", 1)];
+
+        let changes = Fake::keyed_diff(&declared, &installed);
+
+        assert_eq!(changes.new, vec![item("new", 1)]);
+        assert_eq!(changes.unchanged, vec![item("same", 1)]);
+        assert_eq!(
+            changes.upgraded,
+            vec![(item("changed", 1), item("changed", 2))]
+        );
+        assert_eq!(changes.removed, vec![item("gone", 1)]);
+    }
+
+    #[test]
+    fn keyed_diff_of_nothing_installed_is_all_new() {
+        let declared = [item("a", 1), item("b", 1)];
+
+        let changes = Fake::keyed_diff(&declared, &[]);
+
+        assert_eq!(changes.new, declared.to_vec());
+        assert!(changes.unchanged.is_empty());
+        assert!(changes.upgraded.is_empty());
+        assert!(changes.removed.is_empty());
+    }
+
+    #[test]
+    fn actions_describe_upgrades() {
+        let changes = Fake::keyed_diff(&[item("a", 2)], &[item("a", 1), item("b", 1)]);
+
+        let actions: Vec<_> = Fake::actions(&changes)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+
+        assert_eq!(
+            actions,
+            vec!["[fake] ~ a (configuration changed)", "[fake] - b"]
+        );
+    }
 }
@@ -0,0 +1,864 @@
+use std::{cell::OnceCell, collections::BTreeMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Context, Error},
+    lockfile::{self, LockEntry},
+    package_types::{CommandRunner, PackageProvider, ProcessRunner},
+    state::{State, StateEntry},
+    template,
+};
+
+/// A distro package manager bow knows how to drive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PackageManager {
+    Dnf,
+    Apt,
+    Pacman,
+    Zypper,
+}
+
+impl PackageManager {
+    /// Detect the package manager from the `ID` and `ID_LIKE` of `/etc/os-release`
+    pub fn detect() -> crate::Result<Self> {
+        let (path, contents) = template::os_release().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "failed to read /etc/os-release, please set `manager`",
+            )
+        })?;
+
+        let field = |key: &str| template::os_release_value(&contents, key).unwrap_or_default();
+        let id = field("ID");
+        let id_like = field("ID_LIKE");
+
+        std::iter::once(id.as_str())
+            .chain(id_like.split_whitespace())
+            .find_map(Self::for_distro)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!(
+                        "don't know the package manager of `{id}` (from {path}), please set `manager`"
+                    ),
+                )
+                .into()
+            })
+    }
+
+    fn for_distro(id: &str) -> Option<Self> {
+        Some(match id {
+            "fedora" | "rhel" | "centos" | "rocky" | "almalinux" | "nobara" => Self::Dnf,
+            "debian" | "ubuntu" | "linuxmint" | "pop" | "raspbian" => Self::Apt,
+            "arch" | "archarm" | "manjaro" | "endeavouros" | "cachyos" => Self::Pacman,
+            id if id == "suse" || id == "sles" || id.starts_with("opensuse") => Self::Zypper,
+            _ => return None,
+        })
+    }
+
+    /// The command that installs packages without asking, minus the packages
+    fn install_command(self) -> &'static [&'static str] {
+        match self {
+            Self::Dnf => &["dnf", "install", "-y"],
+            Self::Apt => &["apt-get", "install", "-y"],
+            Self::Pacman => &["pacman", "-S", "--needed", "--noconfirm"],
+            Self::Zypper => &["zypper", "--non-interactive", "install"],
+        }
+    }
+
+    /// The command that removes packages without asking, minus the packages
+    fn remove_command(self) -> &'static [&'static str] {
+        match self {
+            Self::Dnf => &["dnf", "remove", "-y"],
+            Self::Apt => &["apt-get", "remove", "-y"],
+            Self::Pacman => &["pacman", "-R", "--noconfirm"],
+            Self::Zypper => &["zypper", "--non-interactive", "remove"],
+        }
+    }
+
+    /// The command that lists every installed package as `<name> <version>` lines
+    fn query_command(self) -> &'static [&'static str] {
+        match self {
+            Self::Dnf | Self::Zypper => &[
+                "rpm",
+                "-qa",
+                "--queryformat",
+                "%{NAME} %{VERSION}-%{RELEASE}\n",
+            ],
+            Self::Apt => &[
+                "dpkg-query",
+                "-W",
+                "-f",
+                "${db:Status-Abbrev} ${Package} ${Version}\n",
+            ],
+            Self::Pacman => &["pacman", "-Q"],
+        }
+    }
+
+    /// How to ask for a specific version of a package, or `None` if the package manager can't.
+    /// pacman only ever installs what its repositories have right now
+    fn pinned(self, name: &str, version: &str) -> Option<String> {
+        match self {
+            Self::Dnf => Some(format!("{name}-{version}")),
+            Self::Apt | Self::Zypper => Some(format!("{name}={version}")),
+            Self::Pacman => None,
+        }
+    }
+}
+
+/// How to get the privileges the package manager needs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Escalation {
+    Sudo,
+    Pkexec,
+    /// Run the package manager as is, e.g. when bow itself runs as root
+    None,
+}
+
+impl Escalation {
+    /// `sudo`, unless bow already runs as root
+    fn default_for_user() -> Self {
+        use std::os::unix::fs::MetadataExt;
+
+        match std::fs::metadata("/proc/self") {
+            Ok(meta) if meta.uid() == 0 => Self::None,
+            _ => Self::Sudo,
+        }
+    }
+
+    fn program(self) -> Option<&'static str> {
+        match self {
+            Self::Sudo => Some("sudo"),
+            Self::Pkexec => Some("pkexec"),
+            Self::None => None,
+        }
+    }
+}
+
+/// Every command the system provider runs goes through here
+#[derive(Debug)]
+struct Commands<'a> {
+    manager: PackageManager,
+    escalation: Escalation,
+    runner: &'a dyn CommandRunner,
+}
+
+impl Commands<'_> {
+    /// Every installed package and its version, straight from the package database
+    fn query(&self) -> crate::Result<BTreeMap<String, String>> {
+        let argv = self.manager.query_command();
+        let output = self
+            .runner
+            .output(argv)
+            .map_err(|e| Error::from_spawn(argv[0], e))?;
+
+        if !output.status.success() {
+            return Err(Error::CommandFailed {
+                command: argv.join(" "),
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut installed = BTreeMap::new();
+        for line in stdout.lines() {
+            let mut fields = line.split_whitespace();
+            if self.manager == PackageManager::Apt {
+                // Only `ii` (installed, no errors) counts, not removed packages whose config is left behind
+                if fields.next() != Some("ii") {
+                    continue;
+                }
+            }
+
+            if let (Some(name), Some(version)) = (fields.next(), fields.next()) {
+                installed.insert(name.to_string(), version.to_string());
+            }
+        }
+
+        Ok(installed)
+    }
+
+    fn install(&self, packages: &[String]) -> crate::Result<()> {
+        self.run(self.manager.install_command(), packages)
+    }
+
+    fn remove(&self, packages: &[String]) -> crate::Result<()> {
+        self.run(self.manager.remove_command(), packages)
+    }
+
+    /// Run `command` on `packages` with escalated privileges, letting it talk to the terminal
+    /// so that sudo can ask for a password
+    fn run(&self, command: &[&str], packages: &[String]) -> crate::Result<()> {
+        let mut argv: Vec<&str> = self.escalation.program().into_iter().collect();
+        argv.extend(command);
+        argv.extend(packages.iter().map(String::as_str));
+
+        let status = self
+            .runner
+            .status(&argv)
+            .map_err(|e| Error::from_spawn(argv[0], e))?;
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err(Error::CommandFailed {
+                command: argv.join(" "),
+                status,
+                stderr: String::new(),
+            })
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SystemProvider {
+    /// Detected from `/etc/os-release` the first time it's needed, unless set in the config
+    manager: OnceCell<PackageManager>,
+    escalation: Escalation,
+    pub packages: Vec<SystemPackage>,
+    locked: Option<BTreeMap<String, LockEntry>>,
+    runner: Box<dyn CommandRunner>,
+}
+
+impl SystemProvider {
+    fn new(
+        manager: Option<PackageManager>,
+        escalation: Escalation,
+        packages: Vec<SystemPackage>,
+    ) -> Self {
+        Self {
+            manager: manager.map(OnceCell::from).unwrap_or_default(),
+            escalation,
+            packages,
+            locked: None,
+            runner: Box::new(ProcessRunner),
+        }
+    }
+
+    /// Run every command through `runner` instead of running it for real
+    #[cfg(test)]
+    fn with_runner(mut self, runner: impl CommandRunner + 'static) -> Self {
+        self.runner = Box::new(runner);
+        self
+    }
+
+    fn commands(&self) -> crate::Result<Commands<'_>> {
+        let manager = match self.manager.get() {
+            Some(manager) => *manager,
+            None => {
+                let manager = PackageManager::detect()?;
+                Self::log_debug(&format!("Detected package manager {manager:?}"));
+                *self.manager.get_or_init(|| manager)
+            }
+        };
+
+        Ok(Commands {
+            manager,
+            escalation: self.escalation,
+            runner: self.runner.as_ref(),
+        })
+    }
+
+    /// Record every package in `items` that made it into the package database,
+    /// and check that each locked one ended up on its locked version.
+    /// Packages that were already there `before` don't become bow's to remove
+    fn record_installed(
+        &self,
+        commands: &Commands,
+        items: &[SystemPackage],
+        before: &BTreeMap<String, String>,
+    ) -> crate::Result<()> {
+        let installed = commands.query()?;
+
+        let mut state = State::load()?;
+        for p in items
+            .iter()
+            .filter(|p| installed.contains_key(&p.name) && !before.contains_key(&p.name))
+        {
+            state.insert(Self::LOG_PREFIX, &p.name, StateEntry::now());
+        }
+        state.save()?;
+
+        if let Some(locked) = &self.locked {
+            for p in items {
+                let Some(expected) = locked.get(&p.name).and_then(|e| e.version.as_ref()) else {
+                    continue;
+                };
+
+                let actual = installed.get(&p.name);
+                if actual != Some(expected) {
+                    return Err(Error::LockMismatch {
+                        expected: expected.clone(),
+                        actual: actual
+                            .cloned()
+                            .unwrap_or_else(|| String::from("<not installed>")),
+                    })
+                    .for_package(&p.name);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for SystemProvider {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        pub struct Fields {
+            manager: Option<PackageManager>,
+            escalate: Option<Escalation>,
+            packages: Vec<SystemPackage>,
+        }
+
+        let f = Fields::deserialize(deserializer)?;
+
+        Ok(Self::new(
+            f.manager,
+            f.escalate.unwrap_or_else(Escalation::default_for_user),
+            f.packages,
+        ))
+    }
+}
+
+impl PackageProvider for SystemProvider {
+    type Item = SystemPackage;
+    type Key = String;
+    const LOG_PREFIX: &'static str = "system";
+
+    fn key(item: &Self::Item) -> Self::Key {
+        item.name.clone()
+    }
+
+    fn install_items(&self, items: &[Self::Item]) -> crate::Result<()> {
+        if items.is_empty() {
+            return Ok(());
+        }
+
+        let commands = self.commands()?;
+
+        // Everything has to be pinnable before anything is installed, or `--locked` would install whatever is newest
+        let mut packages = Vec::new();
+        for p in items.iter() {
+            match &self.locked {
+                Some(locked) => {
+                    let entry = locked
+                        .get(&p.name)
+                        .ok_or(Error::NotLocked)
+                        .for_package(&p.name)?;
+                    match &entry.version {
+                        Some(version) => {
+                            let Some(pinned) = commands.manager.pinned(&p.name, version) else {
+                                return Err(Error::from(std::io::Error::new(
+                                    std::io::ErrorKind::Unsupported,
+                                    "the package manager can't install a specific version, so it can't be locked",
+                                )))
+                                .for_package(&p.name);
+                            };
+                            packages.push(pinned);
+                        }
+                        None => packages.push(p.name.clone()),
+                    }
+                }
+                None => packages.push(p.name.clone()),
+            }
+        }
+
+        let before = commands.query()?;
+
+        Self::log_msg(&format!("Installing {}", packages.join(" ")));
+        let result = commands.install(&packages);
+        // Even if some failed, the ones that did get installed are bow's now
+        self.record_installed(&commands, items, &before)?;
+        result?;
+
+        Self::log_msg(&format!(
+            "Successfully installed {} package(s)",
+            items.len()
+        ));
+
+        Ok(())
+    }
+
+    fn remove_items(&self, items: &[Self::Item]) -> crate::Result<()> {
+        if items.is_empty() {
+            return Ok(());
+        }
+
+        let state = State::load()?;
+        if let Some(p) = items
+            .iter()
+            .find(|p| state.get(Self::LOG_PREFIX, &p.name).is_none())
+        {
+            return Err(Error::from(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "not installed by bow, refusing to remove it",
+            )))
+            .for_package(&p.name);
+        }
+
+        let commands = self.commands()?;
+        let packages: Vec<_> = items.iter().map(|p| p.name.clone()).collect();
+
+        Self::log_msg(&format!("Removing {}", packages.join(" ")));
+        let result = commands.remove(&packages);
+
+        let installed = commands.query()?;
+        let mut state = State::load()?;
+        for p in items.iter().filter(|p| !installed.contains_key(&p.name)) {
+            state.remove(Self::LOG_PREFIX, &p.name);
+        }
+        state.save()?;
+        result?;
+
+        Self::log_msg(&format!("Successfully removed {} package(s)", items.len()));
+
+        Ok(())
+    }
+
+    fn ensure(&self) -> crate::Result<()> {
+        self.keyed_ensure()
+    }
+
+    fn declared(&self) -> &[Self::Item] {
+        &self.packages
+    }
+
+    fn lock_entries(&self, items: &[Self::Item]) -> crate::Result<Vec<(String, LockEntry)>> {
+        let installed = self.commands()?.query()?;
+        let state = State::load()?;
+
+        Ok(items
+            .iter()
+            .filter_map(|p| {
+                let version = installed.get(&p.name)?;
+                let entry = LockEntry {
+                    version: Some(version.clone()),
+                    installed_at: state
+                        .get(Self::LOG_PREFIX, &p.name)
+                        .map_or_else(lockfile::now, |e| e.installed_at),
+                    ..Default::default()
+                };
+                Some((p.name.clone(), entry))
+            })
+            .collect())
+    }
+
+    fn set_locked(&mut self, locked: BTreeMap<String, LockEntry>) {
+        self.locked = Some(locked);
+    }
+
+    /// The installed packages that are either declared or were installed by bow.
+    /// Everything else on the system is none of bow's business, so it's never offered for removal
+    fn get_installed(&self) -> crate::Result<Vec<Self::Item>> {
+        let installed = self.commands()?.query()?;
+        let state = State::load()?;
+        let tracked = state.entries(Self::LOG_PREFIX);
+
+        Ok(installed
+            .into_keys()
+            .filter(|name| {
+                tracked.contains_key(name) || self.packages.iter().any(|p| p.name == *name)
+            })
+            .map(|name| SystemPackage::new(&name))
+            .collect())
+    }
+}
+
+/// A package from the distro's repositories, by the name its package manager knows it by
+#[derive(PartialEq, Eq, Debug, Hash, Clone, Serialize)]
+#[serde(transparent)]
+pub struct SystemPackage {
+    name: String,
+}
+
+impl SystemPackage {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SystemPackage {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let name = String::deserialize(deserializer)?;
+
+        Ok(Self::new(&name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::{Cell, RefCell},
+        os::unix::process::ExitStatusExt,
+        process::{Child, Command, ExitStatus, Output, Stdio},
+        rc::Rc,
+    };
+
+    use super::*;
+    use crate::state::{self, with_state_dir};
+
+    /// Records what would run instead of running it.
+    /// Queries print each of `stdout` in turn, sticking to the last one, and never fail,
+    /// everything else exits with `exit_code`
+    #[derive(Debug, Default)]
+    struct FakeRunner {
+        calls: Rc<RefCell<Vec<Vec<String>>>>,
+        stdout: Vec<&'static str>,
+        queries: Cell<usize>,
+        exit_code: i32,
+    }
+
+    impl FakeRunner {
+        fn record(&self, argv: &[&str]) -> ExitStatus {
+            self.calls
+                .borrow_mut()
+                .push(argv.iter().map(|arg| arg.to_string()).collect());
+            ExitStatus::from_raw(self.exit_code << 8)
+        }
+
+        fn calls(&self) -> Vec<Vec<String>> {
+            self.calls.borrow().clone()
+        }
+    }
+
+    impl CommandRunner for FakeRunner {
+        fn status(&self, argv: &[&str]) -> std::io::Result<ExitStatus> {
+            Ok(self.record(argv))
+        }
+
+        fn output(&self, argv: &[&str]) -> std::io::Result<Output> {
+            self.record(argv);
+            let query = self.queries.replace(self.queries.get() + 1);
+            let stdout = self
+                .stdout
+                .get(query)
+                .or(self.stdout.last())
+                .copied()
+                .unwrap_or_default();
+
+            Ok(Output {
+                status: ExitStatus::from_raw(0),
+                stdout: stdout.as_bytes().to_vec(),
+                stderr: Vec::new(),
+            })
+        }
+
+        fn spawn(&self, argv: &[&str]) -> std::io::Result<Child> {
+            self.record(argv);
+            Command::new("sh")
+                .args(["-c", &format!("exit {}", self.exit_code)])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+        }
+    }
+
+    fn commands(
+        manager: PackageManager,
+        escalation: Escalation,
+        runner: &FakeRunner,
+    ) -> Commands<'_> {
+        Commands {
+            manager,
+            escalation,
+            runner,
+        }
+    }
+
+    fn argv(args: &str) -> Vec<Vec<String>> {
+        vec![args.split(' ').map(String::from).collect()]
+    }
+
+    fn packages() -> Vec<String> {
+        vec!["htop".to_string(), "tree".to_string()]
+    }
+
+    #[test]
+    fn installs_with_each_package_manager() {
+        for (manager, expected) in [
+            (PackageManager::Dnf, "sudo dnf install -y htop tree"),
+            (PackageManager::Apt, "sudo apt-get install -y htop tree"),
+            (
+                PackageManager::Pacman,
+                "sudo pacman -S --needed --noconfirm htop tree",
+            ),
+            (
+                PackageManager::Zypper,
+                "sudo zypper --non-interactive install htop tree",
+            ),
+        ] {
+            let runner = FakeRunner::default();
+            commands(manager, Escalation::Sudo, &runner)
+                .install(&packages())
+                .unwrap();
+            assert_eq!(runner.calls(), argv(expected));
+        }
+    }
+
+    #[test]
+    fn removes_with_each_package_manager() {
+        for (manager, expected) in [
+            (PackageManager::Dnf, "sudo dnf remove -y htop tree"),
+            (PackageManager::Apt, "sudo apt-get remove -y htop tree"),
+            (
+                PackageManager::Pacman,
+                "sudo pacman -R --noconfirm htop tree",
+            ),
+            (
+                PackageManager::Zypper,
+                "sudo zypper --non-interactive remove htop tree",
+            ),
+        ] {
+            let runner = FakeRunner::default();
+            commands(manager, Escalation::Sudo, &runner)
+                .remove(&packages())
+                .unwrap();
+            assert_eq!(runner.calls(), argv(expected));
+        }
+    }
+
+    #[test]
+    fn escalates_with_the_configured_program() {
+        for (escalation, expected) in [
+            (Escalation::Sudo, "sudo dnf install -y htop tree"),
+            (Escalation::Pkexec, "pkexec dnf install -y htop tree"),
+            (Escalation::None, "dnf install -y htop tree"),
+        ] {
+            let runner = FakeRunner::default();
+            commands(PackageManager::Dnf, escalation, &runner)
+                .install(&packages())
+                .unwrap();
+            assert_eq!(runner.calls(), argv(expected));
+        }
+    }
+
+    #[test]
+    fn queries_without_escalating() {
+        let runner = FakeRunner {
+            stdout: vec!["htop 3.3.0-1\ntree 2.1.1-2\n"],
+            ..FakeRunner::default()
+        };
+        let installed = commands(PackageManager::Pacman, Escalation::Sudo, &runner)
+            .query()
+            .unwrap();
+
+        assert_eq!(runner.calls(), argv("pacman -Q"));
+        assert_eq!(
+            installed,
+            BTreeMap::from([
+                ("htop".to_string(), "3.3.0-1".to_string()),
+                ("tree".to_string(), "2.1.1-2".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn only_counts_fully_installed_apt_packages() {
+        let runner = FakeRunner {
+            stdout: vec!["ii  htop 3.3.0-4\nrc  tree 2.1.1-2\n"],
+            ..FakeRunner::default()
+        };
+        let installed = commands(PackageManager::Apt, Escalation::None, &runner)
+            .query()
+            .unwrap();
+
+        assert_eq!(
+            runner.calls(),
+            vec![vec![
+                "dpkg-query".to_string(),
+                "-W".to_string(),
+                "-f".to_string(),
+                "${db:Status-Abbrev} ${Package} ${Version}\n".to_string(),
+            ]]
+        );
+        assert_eq!(
+            installed,
+            BTreeMap::from([("htop".to_string(), "3.3.0-4".to_string())])
+        );
+    }
+
+    #[test]
+    fn reports_the_failed_command() {
+        let runner = FakeRunner {
+            exit_code: 100,
+            ..FakeRunner::default()
+        };
+        let err = commands(PackageManager::Apt, Escalation::Pkexec, &runner)
+            .remove(&packages())
+            .unwrap_err();
+
+        assert!(matches!(
+            err,
+            Error::CommandFailed { command, status, .. }
+                if command == "pkexec apt-get remove -y htop tree" && status.code() == Some(100)
+        ));
+    }
+
+    #[test]
+    fn pins_versions_the_way_each_package_manager_expects() {
+        assert_eq!(
+            PackageManager::Dnf.pinned("htop", "3.3.0-1").as_deref(),
+            Some("htop-3.3.0-1")
+        );
+        assert_eq!(
+            PackageManager::Apt.pinned("htop", "3.3.0-4").as_deref(),
+            Some("htop=3.3.0-4")
+        );
+        assert_eq!(
+            PackageManager::Zypper.pinned("htop", "3.3.0-1").as_deref(),
+            Some("htop=3.3.0-1")
+        );
+        assert_eq!(PackageManager::Pacman.pinned("htop", "3.3.0-1"), None);
+    }
+
+    #[test]
+    fn detects_the_package_manager_of_a_distro() {
+        assert_eq!(
+            PackageManager::for_distro("fedora"),
+            Some(PackageManager::Dnf)
+        );
+        assert_eq!(
+            PackageManager::for_distro("ubuntu"),
+            Some(PackageManager::Apt)
+        );
+        assert_eq!(
+            PackageManager::for_distro("endeavouros"),
+            Some(PackageManager::Pacman)
+        );
+        assert_eq!(
+            PackageManager::for_distro("opensuse-tumbleweed"),
+            Some(PackageManager::Zypper)
+        );
+        assert_eq!(PackageManager::for_distro("nixos"), None);
+    }
+
+    const RPM_QUERY: &str = "rpm -qa --queryformat";
+
+    fn provider(manager: PackageManager, runner: FakeRunner) -> SystemProvider {
+        SystemProvider::new(Some(manager), Escalation::None, Vec::new()).with_runner(runner)
+    }
+
+    fn recorded() -> Vec<String> {
+        State::load()
+            .unwrap()
+            .entries("system")
+            .into_keys()
+            .collect()
+    }
+
+    fn commands_run(calls: &Rc<RefCell<Vec<Vec<String>>>>) -> Vec<String> {
+        calls.borrow().iter().map(|argv| argv.join(" ")).collect()
+    }
+
+    #[test]
+    fn only_owns_the_packages_it_installed() {
+        with_state_dir(|_| {
+            let runner = FakeRunner {
+                stdout: vec!["htop 3.3.0-1\n", "htop 3.3.0-1\ntree 2.1.1-2\n"],
+                ..FakeRunner::default()
+            };
+            let calls = runner.calls.clone();
+
+            provider(PackageManager::Dnf, runner)
+                .install_items(&[SystemPackage::new("htop"), SystemPackage::new("tree")])
+                .unwrap();
+
+            let commands = commands_run(&calls);
+            assert!(commands[0].starts_with(RPM_QUERY));
+            assert_eq!(commands[1], "dnf install -y htop tree");
+            assert!(commands[2].starts_with(RPM_QUERY));
+            assert_eq!(recorded(), ["tree"]);
+        });
+    }
+
+    #[test]
+    fn owns_what_got_installed_even_when_the_install_failed() {
+        with_state_dir(|_| {
+            let runner = FakeRunner {
+                stdout: vec!["", "tree 2.1.1-2\n"],
+                exit_code: 1,
+                ..FakeRunner::default()
+            };
+
+            let err = provider(PackageManager::Dnf, runner)
+                .install_items(&[SystemPackage::new("htop"), SystemPackage::new("tree")])
+                .unwrap_err();
+
+            assert!(matches!(err, Error::CommandFailed { .. }), "{err}");
+            assert_eq!(recorded(), ["tree"]);
+        });
+    }
+
+    #[test]
+    fn refuses_to_remove_packages_it_did_not_install() {
+        with_state_dir(|_| {
+            state::record("system", "tree", StateEntry::now()).unwrap();
+            let runner = FakeRunner::default();
+            let calls = runner.calls.clone();
+
+            let err = provider(PackageManager::Dnf, runner)
+                .remove_items(&[SystemPackage::new("tree"), SystemPackage::new("htop")])
+                .unwrap_err();
+
+            assert!(err.to_string().contains("not installed by bow"), "{err}");
+            assert!(calls.borrow().is_empty());
+            assert_eq!(recorded(), ["tree"]);
+        });
+    }
+
+    #[test]
+    fn forgets_the_packages_it_removed() {
+        with_state_dir(|_| {
+            state::record("system", "htop", StateEntry::now()).unwrap();
+            state::record("system", "tree", StateEntry::now()).unwrap();
+            let runner = FakeRunner {
+                stdout: vec!["htop 3.3.0-1\n"],
+                ..FakeRunner::default()
+            };
+            let calls = runner.calls.clone();
+
+            provider(PackageManager::Dnf, runner)
+                .remove_items(&[SystemPackage::new("tree")])
+                .unwrap();
+
+            assert_eq!(commands_run(&calls)[0], "dnf remove -y tree");
+            assert_eq!(recorded(), ["htop"]);
+        });
+    }
+
+    #[test]
+    fn refuses_to_install_locked_packages_with_pacman() {
+        with_state_dir(|_| {
+            let runner = FakeRunner::default();
+            let calls = runner.calls.clone();
+            let mut provider = provider(PackageManager::Pacman, runner);
+            provider.set_locked(BTreeMap::from([(
+                "htop".to_string(),
+                LockEntry {
+                    version: Some("3.3.0-1".to_string()),
+                    ..Default::default()
+                },
+            )]));
+
+            let err = provider
+                .install_items(&[SystemPackage::new("htop")])
+                .unwrap_err();
+
+            assert!(err.to_string().contains("can't be locked"), "{err}");
+            assert!(calls.borrow().is_empty());
+        });
+    }
+}
@@ -0,0 +1,218 @@
+use std::{ffi::OsString, path::PathBuf};
+
+/// The XDG base directories, along with their defaults relative to the home directory
+const XDG_DEFAULTS: &[(&str, &str)] = &[
+    ("XDG_CONFIG_HOME", ".config"),
+    ("XDG_DATA_HOME", ".local/share"),
+    ("XDG_STATE_HOME", ".local/state"),
+    ("XDG_CACHE_HOME", ".cache"),
+    ("XDG_BIN_HOME", ".local/bin"),
+];
+
+/// Look up an XDG base directory, falling back to its default if it's unset or empty
+pub fn xdg(var: &str) -> Option<PathBuf> {
+    xdg_in(var, &env)
+}
+
+/// [`xdg`] with the variables of `env` rather than the environment
+fn xdg_in(var: &str, env: &impl Fn(&str) -> Option<OsString>) -> Option<PathBuf> {
+    env(var)
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            let (_, default) = XDG_DEFAULTS.iter().find(|(name, _)| *name == var)?;
+            Some(std::env::home_dir()?.join(default))
+        })
+}
+
+/// Expand `~`, `$VAR`, `${VAR}` and `${VAR:-default}` in a path from the config.
+/// XDG base directories expand to their defaults when unset or empty, any other unset variable is an error.
+/// Like in the shell, a variable set to the empty string expands to it, unless it has a `:-default`
+pub fn expand(path: &str) -> Result<PathBuf, String> {
+    expand_in(path, &env)
+}
+
+/// [`expand`] with the variables of `env` rather than the environment
+fn expand_in(path: &str, env: &impl Fn(&str) -> Option<OsString>) -> Result<PathBuf, String> {
+    let mut expanded = String::with_capacity(path.len());
+
+    let rest = if path == "~" || path.starts_with("~/") {
+        expanded.push_str(&home()?);
+        &path[1..]
+    } else {
+        path
+    };
+
+    let mut chars = rest.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c != '$' {
+            expanded.push(c);
+            continue;
+        }
+
+        match chars.peek() {
+            Some((_, '{')) => {
+                let end = rest[i..]
+                    .find('}')
+                    .map(|end| i + end)
+                    .ok_or_else(|| format!("unterminated `${{` in `{path}`"))?;
+                let inner = &rest[i + 2..end];
+
+                let value = match inner.split_once(":-") {
+                    Some((var, default)) => lookup(var, env)?
+                        .filter(|value| !value.is_empty())
+                        .unwrap_or_else(|| default.to_string()),
+                    None => lookup(inner, env)?.ok_or_else(|| unset(inner, path))?,
+                };
+                expanded.push_str(&value);
+
+                while chars.next_if(|&(j, _)| j <= end).is_some() {}
+            }
+            Some(&(start, c)) if c.is_ascii_alphabetic() || c == '_' => {
+                let mut end = start;
+                while let Some(&(j, c)) = chars.peek() {
+                    if !(c.is_ascii_alphanumeric() || c == '_') {
+                        break;
+                    }
+                    end = j + c.len_utf8();
+                    chars.next();
+                }
+
+                let var = &rest[start..end];
+                expanded.push_str(&lookup(var, env)?.ok_or_else(|| unset(var, path))?);
+            }
+            // A lone `$` isn't a variable
+            _ => expanded.push(c),
+        }
+    }
+
+    Ok(PathBuf::from(expanded))
+}
+
+/// The value of `var`, with XDG base directories falling back to their defaults
+fn lookup(var: &str, env: &impl Fn(&str) -> Option<OsString>) -> Result<Option<String>, String> {
+    if var.is_empty() || !var.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!("invalid environment variable name `{var}`"));
+    }
+
+    if var == "HOME" {
+        return home().map(Some);
+    }
+
+    let value = if XDG_DEFAULTS.iter().any(|(name, _)| *name == var) {
+        xdg_in(var, env)
+    } else {
+        env(var).map(PathBuf::from)
+    };
+
+    value
+        .map(|value: PathBuf| {
+            value
+                .into_os_string()
+                .into_string()
+                .map_err(|_| format!("${var} is not valid UTF-8"))
+        })
+        .transpose()
+}
+
+fn env(var: &str) -> Option<OsString> {
+    std::env::var_os(var)
+}
+
+fn home() -> Result<String, String> {
+    std::env::home_dir()
+        .ok_or_else(|| String::from("failed to determine the home directory"))?
+        .into_os_string()
+        .into_string()
+        .map_err(|_| String::from("the home directory is not valid UTF-8"))
+}
+
+fn unset(var: &str, path: &str) -> String {
+    format!("environment variable `{var}` used in `{path}` is not set")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expands_to(path: &str) -> String {
+        expand(path)
+            .unwrap()
+            .into_os_string()
+            .into_string()
+            .unwrap()
+    }
+
+    #[test]
+    fn expands_a_leading_tilde() {
+        let home = home().unwrap();
+        assert_eq!(expands_to("~"), home);
+        assert_eq!(expands_to("~/bin"), format!("{home}/bin"));
+        // Only a leading `~` is the home directory
+        assert_eq!(expands_to("~user/bin"), "~user/bin");
+        assert_eq!(expands_to("/opt/~/bin"), "/opt/~/bin");
+    }
+
+    #[test]
+    fn expands_variables() {
+        let home = home().unwrap();
+        let path = std::env::var("PATH").unwrap();
+        assert_eq!(expands_to("$HOME/bin"), format!("{home}/bin"));
+        assert_eq!(expands_to("${HOME}bin"), format!("{home}bin"));
+        assert_eq!(expands_to("$PATH:$HOME"), format!("{path}:{home}"));
+        assert_eq!(expands_to("/plain/path"), "/plain/path");
+    }
+
+    #[test]
+    fn falls_back_to_defaults() {
+        let home = home().unwrap();
+        assert_eq!(
+            expands_to("${BOW_TEST_NEVER_SET:-/opt/bow}/bin"),
+            "/opt/bow/bin"
+        );
+        assert_eq!(expands_to("${HOME:-/opt/bow}"), home);
+        assert_eq!(expands_to("${BOW_TEST_NEVER_SET:-}x"), "x");
+    }
+
+    #[test]
+    fn xdg_directories_are_never_unset() {
+        let cache = xdg("XDG_CACHE_HOME").unwrap();
+        assert_eq!(expand("$XDG_CACHE_HOME/bow").unwrap(), cache.join("bow"));
+        assert_eq!(xdg("NOT_AN_XDG_DIR"), None);
+    }
+
+    #[test]
+    fn leaves_a_lone_dollar_alone() {
+        assert_eq!(expands_to("/a/$/b$"), "/a/$/b$");
+        assert_eq!(expands_to("$1"), "$1");
+    }
+
+    #[test]
+    fn rejects_unset_and_malformed_variables() {
+        let err = expand("$BOW_TEST_NEVER_SET/bin").unwrap_err();
+        assert!(err.contains("`BOW_TEST_NEVER_SET`"), "{err}");
+        assert!(err.contains("is not set"), "{err}");
+        assert!(expand("${BOW_TEST_NEVER_SET}").is_err());
+        assert!(expand("${HOME").unwrap_err().contains("unterminated"));
+        assert!(expand("${}").unwrap_err().contains("invalid"));
+        assert!(expand("${NOT-VALID}").unwrap_err().contains("invalid"));
+    }
+
+    #[test]
+    fn only_defaults_and_xdg_directories_treat_empty_as_unset() {
+        let env = |var: &str| match var {
+            "EMPTY" | "XDG_CACHE_HOME" => Some(OsString::new()),
+            _ => None,
+        };
+        let expands_to = |path: &str| expand_in(path, &env).unwrap();
+
+        assert_eq!(expands_to("/a/$EMPTY/b"), PathBuf::from("/a//b"));
+        assert_eq!(expands_to("/a${EMPTY}b"), PathBuf::from("/ab"));
+        assert_eq!(expands_to("${EMPTY:-/opt}/b"), PathBuf::from("/opt/b"));
+        assert_eq!(
+            expands_to("$XDG_CACHE_HOME/bow"),
+            xdg_in("XDG_CACHE_HOME", &|_| None).unwrap().join("bow")
+        );
+        assert!(expand_in("$UNSET", &env).is_err());
+    }
+}
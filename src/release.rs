@@ -0,0 +1,505 @@
+use serde::{Deserialize, Serialize, ser::SerializeMap};
+
+use crate::{error::Error, template};
+
+/// File names of checksum assets that cover every other asset of a release
+const CHECKSUM_FILE_NAMES: &[&str] = &[
+    "sha256sums",
+    "sha256sums.txt",
+    "sha512sums",
+    "checksums.txt",
+];
+
+/// A forge that publishes releases with downloadable assets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Forge {
+    Github,
+    Gitlab,
+    Gitea,
+}
+
+impl Forge {
+    /// The config key that selects this forge, e.g. `github: owner/repo`
+    pub fn key(self) -> &'static str {
+        match self {
+            Self::Github => "github",
+            Self::Gitlab => "gitlab",
+            Self::Gitea => "gitea",
+        }
+    }
+
+    fn default_api(self) -> &'static str {
+        match self {
+            Self::Github => "https://api.github.com",
+            Self::Gitlab => "https://gitlab.com/api/v4",
+            Self::Gitea => "https://gitea.com/api/v1",
+        }
+    }
+
+    /// Environment variable holding an API token, to get around rate limits and access private repos
+    fn token_var(self) -> &'static str {
+        match self {
+            Self::Github => "GITHUB_TOKEN",
+            Self::Gitlab => "GITLAB_TOKEN",
+            Self::Gitea => "GITEA_TOKEN",
+        }
+    }
+}
+
+/// Where to find the releases of a binary, and which of their assets to download
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Release {
+    pub forge: Forge,
+    /// `owner/repo`, or the full project path on GitLab
+    pub repo: String,
+    /// Pattern the asset's file name must match, a glob or a regex prefixed with `regex:`
+    pub asset: String,
+    /// Pattern for the asset holding the checksum, found automatically if not set
+    pub checksum_asset: Option<String>,
+    /// Base URL of the forge's API, for self-hosted instances
+    pub api: Option<String>,
+}
+
+/// A release picked for a version constraint
+#[derive(Debug)]
+pub struct Resolved {
+    pub tag: String,
+    pub url: String,
+    pub checksum_url: Option<String>,
+}
+
+#[derive(Debug)]
+struct ReleaseInfo {
+    tag: String,
+    prerelease: bool,
+    /// `(file name, download URL)`
+    assets: Vec<(String, String)>,
+}
+
+impl Release {
+    /// Pick the release matching `version` (`latest`, a tag or a semver constraint) and the assets to download from it
+    pub fn resolve(&self, name: &str, version: &str) -> crate::Result<Resolved> {
+        let releases = self.fetch()?;
+        let release = self.pick(&releases, version)?;
+
+        let cx = template::Context {
+            name,
+            version: Some(&release.tag),
+        };
+        let asset_pattern = Pattern::new(&self.asset, cx)?;
+        let (asset_name, url) = release
+            .assets
+            .iter()
+            .find(|(name, _)| asset_pattern.matches(name))
+            .ok_or_else(|| Error::NoMatchingAsset {
+                tag: release.tag.clone(),
+                pattern: self.asset.clone(),
+            })?;
+
+        let checksum_url = match &self.checksum_asset {
+            Some(pattern) => {
+                let checksum_pattern = Pattern::new(pattern, cx)?;
+                let (_, url) = release
+                    .assets
+                    .iter()
+                    .find(|(name, _)| checksum_pattern.matches(name))
+                    .ok_or_else(|| Error::NoMatchingAsset {
+                        tag: release.tag.clone(),
+                        pattern: pattern.clone(),
+                    })?;
+                Some(url.clone())
+            }
+            None => release
+                .assets
+                .iter()
+                .find(|(name, _)| is_checksum_of(name, asset_name))
+                .map(|(_, url)| url.clone()),
+        };
+
+        Ok(Resolved {
+            tag: release.tag.clone(),
+            url: url.clone(),
+            checksum_url,
+        })
+    }
+
+    fn fetch(&self) -> crate::Result<Vec<ReleaseInfo>> {
+        let api = self
+            .api
+            .as_deref()
+            .unwrap_or(self.forge.default_api())
+            .trim_end_matches('/');
+
+        let url = match self.forge {
+            Forge::Github => format!("{api}/repos/{}/releases?per_page=100", self.repo),
+            Forge::Gitea => format!("{api}/repos/{}/releases?limit=50", self.repo),
+            Forge::Gitlab => format!("{api}/projects/{}/releases", self.repo.replace('/', "%2F")),
+        };
+
+        let client = reqwest::blocking::Client::builder()
+            .user_agent(concat!("bow/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+        let mut request = client.get(&url);
+        if let Ok(token) = std::env::var(self.forge.token_var()) {
+            request = match self.forge {
+                Forge::Gitlab => request.header("PRIVATE-TOKEN", token),
+                Forge::Github | Forge::Gitea => request.bearer_auth(token),
+            };
+        }
+
+        let body = request.send()?.error_for_status()?.bytes()?;
+        let invalid = |e: serde_json::Error| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "failed to parse the releases of {} from {url}: {e}",
+                    self.repo
+                ),
+            )
+        };
+
+        match self.forge {
+            Forge::Github | Forge::Gitea => {
+                #[derive(Deserialize)]
+                struct Asset {
+                    name: String,
+                    browser_download_url: String,
+                }
+
+                #[derive(Deserialize)]
+                struct Release {
+                    tag_name: String,
+                    #[serde(default)]
+                    draft: bool,
+                    #[serde(default)]
+                    prerelease: bool,
+                    #[serde(default)]
+                    assets: Vec<Asset>,
+                }
+
+                let releases: Vec<Release> = serde_json::from_slice(&body).map_err(invalid)?;
+                Ok(releases
+                    .into_iter()
+                    .filter(|r| !r.draft)
+                    .map(|r| ReleaseInfo {
+                        tag: r.tag_name,
+                        prerelease: r.prerelease,
+                        assets: r
+                            .assets
+                            .into_iter()
+                            .map(|a| (a.name, a.browser_download_url))
+                            .collect(),
+                    })
+                    .collect())
+            }
+            Forge::Gitlab => {
+                #[derive(Deserialize)]
+                struct Link {
+                    name: String,
+                    url: String,
+                    direct_asset_url: Option<String>,
+                }
+
+                #[derive(Deserialize, Default)]
+                struct Assets {
+                    #[serde(default)]
+                    links: Vec<Link>,
+                }
+
+                #[derive(Deserialize)]
+                struct Release {
+                    tag_name: String,
+                    #[serde(default)]
+                    upcoming_release: bool,
+                    #[serde(default)]
+                    assets: Assets,
+                }
+
+                let releases: Vec<Release> = serde_json::from_slice(&body).map_err(invalid)?;
+                Ok(releases
+                    .into_iter()
+                    .map(|r| ReleaseInfo {
+                        tag: r.tag_name,
+                        prerelease: r.upcoming_release,
+                        assets: r
+                            .assets
+                            .links
+                            .into_iter()
+                            .map(|l| (l.name, l.direct_asset_url.unwrap_or(l.url)))
+                            .collect(),
+                    })
+                    .collect())
+            }
+        }
+    }
+
+    fn pick<'a>(
+        &self,
+        releases: &'a [ReleaseInfo],
+        version: &str,
+    ) -> crate::Result<&'a ReleaseInfo> {
+        let not_found = || Error::NoMatchingRelease {
+            repo: self.repo.clone(),
+            version: version.to_string(),
+        };
+
+        // An exact tag always wins, even if it's a prerelease
+        if let Some(release) = releases
+            .iter()
+            .find(|r| r.tag == version || r.tag.trim_start_matches('v') == version)
+        {
+            return Ok(release);
+        }
+
+        let stable = releases.iter().filter(|r| !r.prerelease);
+
+        if version == "latest" {
+            // Forges list the newest release first, which is used if no tag looks like semver
+            return stable
+                .clone()
+                .filter_map(|r| Some((parse_tag(&r.tag)?, r)))
+                .max_by(|(a, _), (b, _)| a.cmp(b))
+                .map(|(_, r)| r)
+                .or_else(|| stable.clone().next())
+                .ok_or_else(not_found);
+        }
+
+        let req = semver::VersionReq::parse(version).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid version constraint {version}: {e}"),
+            )
+        })?;
+
+        stable
+            .filter_map(|r| Some((parse_tag(&r.tag)?, r)))
+            .filter(|(v, _)| req.matches(v))
+            .max_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(_, r)| r)
+            .ok_or_else(not_found)
+    }
+}
+
+impl Serialize for Release {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        // Serialized the same way it's written in the config, so it can be flattened into a binary
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry(self.forge.key(), &self.repo)?;
+        map.serialize_entry("asset", &self.asset)?;
+        if let Some(checksum_asset) = &self.checksum_asset {
+            map.serialize_entry("checksum_asset", checksum_asset)?;
+        }
+        if let Some(api) = &self.api {
+            map.serialize_entry("api", api)?;
+        }
+        map.end()
+    }
+}
+
+/// Whether the release tagged `tag` is one that `version` (`latest`, a tag or a semver constraint) allows,
+/// any release counts as the latest when the releases can't be looked up
+pub fn satisfies(version: &str, tag: &str) -> bool {
+    if version == "latest" || tag == version || tag.trim_start_matches('v') == version {
+        return true;
+    }
+
+    semver::VersionReq::parse(version)
+        .ok()
+        .zip(parse_tag(tag))
+        .is_some_and(|(req, v)| req.matches(&v))
+}
+
+/// Parse a tag like `v1.2.3` or `tool-1.2.3` as a semver version
+fn parse_tag(tag: &str) -> Option<semver::Version> {
+    let start = tag.find(|c: char| c.is_ascii_digit())?;
+    semver::Version::parse(&tag[start..]).ok()
+}
+
+/// Whether the asset `name` looks like it holds the checksum of `asset`
+fn is_checksum_of(name: &str, asset: &str) -> bool {
+    let lower = name.to_lowercase();
+    [".sha256", ".sha256sum", ".sha512", ".sha512sum"]
+        .iter()
+        .any(|ext| name.strip_suffix(ext) == Some(asset))
+        || CHECKSUM_FILE_NAMES.contains(&lower.as_str())
+        || lower.ends_with("_checksums.txt")
+}
+
+enum Pattern {
+    Glob(glob::Pattern),
+    Regex(regex::Regex),
+}
+
+impl Pattern {
+    /// Render and compile an asset pattern
+    fn new(pattern: &str, cx: template::Context) -> crate::Result<Self> {
+        let invalid = |e: String| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid asset pattern {pattern}: {e}"),
+            )
+        };
+        let pattern = template::render(pattern, cx).map_err(invalid)?;
+
+        match pattern.strip_prefix("regex:") {
+            Some(regex) => regex::Regex::new(regex)
+                .map(Self::Regex)
+                .map_err(|e| invalid(e.to_string()).into()),
+            None => glob::Pattern::new(&pattern)
+                .map(Self::Glob)
+                .map_err(|e| invalid(e.to_string()).into()),
+        }
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Self::Glob(glob) => glob.matches(name),
+            Self::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn release() -> Release {
+        Release {
+            forge: Forge::Github,
+            repo: "owner/tool".to_string(),
+            asset: "tool-*-linux.tar.gz".to_string(),
+            checksum_asset: None,
+            api: None,
+        }
+    }
+
+    fn info(tag: &str, prerelease: bool) -> ReleaseInfo {
+        ReleaseInfo {
+            tag: tag.to_string(),
+            prerelease,
+            assets: Vec::new(),
+        }
+    }
+
+    fn releases() -> Vec<ReleaseInfo> {
+        // Listed newest first, the way forges do
+        vec![
+            info("v2.0.0-rc.1", true),
+            info("v1.10.0", false),
+            info("v1.9.2", false),
+            info("v1.2.0", false),
+            info("v0.9.0", false),
+        ]
+    }
+
+    fn pick(version: &str) -> crate::Result<String> {
+        let releases = releases();
+        release()
+            .pick(&releases, version)
+            .map(|release| release.tag.clone())
+    }
+
+    #[test]
+    fn picks_the_highest_stable_release_as_latest() {
+        assert_eq!(pick("latest").unwrap(), "v1.10.0");
+    }
+
+    #[test]
+    fn picks_the_highest_release_matching_a_constraint() {
+        assert_eq!(pick("^1.2").unwrap(), "v1.10.0");
+        assert_eq!(pick("~1.9").unwrap(), "v1.9.2");
+        assert_eq!(pick("<1.9").unwrap(), "v1.2.0");
+        assert_eq!(pick("=0.9.0").unwrap(), "v0.9.0");
+    }
+
+    #[test]
+    fn constraints_skip_prereleases_but_exact_tags_do_not() {
+        assert!(matches!(
+            pick(">=2.0.0-rc.1"),
+            Err(Error::NoMatchingRelease { .. })
+        ));
+        assert_eq!(pick("v2.0.0-rc.1").unwrap(), "v2.0.0-rc.1");
+        assert_eq!(pick("2.0.0-rc.1").unwrap(), "v2.0.0-rc.1");
+    }
+
+    #[test]
+    fn exact_tags_are_matched_with_or_without_a_v() {
+        assert_eq!(pick("v1.9.2").unwrap(), "v1.9.2");
+        assert_eq!(pick("1.2.0").unwrap(), "v1.2.0");
+    }
+
+    #[test]
+    fn fails_without_a_matching_release() {
+        assert!(matches!(
+            pick("^3"),
+            Err(Error::NoMatchingRelease { version, .. }) if version == "^3"
+        ));
+        assert!(pick("not a constraint").is_err());
+    }
+
+    #[test]
+    fn latest_falls_back_to_the_newest_release_without_semver_tags() {
+        let releases = vec![
+            info("nightly", true),
+            info("2024-05-01", false),
+            info("2024-04-01", false),
+        ];
+        let picked = release().pick(&releases, "latest").unwrap();
+        assert_eq!(picked.tag, "2024-05-01");
+    }
+
+    #[test]
+    fn checks_whether_a_tag_satisfies_a_version() {
+        assert!(satisfies("latest", "v1.0.0"));
+        assert!(satisfies("latest", "nightly"));
+        assert!(satisfies("v1.2.3", "v1.2.3"));
+        assert!(satisfies("1.2.3", "v1.2.3"));
+        assert!(satisfies("^1.2", "v1.9.0"));
+        assert!(satisfies("^14", "14.1.1"));
+        assert!(!satisfies("^15", "14.1.1"));
+        assert!(!satisfies("v1.2.4", "v1.2.3"));
+        assert!(!satisfies("^1", "nightly"));
+    }
+
+    #[test]
+    fn parses_semver_out_of_tags() {
+        let v = |s| semver::Version::parse(s).unwrap();
+        assert_eq!(parse_tag("v1.2.3"), Some(v("1.2.3")));
+        assert_eq!(parse_tag("tool-1.2.3"), Some(v("1.2.3")));
+        assert_eq!(parse_tag("1.2.3-rc.1"), Some(v("1.2.3-rc.1")));
+        assert_eq!(parse_tag("v1.2"), None);
+        assert_eq!(parse_tag("nightly"), None);
+    }
+
+    #[test]
+    fn recognises_checksum_assets() {
+        let asset = "tool-1.0.0-linux.tar.gz";
+        assert!(is_checksum_of("tool-1.0.0-linux.tar.gz.sha256", asset));
+        assert!(is_checksum_of("tool-1.0.0-linux.tar.gz.sha512sum", asset));
+        assert!(is_checksum_of("SHA256SUMS", asset));
+        assert!(is_checksum_of("tool_1.0.0_checksums.txt", asset));
+        assert!(!is_checksum_of("tool-1.0.0-darwin.tar.gz.sha256", asset));
+        assert!(!is_checksum_of("tool-1.0.0-linux.tar.gz.sig", asset));
+    }
+
+    #[test]
+    fn asset_patterns_are_rendered_globs_or_regexes() {
+        let cx = template::Context {
+            name: "tool",
+            version: Some("v1.0.0"),
+        };
+
+        let glob = Pattern::new("{{ name }}-*-linux.tar.gz", cx).unwrap();
+        assert!(glob.matches("tool-v1.0.0-linux.tar.gz"));
+        assert!(!glob.matches("tool-v1.0.0-darwin.tar.gz"));
+
+        let regex = Pattern::new(r"regex:^tool-{{ version }}-(amd64|x86_64)\.zip$", cx).unwrap();
+        assert!(regex.matches("tool-v1.0.0-x86_64.zip"));
+        assert!(!regex.matches("tool-v1.0.1-x86_64.zip"));
+
+        assert!(Pattern::new("regex:(", cx).is_err());
+    }
+}
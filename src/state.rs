@@ -0,0 +1,487 @@
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_yaml_bw::{Mapping, Value};
+
+use crate::error::Error;
+
+static STATE_DIR: OnceLock<PathBuf> = OnceLock::new();
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+#[cfg(test)]
+thread_local! {
+    /// The state directory of the running test, see [`with_state_dir`]
+    static TEST_STATE_DIR: std::cell::Cell<Option<&'static Path>> = const { std::cell::Cell::new(None) };
+}
+
+pub const STATE_FILE_NAME: &str = "state.yaml";
+/// Bump this and add a step to [`State::migrate`] whenever the layout of the state file changes
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Where the binary provider used to keep its info file, before bow had a state directory
+const LEGACY_BINARIES_INFO_FILE: &str = ".local/share/bow-binaries.yaml";
+/// The binary provider's own info file, from before every provider shared the state file
+const LEGACY_BINARIES_STATE_FILE: &str = "binaries.yaml";
+
+/// `$XDG_STATE_HOME/bow`, falling back to `~/.local/state/bow`
+pub fn default_state_dir() -> Option<PathBuf> {
+    crate::paths::xdg("XDG_STATE_HOME").map(|dir| dir.join("bow"))
+}
+
+/// Resolve and create the state directory, migrating any state left in legacy locations.
+/// Must be called before anything calls [`state_dir`]
+pub fn init(
+    state_dir: Option<PathBuf>,
+    config_path: Option<&Path>,
+) -> crate::Result<&'static Path> {
+    let state_dir = state_dir.or_else(default_state_dir).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "failed to determine the state directory, please set --state-dir",
+        )
+    })?;
+
+    std::fs::create_dir_all(&state_dir)?;
+    migrate_legacy(&state_dir, std::env::home_dir().as_deref())?;
+
+    if let Some(config_path) = config_path {
+        let config_path = std::path::absolute(config_path)?;
+        CONFIG_PATH.get_or_init(|| config_path);
+    }
+
+    Ok(STATE_DIR.get_or_init(|| state_dir))
+}
+
+pub fn state_dir() -> &'static Path {
+    #[cfg(test)]
+    if let Some(dir) = TEST_STATE_DIR.get() {
+        return dir;
+    }
+
+    STATE_DIR
+        .get()
+        .expect("state::init should be called before the state directory is used")
+}
+
+/// The config file passed to [`init`]
+pub fn config_path() -> Option<&'static Path> {
+    CONFIG_PATH.get().map(PathBuf::as_path)
+}
+
+/// Run `f` with an empty state directory of its own, so tests that go through the state don't see each other's
+#[cfg(test)]
+pub fn with_state_dir<T>(f: impl FnOnce(&Path) -> T) -> T {
+    let dir = tempfile::tempdir().unwrap();
+    let path: &'static Path = Box::leak(dir.path().into());
+
+    TEST_STATE_DIR.set(Some(path));
+    let result = f(path);
+    TEST_STATE_DIR.set(None);
+
+    result
+}
+
+/// Where a provider keeps copies of the previously installed version of `key`
+pub fn backup_dir(provider: &str, key: &str) -> PathBuf {
+    state_dir().join("backups").join(provider).join(key)
+}
+
+/// Move the binary provider's info file out of `home` into the state directory,
+/// where [`State::load`] turns it into the state file
+fn migrate_legacy(state_dir: &Path, home: Option<&Path>) -> crate::Result<()> {
+    let Some(legacy) = home.map(|home| home.join(LEGACY_BINARIES_INFO_FILE)) else {
+        return Ok(());
+    };
+
+    let new = state_dir.join(LEGACY_BINARIES_STATE_FILE);
+    if !legacy.exists() || new.exists() || state_dir.join(STATE_FILE_NAME).exists() {
+        return Ok(());
+    }
+
+    // A plain rename fails across filesystems, so fall back to copying
+    if std::fs::rename(&legacy, &new).is_err() {
+        std::fs::copy(&legacy, &new)?;
+        std::fs::remove_file(&legacy)?;
+    }
+
+    println!("Migrated {} to {}", legacy.display(), new.display());
+
+    Ok(())
+}
+
+/// Something bow installed
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct StateEntry {
+    /// Seconds since the unix epoch
+    pub installed_at: u64,
+    /// The config file that was applied when this was installed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config: Option<PathBuf>,
+    /// `<algorithm>:<hex>` hash of what was installed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// Every file written for this entry
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<PathBuf>,
+    /// Anything else the provider needs to remember, e.g. the full declaration of a binary
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    /// What was installed before this, if a backup of it was kept in [`backup_dir`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous: Option<Box<StateEntry>>,
+}
+
+impl StateEntry {
+    /// A fresh entry for something installed right now from the current config
+    pub fn now() -> Self {
+        Self {
+            installed_at: crate::lockfile::now(),
+            config: CONFIG_PATH.get().cloned(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Everything bow has installed, shared by every provider
+#[derive(Debug, Deserialize, Serialize)]
+pub struct State {
+    version: u32,
+    /// provider -> package key -> entry
+    #[serde(default)]
+    providers: BTreeMap<String, BTreeMap<String, StateEntry>>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            version: SCHEMA_VERSION,
+            providers: BTreeMap::new(),
+        }
+    }
+}
+
+impl State {
+    pub fn path() -> PathBuf {
+        state_dir().join(STATE_FILE_NAME)
+    }
+
+    pub fn load() -> crate::Result<Self> {
+        let path = Self::path();
+
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Self::migrate_binaries_file();
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if contents.trim().is_empty() {
+            return Ok(Self::default());
+        }
+
+        let corrupted = |message: String| Error::StateCorrupted {
+            path: path.clone(),
+            message,
+        };
+
+        let doc: Value =
+            serde_yaml_bw::from_str(&contents).map_err(|e| corrupted(e.to_string()))?;
+        let version = doc
+            .get("version")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| corrupted(String::from("missing schema version")))?
+            as u32;
+
+        if version > SCHEMA_VERSION {
+            return Err(corrupted(format!(
+                "schema version {version} is newer than this version of bow supports ({SCHEMA_VERSION})"
+            )));
+        }
+
+        let doc = Self::migrate(doc, version).map_err(corrupted)?;
+        serde_yaml_bw::from_value(doc).map_err(|e| corrupted(e.to_string()))
+    }
+
+    /// Bring a state document from `version` up to [`SCHEMA_VERSION`], one step at a time
+    fn migrate(doc: Value, version: u32) -> Result<Value, String> {
+        match version {
+            SCHEMA_VERSION => Ok(doc),
+            v => Err(format!("don't know how to migrate from schema version {v}")),
+        }
+    }
+
+    /// Build the initial state from the binary provider's old info file, if there is one
+    fn migrate_binaries_file() -> crate::Result<Self> {
+        let legacy = state_dir().join(LEGACY_BINARIES_STATE_FILE);
+        let contents = match std::fs::read_to_string(&legacy) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let records: Vec<Mapping> = if contents.trim().is_empty() {
+            Vec::new()
+        } else {
+            serde_yaml_bw::from_str(&contents).map_err(|e| Error::StateCorrupted {
+                path: legacy.clone(),
+                message: e.to_string(),
+            })?
+        };
+
+        let mut state = Self::default();
+        for mut record in records {
+            let Some(name) = record.get("name").and_then(Value::as_str).map(String::from) else {
+                continue;
+            };
+
+            let files = record
+                .remove("files")
+                .and_then(|v| serde_yaml_bw::from_value(v).ok())
+                .unwrap_or_default();
+            let hash = record
+                .remove("hash")
+                .and_then(|v| v.as_str().map(String::from));
+            let installed_at = record
+                .remove("installed_at")
+                .and_then(|v| v.as_u64())
+                .unwrap_or_default();
+
+            let entry = StateEntry {
+                installed_at,
+                config: None,
+                hash,
+                files,
+                data: Some(Value::Mapping(record)),
+                previous: None,
+            };
+            state
+                .providers
+                .entry(String::from("binary"))
+                .or_default()
+                .insert(name, entry);
+        }
+
+        state.save()?;
+        std::fs::remove_file(&legacy)?;
+        println!(
+            "Migrated {} to {}",
+            legacy.display(),
+            Self::path().display()
+        );
+
+        Ok(state)
+    }
+
+    /// Write the state file so that it's either fully replaced or left untouched, even if bow is killed halfway
+    pub fn save(&self) -> crate::Result<()> {
+        let path = Self::path();
+        let contents = serde_yaml_bw::to_string(self).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("failed to write {}: {e}", path.display()),
+            )
+        })?;
+
+        write_atomically(&path, &contents)?;
+
+        Ok(())
+    }
+
+    pub fn entries(&self, provider: &str) -> BTreeMap<String, StateEntry> {
+        self.providers.get(provider).cloned().unwrap_or_default()
+    }
+
+    pub fn get(&self, provider: &str, key: &str) -> Option<&StateEntry> {
+        self.providers.get(provider)?.get(key)
+    }
+
+    pub fn insert(&mut self, provider: &str, key: &str, entry: StateEntry) {
+        self.providers
+            .entry(provider.to_string())
+            .or_default()
+            .insert(key.to_string(), entry);
+    }
+
+    pub fn remove(&mut self, provider: &str, key: &str) -> Option<StateEntry> {
+        let section = self.providers.get_mut(provider)?;
+        let entry = section.remove(key);
+        if section.is_empty() {
+            self.providers.remove(provider);
+        }
+        entry
+    }
+}
+
+/// Replace `path` with `contents` by syncing them to a temporary file next to it and renaming that over it
+pub fn write_atomically(path: &Path, contents: &str) -> std::io::Result<()> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = path.with_file_name(format!("{file_name}.tmp"));
+
+    let mut tmp = std::fs::File::create(&tmp_path)?;
+    tmp.write_all(contents.as_bytes())?;
+    tmp.sync_all()?;
+    drop(tmp);
+
+    std::fs::rename(&tmp_path, path)?;
+    // A relative path in the current directory has an empty parent
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Everything recorded for `provider` along with the item stored in each entry's `data`,
+/// skipping entries without any
+pub fn installed<T: DeserializeOwned>(provider: &str) -> crate::Result<Vec<(T, StateEntry)>> {
+    State::load()?
+        .entries(provider)
+        .into_values()
+        .filter_map(|entry| {
+            let data = entry.data.clone()?;
+            Some(
+                serde_yaml_bw::from_value::<T>(data)
+                    .map(|item| (item, entry))
+                    .map_err(|e| Error::StateCorrupted {
+                        path: State::path(),
+                        message: e.to_string(),
+                    }),
+            )
+        })
+        .collect()
+}
+
+/// Load the state, record `entry` and save it straight away
+pub fn record(provider: &str, key: &str, entry: StateEntry) -> crate::Result<()> {
+    let mut state = State::load()?;
+    state.insert(provider, key, entry);
+    state.save()
+}
+
+/// Load the state, drop the entry for `key` and save it straight away
+pub fn forget(provider: &str, key: &str) -> crate::Result<()> {
+    let mut state = State::load()?;
+    state.remove(provider, key);
+    state.save()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEGACY_BINARIES: &str = "\
+- name: rg
+  url: https://example.com/rg.tar.gz
+  hash: sha256:abc
+  installed_at: 1700000000
+  files:
+  - /home/user/.local/bin/rg
+";
+
+    fn write_legacy_info_file(home: &Path) -> PathBuf {
+        let legacy = home.join(LEGACY_BINARIES_INFO_FILE);
+        std::fs::create_dir_all(legacy.parent().unwrap()).unwrap();
+        std::fs::write(&legacy, LEGACY_BINARIES).unwrap();
+        legacy
+    }
+
+    fn load_err(contents: &str) -> String {
+        std::fs::write(State::path(), contents).unwrap();
+        match State::load().unwrap_err() {
+            Error::StateCorrupted { message, .. } => message,
+            e => panic!("unexpected error: {e}"),
+        }
+    }
+
+    #[test]
+    fn migrates_the_legacy_info_file_into_the_state_file() {
+        with_state_dir(|state_dir| {
+            let home = tempfile::tempdir().unwrap();
+            let legacy = write_legacy_info_file(home.path());
+
+            migrate_legacy(state_dir, Some(home.path())).unwrap();
+            assert!(!legacy.exists());
+            assert!(state_dir.join(LEGACY_BINARIES_STATE_FILE).exists());
+
+            let state = State::load().unwrap();
+            assert!(!state_dir.join(LEGACY_BINARIES_STATE_FILE).exists());
+            assert!(State::path().exists());
+
+            let entry = state.get("binary", "rg").unwrap();
+            assert_eq!(entry.installed_at, 1700000000);
+            assert_eq!(entry.hash.as_deref(), Some("sha256:abc"));
+            assert_eq!(entry.files, [PathBuf::from("/home/user/.local/bin/rg")]);
+
+            let data = entry.data.as_ref().unwrap();
+            assert_eq!(
+                data.get("url").and_then(Value::as_str),
+                Some("https://example.com/rg.tar.gz")
+            );
+            assert!(data.get("hash").is_none());
+
+            // Once migrated, loading again reads the state file
+            let state = State::load().unwrap();
+            assert!(state.get("binary", "rg").is_some());
+        });
+    }
+
+    #[test]
+    fn leaves_the_legacy_info_file_alone_once_there_is_state() {
+        with_state_dir(|state_dir| {
+            let home = tempfile::tempdir().unwrap();
+            let legacy = write_legacy_info_file(home.path());
+            State::default().save().unwrap();
+
+            migrate_legacy(state_dir, Some(home.path())).unwrap();
+            assert!(legacy.exists());
+            assert!(!state_dir.join(LEGACY_BINARIES_STATE_FILE).exists());
+        });
+    }
+
+    #[test]
+    fn checks_the_schema_version() {
+        with_state_dir(|_| {
+            let message = load_err("version: 2\nproviders: {}\n");
+            assert!(message.contains("schema version 2 is newer"), "{message}");
+
+            let message = load_err("providers: {}\n");
+            assert_eq!(message, "missing schema version");
+
+            std::fs::write(State::path(), "version: 1\n").unwrap();
+            assert!(State::load().unwrap().entries("binary").is_empty());
+        });
+    }
+
+    #[test]
+    fn saves_through_a_temporary_file() {
+        with_state_dir(|state_dir| {
+            let tmp = state_dir.join(format!("{STATE_FILE_NAME}.tmp"));
+            // What a save that was killed halfway leaves behind
+            std::fs::write(&tmp, "version: 1\nprovid").unwrap();
+
+            let mut state = State::default();
+            state.insert("flatpak", "org.gnome.Maps", StateEntry::now());
+            state.save().unwrap();
+
+            assert!(!tmp.exists());
+            assert!(
+                State::load()
+                    .unwrap()
+                    .get("flatpak", "org.gnome.Maps")
+                    .is_some()
+            );
+
+            state.remove("flatpak", "org.gnome.Maps");
+            state.save().unwrap();
+            assert!(!tmp.exists());
+            assert!(State::load().unwrap().entries("flatpak").is_empty());
+        });
+    }
+}
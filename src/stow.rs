@@ -0,0 +1,2515 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::{OsStr, OsString},
+    fmt,
+    os::unix::fs::PermissionsExt,
+    path::{Component, Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use regex::Regex;
+use serde::{Deserialize, Serialize, de};
+
+use crate::checksum::{Algorithm, Checksum};
+
+/// A stow directory holding packages, and the target directory they're linked into
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Farm {
+    pub stow_dir: PathBuf,
+    pub target: PathBuf,
+}
+
+impl Farm {
+    fn package_dir(&self, package: &str) -> PathBuf {
+        self.stow_dir.join(package)
+    }
+
+    /// Whether `path` is inside the stow directory, i.e. a link to it belongs to one of its packages
+    fn owns(&self, path: &Path) -> bool {
+        path.starts_with(&self.stow_dir) && path != self.stow_dir
+    }
+
+    /// The path of `src` inside its package, e.g. `.config/nvim/init.lua`
+    fn path_in_package(&self, src: &Path) -> PathBuf {
+        src.strip_prefix(&self.stow_dir)
+            .map(|rel| rel.components().skip(1).collect())
+            .unwrap_or_default()
+    }
+
+    /// `path` relative to the target directory, which `--defer` and `--override` match against
+    fn path_in_target(&self, path: &Path) -> PathBuf {
+        path.strip_prefix(&self.target)
+            .map(Path::to_path_buf)
+            .unwrap_or_default()
+    }
+
+    /// The package `path` belongs to, for paths inside the stow directory
+    fn package_of(&self, path: &Path) -> String {
+        path.strip_prefix(&self.stow_dir)
+            .ok()
+            .and_then(|rel| rel.components().next())
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    /// Every link in the target that currently points into `package`
+    pub fn links_of(&self, package: &str, options: &Options) -> Vec<PathBuf> {
+        let mut links = Vec::new();
+        self.collect_links(
+            &self.package_dir(package),
+            &self.target,
+            options,
+            &mut links,
+        );
+        links
+    }
+
+    fn collect_links(
+        &self,
+        src_dir: &Path,
+        target_dir: &Path,
+        options: &Options,
+        links: &mut Vec<PathBuf>,
+    ) {
+        let Ok(entries) = std::fs::read_dir(src_dir) else {
+            return;
+        };
+
+        for entry in entries.flatten() {
+            let src = entry.path();
+            let target = target_dir.join(options.target_name(&entry.file_name()));
+            match real_node(&target) {
+                Node::Link(dest) if resolve_link(&target, &dest) == src => links.push(target),
+                Node::Dir if is_real_dir(&src) => self.collect_links(&src, &target, options, links),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// How packages are stowed, mirroring Stow's command line options
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Link whole directories when only one package has anything in them
+    pub folding: bool,
+    /// Paths in a package ending in a match are never stowed, like `--ignore`
+    pub ignore: Vec<Regex>,
+    /// Patterns ignored on top of each package's ignore file, matched the same way as the lines of one
+    pub ignore_list: IgnoreList,
+    /// Paths in the target starting with a match are left to the package that already owns them, like `--defer`
+    pub defer: Vec<Regex>,
+    /// Paths in the target starting with a match are taken over from the package that owns them, like `--override`
+    pub overrides: Vec<Regex>,
+    /// What to do with files that are in the way of a link
+    pub on_conflict: Strategy,
+    /// Render files ending in `.tmpl` into real files without the suffix, instead of linking them
+    pub templates: bool,
+    /// Put files and directories named `dot-foo` into the target as `.foo`, like `--dotfiles`
+    pub dotfiles: bool,
+    /// How the files of each package are put into the target, by package name
+    pub rules: BTreeMap<String, Rules>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            folding: true,
+            ignore: Vec::new(),
+            ignore_list: IgnoreList::default(),
+            defer: Vec::new(),
+            overrides: Vec::new(),
+            on_conflict: Strategy::Fail,
+            templates: false,
+            dotfiles: false,
+            rules: BTreeMap::new(),
+        }
+    }
+}
+
+impl Options {
+    /// Compile `--ignore` patterns, which match the end of a path
+    pub fn suffix_patterns(patterns: &[String]) -> Result<Vec<Regex>, regex::Error> {
+        patterns
+            .iter()
+            .map(|p| Regex::new(&format!("(?:{p})\\z")))
+            .collect()
+    }
+
+    /// Compile `--defer` and `--override` patterns, which match the start of a path
+    pub fn prefix_patterns(patterns: &[String]) -> Result<Vec<Regex>, regex::Error> {
+        patterns
+            .iter()
+            .map(|p| Regex::new(&format!("\\A(?:{p})")))
+            .collect()
+    }
+
+    /// The name a file named `name` in a package gets in the target, which is only different for `dot-` files
+    fn target_name(&self, name: &OsStr) -> OsString {
+        let text = name.to_string_lossy();
+        match text.strip_prefix(DOT_PREFIX) {
+            Some(rest) if self.dotfiles && !rest.is_empty() => format!(".{rest}").into(),
+            _ => name.to_os_string(),
+        }
+    }
+}
+
+/// What to do with a file or a link that isn't bow's where a link should go
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Strategy {
+    /// Leave it and report a conflict, so nothing is changed
+    #[default]
+    Fail,
+    /// Move the file into the package, replacing the package's version, and link it like `stow --adopt`
+    Adopt,
+    /// Rename it to `<name>.<timestamp>.bow-bak`, which is moved back once the package is unstowed
+    Backup,
+    /// Delete it
+    Overwrite,
+}
+
+/// How a file of a package gets into the target
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    #[default]
+    Link,
+    /// Copy the file and keep the copy in sync, for programs that don't follow links
+    Copy,
+}
+
+/// Unix permission bits, written in octal like `0600`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Permissions(u32);
+
+impl Serialize for Permissions {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&format!("{:04o}", self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Permissions {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        /// `0600` is read as the number 600 by YAML unless it's quoted, either way the digits are octal
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Text(String),
+            Number(u32),
+        }
+
+        let digits = match Repr::deserialize(deserializer)? {
+            Repr::Text(text) => text,
+            Repr::Number(number) => number.to_string(),
+        };
+
+        u32::from_str_radix(digits.trim_start_matches("0o"), 8)
+            .ok()
+            .filter(|bits| *bits <= 0o7777)
+            .map(Self)
+            .ok_or_else(|| {
+                de::Error::custom(format!(
+                    "invalid permissions `{digits}`, expected e.g. 0600"
+                ))
+            })
+    }
+}
+
+/// How the files of a package are put into the target, besides linking them
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Rules {
+    /// How every file is put into the target, unless `files` says otherwise
+    #[serde(default, skip_serializing_if = "is_link")]
+    pub mode: Mode,
+    /// Settings for files and directories by their path in the package, a mode applies to everything under a directory
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub files: BTreeMap<PathBuf, FileRules>,
+}
+
+fn is_link(mode: &Mode) -> bool {
+    *mode == Mode::Link
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FileRules {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<Mode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<Permissions>,
+}
+
+impl Rules {
+    /// The mode of `path` in the package, from the closest entry in `files` that sets one
+    fn mode(&self, path: &Path) -> Mode {
+        self.files
+            .iter()
+            .filter(|(p, rules)| path.starts_with(p) && rules.mode.is_some())
+            .max_by_key(|(p, _)| p.components().count())
+            .and_then(|(_, rules)| rules.mode)
+            .unwrap_or(self.mode)
+    }
+
+    fn permissions(&self, path: &Path) -> Option<u32> {
+        self.files.get(path)?.permissions.map(|p| p.0)
+    }
+
+    /// Whether anything at or under `path` is copied or has permissions set, so it can't be folded into a link
+    fn needs_real(&self, path: &Path) -> bool {
+        self.mode(path) == Mode::Copy
+            || self.files.iter().any(|(p, rules)| {
+                p.starts_with(path)
+                    && (rules.mode == Some(Mode::Copy) || rules.permissions.is_some())
+            })
+    }
+}
+
+/// Files ending in this are rendered rather than linked when templates are enabled
+const TEMPLATE_SUFFIX: &str = ".tmpl";
+
+/// What `--dotfiles` replaces with a `.` at the start of a name in a package
+const DOT_PREFIX: &str = "dot-";
+
+/// The name of the per-package ignore file, which replaces the global and default ignore lists
+const LOCAL_IGNORE: &str = ".stow-local-ignore";
+
+/// The name of the ignore file in the home directory, used by packages without a local one
+const GLOBAL_IGNORE: &str = ".stow-global-ignore";
+
+/// Stow's built-in ignore list, for packages without a local or global ignore file
+const DEFAULT_IGNORE: &str = r"
+# Comments and blank lines are allowed.
+
+RCS
+.+,v
+
+CVS
+\.\#.+       # CVS conflict files / emacs lock files
+\.cvsignore
+
+\.svn
+_darcs
+\.hg
+
+\.git
+\.gitignore
+\.gitmodules
+
+.+~          # emacs backup files
+\#.*\#       # emacs autosave files
+
+^/README.*
+^/LICENSE.*
+^/COPYING
+";
+
+/// Regexes for paths in a package that are never stowed, in the format of Stow's ignore files.
+/// A pattern containing a `/` matches whole components of the path inside the package with a leading `/`,
+/// so `bin/setup.sh` ignores `/bin/setup.sh` and `/extra/bin/setup.sh/...` while `^/bin/setup.sh` only ignores the first.
+/// Any other pattern has to match the whole file name
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreList {
+    path: Option<Regex>,
+    basename: Option<Regex>,
+}
+
+impl IgnoreList {
+    pub fn new<'a>(patterns: impl IntoIterator<Item = &'a str>) -> Result<Self, regex::Error> {
+        let (path, basename): (Vec<_>, Vec<_>) =
+            patterns.into_iter().partition(|p| p.contains('/'));
+        // Anchored the same way as Stow's, which lets a path pattern match at any depth
+        let compile = |patterns: Vec<&str>, anchored: fn(String) -> String| {
+            (!patterns.is_empty())
+                .then(|| Regex::new(&anchored(patterns.join("|"))))
+                .transpose()
+        };
+
+        Ok(Self {
+            path: compile(path, |p| format!("(^|/)(?:{p})(/|$)"))?,
+            basename: compile(basename, |p| format!("^(?:{p})$"))?,
+        })
+    }
+
+    /// Parse an ignore file, one regex per line with `#` starting a comment unless it's escaped as `\#`
+    pub fn parse(contents: &str) -> Result<Self, regex::Error> {
+        let patterns: Vec<String> = contents.lines().filter_map(strip_comment).collect();
+        Self::new(patterns.iter().map(String::as_str))
+    }
+
+    /// The ignore list of the package in `package_dir`, read from its `.stow-local-ignore`,
+    /// or else from `~/.stow-global-ignore`, or else Stow's built-in list
+    pub fn for_package(package_dir: &Path) -> std::io::Result<Self> {
+        let files = [
+            Some(package_dir.join(LOCAL_IGNORE)),
+            std::env::home_dir().map(|home| home.join(GLOBAL_IGNORE)),
+        ];
+
+        for file in files.into_iter().flatten() {
+            match std::fs::read_to_string(&file) {
+                Ok(contents) => {
+                    return Self::parse(&contents).map_err(|e| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("{}: {e}", file.display()),
+                        )
+                    });
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(Self::parse(DEFAULT_IGNORE).expect("the default ignore list is valid"))
+    }
+
+    /// Whether `path`, relative to the root of its package, is ignored
+    pub fn matches(&self, path: &Path) -> bool {
+        let full = format!("/{}", path.to_string_lossy());
+        let basename = path
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+
+        self.path.as_ref().is_some_and(|re| re.is_match(&full))
+            || self
+                .basename
+                .as_ref()
+                .is_some_and(|re| re.is_match(&basename))
+    }
+}
+
+/// The pattern on a line of an ignore file, if there is one
+fn strip_comment(line: &str) -> Option<String> {
+    if line.starts_with('#') {
+        return None;
+    }
+
+    let mut pattern = String::with_capacity(line.len());
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'#') => {
+                pattern.push('#');
+                chars.next();
+            }
+            '#' => break,
+            c => pattern.push(c),
+        }
+    }
+
+    let pattern = pattern.trim();
+    (!pattern.is_empty()).then(|| pattern.to_string())
+}
+
+/// Whether any of `patterns` matches `path`
+fn matches(patterns: &[Regex], path: &Path) -> bool {
+    let path = path.to_string_lossy();
+    patterns.iter().any(|p| p.is_match(&path))
+}
+
+/// A single change to the target directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Task {
+    /// Create a symlink at `path` pointing to `dest`, which is relative to `path`'s parent
+    Link {
+        path: PathBuf,
+        dest: PathBuf,
+    },
+    Unlink {
+        path: PathBuf,
+    },
+    Mkdir {
+        path: PathBuf,
+    },
+    Rmdir {
+        path: PathBuf,
+    },
+    /// Move the file at `path` into a package at `src`, replacing what the package had
+    Adopt {
+        path: PathBuf,
+        src: PathBuf,
+    },
+    /// Move the file at `path` out of the way to `backup`
+    Backup {
+        path: PathBuf,
+        backup: PathBuf,
+    },
+    /// Move a file that was backed up back to `path`
+    Restore {
+        path: PathBuf,
+        backup: PathBuf,
+    },
+    /// Delete the file at `path`
+    Remove {
+        path: PathBuf,
+    },
+    /// Write `contents`, rendered from the template `src`, to `path`
+    Render {
+        path: PathBuf,
+        src: PathBuf,
+        contents: String,
+        permissions: u32,
+    },
+    /// Copy `src` to `path`
+    Copy {
+        path: PathBuf,
+        src: PathBuf,
+        permissions: u32,
+    },
+    Chmod {
+        path: PathBuf,
+        permissions: u32,
+    },
+}
+
+impl Task {
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Link { path, .. }
+            | Self::Unlink { path }
+            | Self::Mkdir { path }
+            | Self::Rmdir { path }
+            | Self::Adopt { path, .. }
+            | Self::Backup { path, .. }
+            | Self::Restore { path, .. }
+            | Self::Remove { path }
+            | Self::Render { path, .. }
+            | Self::Copy { path, .. }
+            | Self::Chmod { path, .. } => path,
+        }
+    }
+
+    pub fn execute(&self) -> std::io::Result<()> {
+        match self {
+            Self::Link { path, dest } => std::os::unix::fs::symlink(dest, path),
+            Self::Unlink { path } => std::fs::remove_file(path),
+            Self::Mkdir { path } => std::fs::create_dir(path),
+            Self::Rmdir { path } => std::fs::remove_dir(path),
+            Self::Adopt { path, src } => std::fs::rename(path, src).or_else(|_| {
+                // The target and the stow directory can be on different filesystems
+                std::fs::copy(path, src)?;
+                std::fs::remove_file(path)
+            }),
+            Self::Backup { path, backup } => std::fs::rename(path, backup),
+            Self::Restore { path, backup } => std::fs::rename(backup, path),
+            Self::Remove { path } => std::fs::remove_file(path),
+            Self::Render {
+                path,
+                contents,
+                permissions,
+                ..
+            } => {
+                std::fs::write(path, contents)?;
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(*permissions))
+            }
+            Self::Copy {
+                path,
+                src,
+                permissions,
+            } => {
+                std::fs::copy(src, path)?;
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(*permissions))
+            }
+            Self::Chmod { path, permissions } => {
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(*permissions))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Task {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The same wording as `stow -v`
+        match self {
+            Self::Link { path, dest } => {
+                write!(f, "LINK: {} => {}", path.display(), dest.display())
+            }
+            Self::Unlink { path } => write!(f, "UNLINK: {}", path.display()),
+            Self::Mkdir { path } => write!(f, "MKDIR: {}", path.display()),
+            Self::Rmdir { path } => write!(f, "RMDIR: {}", path.display()),
+            Self::Adopt { path, src } => write!(f, "MV: {} => {}", path.display(), src.display()),
+            Self::Backup { path, backup } => {
+                write!(f, "BACKUP: {} => {}", path.display(), backup.display())
+            }
+            Self::Restore { path, backup } => {
+                write!(f, "RESTORE: {} => {}", backup.display(), path.display())
+            }
+            Self::Remove { path } => write!(f, "REMOVE: {}", path.display()),
+            Self::Render { path, src, .. } => {
+                write!(f, "RENDER: {} from {}", path.display(), src.display())
+            }
+            Self::Copy { path, src, .. } => {
+                write!(f, "COPY: {} from {}", path.display(), src.display())
+            }
+            Self::Chmod { path, permissions } => {
+                write!(f, "CHMOD: {} {permissions:04o}", path.display())
+            }
+        }
+    }
+}
+
+/// Something in the target that stops a package from being stowed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    pub path: PathBuf,
+    pub message: String,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.message)
+    }
+}
+
+/// What a path in the target is, or will be once the planned tasks have run
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Missing,
+    Dir,
+    File,
+    /// A symlink and its destination, as it was written
+    Link(PathBuf),
+}
+
+/// Works out the tasks that stow and unstow packages the way GNU Stow does, folding a directory into a single link
+/// when only one package has anything in it and unfolding it again once another package needs it too.
+/// Nothing is touched until the tasks are executed, the planned changes are kept in an overlay of the target instead
+#[derive(Debug)]
+pub struct Planner {
+    options: Options,
+    overlay: BTreeMap<PathBuf, Node>,
+    /// Directories bow created, which are removed or folded back into a link once they're no longer needed
+    created: BTreeSet<PathBuf>,
+    /// The real state of every path a task touches, from before any task ran
+    original: BTreeMap<PathBuf, Node>,
+    /// The ignore list of every package seen so far, by package directory
+    ignore_lists: BTreeMap<PathBuf, IgnoreList>,
+    /// The hash of every file bow rendered or copied into the target, from when it was written
+    known: BTreeMap<PathBuf, String>,
+    /// The files rendered or copied by the planned tasks, and the hash of what they'll hold
+    written: BTreeMap<PathBuf, String>,
+    tasks: Vec<Task>,
+    conflicts: Vec<Conflict>,
+}
+
+impl Planner {
+    pub fn new(
+        options: Options,
+        created: BTreeSet<PathBuf>,
+        known: BTreeMap<PathBuf, String>,
+    ) -> Self {
+        Self {
+            options,
+            overlay: BTreeMap::new(),
+            created,
+            original: BTreeMap::new(),
+            ignore_lists: BTreeMap::new(),
+            known,
+            written: BTreeMap::new(),
+            tasks: Vec::new(),
+            conflicts: Vec::new(),
+        }
+    }
+
+    /// Link every file of `package` into the target, or copy it if its rules say so
+    pub fn stow(&mut self, farm: &Farm, package: &str) -> std::io::Result<()> {
+        let package_dir = farm.package_dir(package);
+        if !is_real_dir(&package_dir) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!(
+                    "package {package} does not exist in {}",
+                    farm.stow_dir.display()
+                ),
+            ));
+        }
+
+        self.stow_contents(farm, &package_dir, &farm.target)
+    }
+
+    /// Remove every link into `package` from the target, folding back directories that no longer need to be real
+    pub fn unstow(&mut self, farm: &Farm, package: &str) -> std::io::Result<()> {
+        let package_dir = farm.package_dir(package);
+        if is_real_dir(&package_dir) {
+            self.unstow_contents(farm, &package_dir, &farm.target)?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove the `files` recorded for `package` that unstowing can't find by walking the package:
+    /// links to files it no longer has, that are now ignored or that now go somewhere else,
+    /// like templates that are rendered instead or `dot-` files once `--dotfiles` is toggled,
+    /// and files it wrote that are still untouched. Run after unstowing, that's only copies of files it no longer has
+    pub fn unlink_stale(
+        &mut self,
+        farm: &Farm,
+        package: &str,
+        files: &[PathBuf],
+    ) -> std::io::Result<()> {
+        let package_dir = farm.package_dir(package);
+
+        for path in files {
+            let stale = match self.node(path) {
+                Node::Link(dest) => {
+                    let resolved = resolve_link(path, &dest);
+                    resolved.starts_with(&package_dir)
+                        && (std::fs::symlink_metadata(&resolved).is_err()
+                            || self.ignored(farm, &resolved)?
+                            || path
+                                .parent()
+                                .is_none_or(|dir| self.target_of(&resolved, dir) != *path))
+                }
+                Node::File => self.owns_file(path),
+                _ => false,
+            };
+
+            if stale {
+                self.unlink(path);
+                for dir in path.ancestors().skip(1) {
+                    if !dir.starts_with(&farm.target) || dir == farm.target {
+                        break;
+                    }
+                    self.cleanup(farm, dir)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Move the file backed up to `backup` back to `path`, unless something else is there by now
+    pub fn restore(&mut self, path: &Path, backup: &Path) {
+        if self.node(path) == Node::Missing && real_node(backup) != Node::Missing {
+            let task = Task::Restore {
+                path: path.to_path_buf(),
+                backup: backup.to_path_buf(),
+            };
+            let node = real_node(backup);
+            self.record(task, node);
+        }
+    }
+
+    /// The files rendered or copied once the planned tasks have run, and the hash of their contents
+    pub fn written(&self) -> &BTreeMap<PathBuf, String> {
+        &self.written
+    }
+
+    /// The tasks to run and the conflicts that stop them from running,
+    /// leaving out changes that end up where they started, like unlinking and relinking a file when restowing
+    pub fn finish(self) -> (Vec<Task>, Vec<Conflict>) {
+        let unchanged: BTreeSet<&PathBuf> = self
+            .original
+            .iter()
+            .filter(|(path, node)| self.overlay.get(*path) == Some(node) && self.untouched(path))
+            .map(|(path, _)| path)
+            .collect();
+
+        let tasks = self
+            .tasks
+            .iter()
+            // Permissions are only changed when they differ from what's on disk
+            .filter(|task| {
+                matches!(task, Task::Chmod { .. })
+                    || !unchanged.contains(&task.path().to_path_buf())
+            })
+            .cloned()
+            .collect();
+
+        (tasks, self.conflicts)
+    }
+
+    /// Whether a path that ends up as what it was also keeps its contents and permissions, which isn't the case
+    /// for a file that's swapped for its backup, or a rendered file whose template changed
+    fn untouched(&self, path: &Path) -> bool {
+        for task in self.tasks.iter().filter(|task| task.path() == path) {
+            match task {
+                Task::Restore { .. } => return false,
+                Task::Render { permissions, .. } | Task::Copy { permissions, .. }
+                    if disk_permissions(path) != Some(*permissions) =>
+                {
+                    return false;
+                }
+                _ => {}
+            }
+        }
+
+        self.written
+            .get(path)
+            .is_none_or(|hash| hash_on_disk(path).as_ref() == Some(hash))
+    }
+
+    fn stow_contents(
+        &mut self,
+        farm: &Farm,
+        src_dir: &Path,
+        target_dir: &Path,
+    ) -> std::io::Result<()> {
+        for name in sorted_entries(src_dir)? {
+            let src = src_dir.join(&name);
+            if self.ignored(farm, &src)? {
+                continue;
+            }
+
+            let target = self.target_of(&src, target_dir);
+            self.stow_node(farm, &src, &target)?;
+        }
+
+        Ok(())
+    }
+
+    fn stow_node(&mut self, farm: &Farm, src: &Path, target: &Path) -> std::io::Result<()> {
+        if self.is_written(farm, src) {
+            return self.write_file(farm, src, target);
+        }
+
+        match self.node(target) {
+            Node::Missing if is_real_dir(src) && !self.foldable(farm, src)? => {
+                self.mkdir(target);
+                self.apply_permissions(farm, src, target);
+                self.stow_contents(farm, src, target)?;
+            }
+            Node::Missing => {
+                self.link(target, src);
+                self.apply_permissions(farm, src, src);
+            }
+            Node::Link(dest) => {
+                let resolved = resolve_link(target, &dest);
+                if resolved == src {
+                    self.apply_permissions(farm, src, src);
+                    return Ok(());
+                }
+
+                if !farm.owns(&resolved) {
+                    self.in_the_way(
+                        farm,
+                        src,
+                        target,
+                        format!("existing link to {} is not owned by bow", dest.display()),
+                    )?;
+                } else if std::fs::symlink_metadata(&resolved).is_err() {
+                    // Left behind by a file that was removed from its package
+                    self.unlink(target);
+                    self.stow_node(farm, src, target)?;
+                } else if matches(&self.options.defer, &farm.path_in_target(target)) {
+                    // Left to the package that already has it
+                } else if matches(&self.options.overrides, &farm.path_in_target(target)) {
+                    self.unlink(target);
+                    self.link(target, src);
+                } else if is_real_dir(&resolved) && is_real_dir(src) {
+                    // Another package folded this directory into a link, which has to become a real directory
+                    // holding the links of both packages
+                    self.unlink(target);
+                    self.mkdir(target);
+                    self.apply_permissions(farm, src, target);
+                    self.stow_contents(farm, &resolved, target)?;
+                    self.stow_contents(farm, src, target)?;
+                } else {
+                    self.conflict(
+                        target,
+                        format!(
+                            "existing link is owned by package {}",
+                            farm.package_of(&resolved)
+                        ),
+                    );
+                }
+            }
+            Node::Dir if is_real_dir(src) => {
+                self.apply_permissions(farm, src, target);
+                self.stow_contents(farm, src, target)?;
+            }
+            Node::Dir => self.conflict(target, String::from("existing directory is in the way")),
+            Node::File => self.in_the_way(
+                farm,
+                src,
+                target,
+                String::from("existing file is neither a link nor a directory"),
+            )?,
+        }
+
+        Ok(())
+    }
+
+    /// Deal with a file or a foreign link at `target` according to the conflict strategy
+    fn in_the_way(
+        &mut self,
+        farm: &Farm,
+        src: &Path,
+        target: &Path,
+        message: String,
+    ) -> std::io::Result<()> {
+        let path = target.to_path_buf();
+        match self.options.on_conflict {
+            Strategy::Fail => self.conflict(target, message),
+            Strategy::Adopt if self.is_template(src) => {
+                self.conflict(target, format!("{message}, templates can't adopt files"))
+            }
+            Strategy::Adopt if self.node(target) == Node::File && real_node(src) == Node::File => {
+                let task = Task::Adopt {
+                    path,
+                    src: src.to_path_buf(),
+                };
+                self.record(task, Node::Missing);
+                self.stow_node(farm, src, target)?;
+            }
+            Strategy::Adopt => {
+                self.conflict(target, format!("{message}, only files can be adopted"))
+            }
+            Strategy::Backup => {
+                let backup = backup_path(target);
+                self.record(Task::Backup { path, backup }, Node::Missing);
+                self.stow_node(farm, src, target)?;
+            }
+            Strategy::Overwrite => {
+                self.record(Task::Remove { path }, Node::Missing);
+                self.stow_node(farm, src, target)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn unstow_contents(
+        &mut self,
+        farm: &Farm,
+        src_dir: &Path,
+        target_dir: &Path,
+    ) -> std::io::Result<()> {
+        for name in sorted_entries(src_dir)? {
+            let src = src_dir.join(&name);
+            if self.ignored(farm, &src)? {
+                continue;
+            }
+            let target = self.target_of(&src, target_dir);
+
+            match self.node(&target) {
+                Node::File if self.owns_file(&target) => self.unlink(&target),
+                Node::Link(dest) if resolve_link(&target, &dest) == src => self.unlink(&target),
+                Node::Dir if is_real_dir(&src) => {
+                    self.unstow_contents(farm, &src, &target)?;
+                    self.cleanup(farm, &target)?;
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Render or copy `src` into a real file at `target`, unless it already holds the same contents.
+    /// A file that was edited since bow wrote it is dealt with like any other file in the way
+    fn write_file(&mut self, farm: &Farm, src: &Path, target: &Path) -> std::io::Result<()> {
+        let permissions = match self.permissions_of(farm, src) {
+            Some(permissions) => permissions,
+            None => std::fs::metadata(src)?.permissions().mode() & 0o7777,
+        };
+        let (task, hash) = if self.is_template(src) {
+            let contents = render_template(farm, src)?;
+            let hash = hash_of(contents.as_bytes());
+            let task = Task::Render {
+                path: target.to_path_buf(),
+                src: src.to_path_buf(),
+                contents,
+                permissions,
+            };
+            (task, hash)
+        } else {
+            let hash = hash_of(&std::fs::read(src)?);
+            let task = Task::Copy {
+                path: target.to_path_buf(),
+                src: src.to_path_buf(),
+                permissions,
+            };
+            (task, hash)
+        };
+
+        match self.node(target) {
+            Node::File if hash_on_disk(target).as_ref() == Some(&hash) => {
+                self.written.insert(target.to_path_buf(), hash);
+                if disk_permissions(target) != Some(permissions) {
+                    self.chmod(target, permissions);
+                }
+            }
+            Node::Missing => self.write(task, hash),
+            Node::File if self.owns_file(target) => self.write(task, hash),
+            Node::File if self.known.contains_key(target) => self.in_the_way(
+                farm,
+                src,
+                target,
+                String::from("existing file was edited since bow wrote it"),
+            )?,
+            Node::File => self.in_the_way(
+                farm,
+                src,
+                target,
+                String::from("existing file was not written by bow"),
+            )?,
+            Node::Link(dest) => {
+                let resolved = resolve_link(target, &dest);
+                if !farm.owns(&resolved) {
+                    self.in_the_way(
+                        farm,
+                        src,
+                        target,
+                        format!("existing link to {} is not owned by bow", dest.display()),
+                    )?;
+                } else if std::fs::symlink_metadata(&resolved).is_err()
+                    || resolve_link(target, &dest) == src
+                {
+                    // Left behind by a removed file, or by the file when it was still linked
+                    self.unlink(target);
+                    self.write(task, hash);
+                } else {
+                    self.conflict(
+                        target,
+                        format!(
+                            "existing link is owned by package {}",
+                            farm.package_of(&resolved)
+                        ),
+                    );
+                }
+            }
+            Node::Dir => self.conflict(target, String::from("existing directory is in the way")),
+        }
+
+        Ok(())
+    }
+
+    /// Whether `src` is a template that's rendered rather than linked
+    fn is_template(&self, src: &Path) -> bool {
+        self.options.templates
+            && src.file_name().is_some_and(|name| {
+                let name = name.to_string_lossy();
+                name.len() > TEMPLATE_SUFFIX.len() && name.ends_with(TEMPLATE_SUFFIX)
+            })
+            && real_node(src) == Node::File
+    }
+
+    /// Where `src` goes in `target_dir`, which for templates is without their suffix
+    /// and with `--dotfiles` starts with a `.` instead of `dot-`
+    fn target_of(&self, src: &Path, target_dir: &Path) -> PathBuf {
+        let name = self
+            .options
+            .target_name(src.file_name().unwrap_or_default());
+        if !self.is_template(src) {
+            return target_dir.join(name);
+        }
+
+        let name = name.to_string_lossy();
+        target_dir.join(&name[..name.len() - TEMPLATE_SUFFIX.len()])
+    }
+
+    /// Whether `src` is rendered or copied rather than linked
+    fn is_written(&self, farm: &Farm, src: &Path) -> bool {
+        self.is_template(src)
+            || (real_node(src) == Node::File
+                && self.rules_of(farm, src).0.mode(&farm.path_in_package(src)) == Mode::Copy)
+    }
+
+    /// The rules of the package `src` is in, and the path of `src` in it
+    fn rules_of(&self, farm: &Farm, src: &Path) -> (Rules, PathBuf) {
+        let rules = self
+            .options
+            .rules
+            .get(&farm.package_of(src))
+            .cloned()
+            .unwrap_or_default();
+        (rules, farm.path_in_package(src))
+    }
+
+    fn permissions_of(&self, farm: &Farm, src: &Path) -> Option<u32> {
+        let (rules, path) = self.rules_of(farm, src);
+        rules.permissions(&path)
+    }
+
+    /// Give `path` the permissions declared for `src`, if it doesn't have them already
+    fn apply_permissions(&mut self, farm: &Farm, src: &Path, path: &Path) {
+        if let Some(permissions) = self.permissions_of(farm, src)
+            && disk_permissions(path) != Some(permissions)
+        {
+            self.chmod(path, permissions);
+        }
+    }
+
+    /// Whether `path` still holds exactly what bow last wrote into it
+    fn owns_file(&self, path: &Path) -> bool {
+        self.known
+            .get(path)
+            .is_some_and(|hash| hash_on_disk(path).as_ref() == Some(hash))
+    }
+
+    /// Whether `src` is ignored by `--ignore`, the configured ignore list or its package's ignore list
+    fn ignored(&mut self, farm: &Farm, src: &Path) -> std::io::Result<bool> {
+        let path = farm.path_in_package(src);
+        if path == Path::new(LOCAL_IGNORE)
+            || matches(&self.options.ignore, &path)
+            || self.options.ignore_list.matches(&path)
+        {
+            return Ok(true);
+        }
+
+        let package_dir = farm.package_dir(&farm.package_of(src));
+        if !self.ignore_lists.contains_key(&package_dir) {
+            let list = IgnoreList::for_package(&package_dir)?;
+            self.ignore_lists.insert(package_dir.clone(), list);
+        }
+        Ok(self.ignore_lists[&package_dir].matches(&path))
+    }
+
+    /// Whether the directory `src` can be linked as a whole,
+    /// which it can't if anything in it is ignored, rendered, copied or has permissions set
+    fn foldable(&mut self, farm: &Farm, src: &Path) -> std::io::Result<bool> {
+        let (rules, path) = self.rules_of(farm, src);
+        if !self.options.folding || rules.needs_real(&path) {
+            return Ok(false);
+        }
+
+        for name in sorted_entries(src)? {
+            let path = src.join(name);
+            if self.ignored(farm, &path)?
+                || self.is_written(farm, &path)
+                || (is_real_dir(&path) && !self.foldable(farm, &path)?)
+            {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Remove a directory bow created once it's empty, or fold it back into a link
+    /// if everything left in it belongs to the same directory of a single package
+    fn cleanup(&mut self, farm: &Farm, dir: &Path) -> std::io::Result<()> {
+        if !self.created.contains(dir) || self.node(dir) != Node::Dir {
+            return Ok(());
+        }
+
+        let children = self.children(dir);
+        if children.is_empty() {
+            self.rmdir(dir);
+            return Ok(());
+        }
+
+        if !self.options.folding {
+            return Ok(());
+        }
+
+        let mut parent = None;
+        for child in children.iter() {
+            let Node::Link(dest) = self.node(child) else {
+                return Ok(());
+            };
+
+            let resolved = resolve_link(child, &dest);
+            if self.target_of(&resolved, dir) != *child {
+                return Ok(());
+            }
+            match (&parent, resolved.parent()) {
+                (None, Some(p)) => parent = Some(p.to_path_buf()),
+                (Some(existing), Some(p)) if existing == p => {}
+                _ => return Ok(()),
+            }
+        }
+
+        let Some(parent) = parent.filter(|p| farm.owns(p)) else {
+            return Ok(());
+        };
+        if !self.foldable(farm, &parent)? {
+            return Ok(());
+        }
+
+        for child in children.iter() {
+            self.unlink(child);
+        }
+        self.rmdir(dir);
+        self.link(dir, &parent);
+
+        Ok(())
+    }
+
+    /// What `path` is once the tasks planned so far have run
+    fn node(&self, path: &Path) -> Node {
+        if let Some(node) = self.overlay.get(path) {
+            return node.clone();
+        }
+
+        // Anything under a path that was replaced only exists in the overlay
+        if path
+            .ancestors()
+            .skip(1)
+            .any(|a| self.overlay.contains_key(a))
+        {
+            return Node::Missing;
+        }
+
+        real_node(path)
+    }
+
+    /// The paths in `dir` once the tasks planned so far have run
+    fn children(&self, dir: &Path) -> BTreeSet<PathBuf> {
+        let mut children = BTreeSet::new();
+
+        let replaced = dir.ancestors().any(|a| self.overlay.contains_key(a));
+        if !replaced && let Ok(entries) = std::fs::read_dir(dir) {
+            children.extend(entries.flatten().map(|e| e.path()));
+        }
+
+        for (path, node) in self.overlay.iter() {
+            if path.parent() != Some(dir) {
+                continue;
+            }
+            if *node == Node::Missing {
+                children.remove(path);
+            } else {
+                children.insert(path.clone());
+            }
+        }
+
+        children
+    }
+
+    fn record(&mut self, task: Task, node: Node) {
+        let path = task.path().to_path_buf();
+        if !self.original.contains_key(&path) {
+            let original = if path
+                .ancestors()
+                .skip(1)
+                .any(|a| self.overlay.contains_key(a))
+            {
+                Node::Missing
+            } else {
+                real_node(&path)
+            };
+            self.original.insert(path.clone(), original);
+        }
+
+        self.overlay.insert(path, node);
+        self.tasks.push(task);
+    }
+
+    fn link(&mut self, path: &Path, src: &Path) {
+        let dest = relative(path.parent().unwrap_or(Path::new("/")), src);
+        self.record(
+            Task::Link {
+                path: path.to_path_buf(),
+                dest: dest.clone(),
+            },
+            Node::Link(dest),
+        );
+    }
+
+    fn unlink(&mut self, path: &Path) {
+        self.record(
+            Task::Unlink {
+                path: path.to_path_buf(),
+            },
+            Node::Missing,
+        );
+    }
+
+    /// Plan a task that renders or copies a file, which will hold contents with `hash`
+    fn write(&mut self, task: Task, hash: String) {
+        self.written.insert(task.path().to_path_buf(), hash);
+        self.record(task, Node::File);
+    }
+
+    fn chmod(&mut self, path: &Path, permissions: u32) {
+        let node = self.node(path);
+        self.record(
+            Task::Chmod {
+                path: path.to_path_buf(),
+                permissions,
+            },
+            node,
+        );
+    }
+
+    fn mkdir(&mut self, path: &Path) {
+        self.created.insert(path.to_path_buf());
+        self.record(
+            Task::Mkdir {
+                path: path.to_path_buf(),
+            },
+            Node::Dir,
+        );
+    }
+
+    fn rmdir(&mut self, path: &Path) {
+        self.created.remove(path);
+        self.record(
+            Task::Rmdir {
+                path: path.to_path_buf(),
+            },
+            Node::Missing,
+        );
+    }
+
+    fn conflict(&mut self, path: &Path, message: String) {
+        self.conflicts.push(Conflict {
+            path: path.to_path_buf(),
+            message,
+        });
+    }
+}
+
+/// Render the template `src` with the name of its package, the usual built-in variables and `vars` from the config
+fn render_template(farm: &Farm, src: &Path) -> std::io::Result<String> {
+    let invalid = |message: String| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("template {}: {message}", src.display()),
+        )
+    };
+
+    let template = std::fs::read_to_string(src).map_err(|e| invalid(e.to_string()))?;
+    let package = farm.package_of(src);
+    let cx = crate::template::Context {
+        name: &package,
+        version: None,
+    };
+
+    crate::template::render(&template, cx).map_err(invalid)
+}
+
+/// `sha256:<hex>` of `bytes`, the way hashes are recorded in the state
+fn hash_of(bytes: &[u8]) -> String {
+    Checksum {
+        algorithm: Algorithm::Sha256,
+        digest: Algorithm::Sha256.digest(bytes),
+    }
+    .to_string()
+}
+
+fn hash_on_disk(path: &Path) -> Option<String> {
+    std::fs::read(path).ok().map(|bytes| hash_of(&bytes))
+}
+
+/// The permission bits of `path`, following links
+fn disk_permissions(path: &Path) -> Option<u32> {
+    std::fs::metadata(path)
+        .ok()
+        .map(|meta| meta.permissions().mode() & 0o7777)
+}
+
+/// A free path next to `path` to move it to, named `<name>.<timestamp>.bow-bak`
+fn backup_path(path: &Path) -> PathBuf {
+    let now = crate::lockfile::now();
+    let mut backup = PathBuf::from(format!("{}.{now}.bow-bak", path.display()));
+    let mut n = 1;
+    while real_node(&backup) != Node::Missing {
+        backup = PathBuf::from(format!("{}.{now}-{n}.bow-bak", path.display()));
+        n += 1;
+    }
+    backup
+}
+
+fn real_node(path: &Path) -> Node {
+    match std::fs::symlink_metadata(path) {
+        Err(_) => Node::Missing,
+        Ok(meta) if meta.is_symlink() => Node::Link(std::fs::read_link(path).unwrap_or_default()),
+        Ok(meta) if meta.is_dir() => Node::Dir,
+        Ok(_) => Node::File,
+    }
+}
+
+/// Whether `path` is a directory and not a link to one
+pub fn is_real_dir(path: &Path) -> bool {
+    std::fs::symlink_metadata(path).is_ok_and(|meta| meta.is_dir())
+}
+
+fn sorted_entries(dir: &Path) -> std::io::Result<Vec<std::ffi::OsString>> {
+    let mut names = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.file_name()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    names.sort();
+    Ok(names)
+}
+
+/// Where the link at `link` pointing to `dest` leads, without following any other links
+fn resolve_link(link: &Path, dest: &Path) -> PathBuf {
+    normalize(&link.parent().unwrap_or(Path::new("/")).join(dest))
+}
+
+/// Resolve `.` and `..` in an absolute path without touching the filesystem
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            c => normalized.push(c),
+        }
+    }
+    normalized
+}
+
+/// The relative path that leads from the directory `from` to `to`, both absolute and normalized
+fn relative(from: &Path, to: &Path) -> PathBuf {
+    let from: Vec<_> = from.components().collect();
+    let to: Vec<_> = to.components().collect();
+    let common = from
+        .iter()
+        .zip(to.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut relative = PathBuf::new();
+    for _ in common..from.len() {
+        relative.push("..");
+    }
+    for component in &to[common..] {
+        relative.push(component);
+    }
+    relative
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ignored(list: &IgnoreList, path: &str) -> bool {
+        list.matches(Path::new(path))
+    }
+
+    // Ported from GNU Stow's t/ignore.t
+
+    #[test]
+    fn default_list_ignores_vcs_and_editor_files() {
+        let list = IgnoreList::parse(DEFAULT_IGNORE).unwrap();
+
+        for path in [
+            "RCS",
+            "foo,v",
+            "CVS",
+            ".#lock-file",
+            ".cvsignore",
+            ".svn",
+            "_darcs",
+            ".hg",
+            ".git",
+            ".gitignore",
+            ".gitmodules",
+            "file~",
+            "#autosave#",
+            "README",
+            "README.md",
+            "LICENSE",
+            "LICENSE.txt",
+            "COPYING",
+            "nested/.git",
+            "nested/emacs-backup~",
+        ] {
+            assert!(ignored(&list, path), "{path} should be ignored");
+        }
+
+        for path in [
+            "foo,v.txt",
+            "sub/README.md",
+            "sub/LICENSE",
+            "sub/COPYING",
+            ".gitconfig",
+            "bin/git",
+            "COPYING.md",
+        ] {
+            assert!(!ignored(&list, path), "{path} should not be ignored");
+        }
+    }
+
+    #[test]
+    fn basename_patterns_match_whole_names_at_any_depth() {
+        let list = IgnoreList::parse("exact\n.+~\nre.*ex\n").unwrap();
+
+        for path in [
+            "exact",
+            "a/exact",
+            "a/b/exact",
+            "x~",
+            "a/x~",
+            "regex",
+            "a/reflex",
+        ] {
+            assert!(ignored(&list, path), "{path} should be ignored");
+        }
+        for path in [
+            "inexact",
+            "exactly",
+            "~",
+            "a/x~y",
+            "prefix-regex",
+            "exact/file",
+        ] {
+            assert!(!ignored(&list, path), "{path} should not be ignored");
+        }
+    }
+
+    #[test]
+    fn path_patterns_match_whole_components() {
+        let list = IgnoreList::parse("foo/bar\n").unwrap();
+
+        for path in ["foo/bar", "foo/bar/baz", "a/foo/bar", "a/foo/bar/baz"] {
+            assert!(ignored(&list, path), "{path} should be ignored");
+        }
+        for path in ["foo", "foo/barn", "foo/bar.txt", "afoo/bar", "bar"] {
+            assert!(!ignored(&list, path), "{path} should not be ignored");
+        }
+    }
+
+    #[test]
+    fn anchored_path_patterns_only_match_from_the_top() {
+        let list = IgnoreList::parse("^/foo/bar\n/baz\n").unwrap();
+
+        // The path being matched starts with a `/`, so a pattern starting with one is anchored as well
+        for path in ["foo/bar", "foo/bar/qux", "baz", "baz/qux"] {
+            assert!(ignored(&list, path), "{path} should be ignored");
+        }
+        for path in ["a/foo/bar", "foo/barn", "a/baz", "bazz"] {
+            assert!(!ignored(&list, path), "{path} should not be ignored");
+        }
+    }
+
+    #[test]
+    fn ignore_files_have_comments() {
+        let list = IgnoreList::parse(
+            "# a comment\n\n   \nfoo   # trailing comment\n\\#hash\\#\n#not-a-pattern\n",
+        )
+        .unwrap();
+
+        assert!(ignored(&list, "foo"));
+        assert!(ignored(&list, "#hash#"));
+        assert!(!ignored(&list, "#not-a-pattern"));
+        assert!(!ignored(&list, "a comment"));
+        assert!(!ignored(&list, "bar"));
+    }
+
+    #[test]
+    fn local_ignore_file_replaces_the_defaults() {
+        let package = tempfile::tempdir().unwrap();
+        std::fs::write(package.path().join(LOCAL_IGNORE), "only-this\n").unwrap();
+
+        let list = IgnoreList::for_package(package.path()).unwrap();
+        assert!(ignored(&list, "only-this"));
+        assert!(!ignored(&list, ".git"));
+        assert!(!ignored(&list, "README.md"));
+    }
+
+    #[test]
+    fn invalid_patterns_are_reported() {
+        let package = tempfile::tempdir().unwrap();
+        std::fs::write(package.path().join(LOCAL_IGNORE), "(unclosed\n").unwrap();
+
+        let err = IgnoreList::for_package(package.path()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains(LOCAL_IGNORE), "{err}");
+    }
+
+    /// A stow directory and an empty target next to it in a temporary directory, which keeps track of the
+    /// directories bow created and the files it wrote across plans the way the dotfiles provider does
+    struct Fixture {
+        root: tempfile::TempDir,
+        farm: Farm,
+        created: BTreeSet<PathBuf>,
+        known: BTreeMap<PathBuf, String>,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let root = tempfile::tempdir().unwrap();
+            let farm = Farm {
+                stow_dir: root.path().join("stow"),
+                target: root.path().join("target"),
+            };
+            std::fs::create_dir_all(&farm.target).unwrap();
+
+            Self {
+                root,
+                farm,
+                created: BTreeSet::new(),
+                known: BTreeMap::new(),
+            }
+        }
+
+        fn path(&self, path: &str) -> PathBuf {
+            self.root.path().join(path)
+        }
+
+        fn write(&self, path: &str, contents: &str) {
+            let path = self.path(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+
+        fn read(&self, path: &str) -> String {
+            std::fs::read_to_string(self.path(path)).unwrap()
+        }
+
+        fn link(&self, path: &str) -> Option<PathBuf> {
+            std::fs::read_link(self.path(path)).ok()
+        }
+
+        /// Add `files` to `package`, each holding its own name.
+        /// Its empty ignore file keeps `~/.stow-global-ignore` out of the tests
+        fn package(&self, package: &str, files: &[&str]) {
+            self.write(&format!("stow/{package}/{LOCAL_IGNORE}"), "");
+            for file in files {
+                self.write(&format!("stow/{package}/{file}"), file);
+            }
+        }
+
+        fn planner(&self, options: &Options) -> Planner {
+            Planner::new(options.clone(), self.created.clone(), self.known.clone())
+        }
+
+        /// `items` as `stow -v` would print them, with paths relative to the fixture
+        fn describe(&self, items: &[impl fmt::Display]) -> Vec<String> {
+            let root = format!("{}/", self.root.path().display());
+            items
+                .iter()
+                .map(|item| item.to_string().replace(&root, ""))
+                .collect()
+        }
+
+        /// The tasks and conflicts of a plan, without running it
+        fn finish(&self, planner: Planner) -> (Vec<String>, Vec<String>) {
+            let (tasks, conflicts) = planner.finish();
+            (self.describe(&tasks), self.describe(&conflicts))
+        }
+
+        /// Run a plan that has no conflicts, returning its tasks
+        fn apply(&mut self, planner: Planner) -> Vec<String> {
+            let written = planner.written().clone();
+            let (tasks, conflicts) = planner.finish();
+            assert_eq!(self.describe(&conflicts), Vec::<String>::new());
+
+            for task in &tasks {
+                task.execute().unwrap();
+                match task {
+                    Task::Mkdir { path } => {
+                        self.created.insert(path.clone());
+                    }
+                    Task::Rmdir { path } => {
+                        self.created.remove(path);
+                    }
+                    _ => {}
+                }
+            }
+            self.known.extend(written);
+
+            self.describe(&tasks)
+        }
+
+        fn stow(&mut self, options: &Options, packages: &[&str]) -> Vec<String> {
+            let mut planner = self.planner(options);
+            for package in packages {
+                planner.stow(&self.farm, package).unwrap();
+            }
+            self.apply(planner)
+        }
+
+        fn unstow(&mut self, options: &Options, packages: &[&str]) -> Vec<String> {
+            let mut planner = self.planner(options);
+            for package in packages {
+                planner.unstow(&self.farm, package).unwrap();
+            }
+            self.apply(planner)
+        }
+    }
+
+    #[test]
+    fn links_files_and_folds_directories() {
+        let mut fx = Fixture::new();
+        fx.package("a", &[".bashrc", ".config/nvim/init.lua"]);
+
+        assert_eq!(
+            fx.stow(&Options::default(), &["a"]),
+            [
+                "LINK: target/.bashrc => ../stow/a/.bashrc",
+                "LINK: target/.config => ../stow/a/.config",
+            ]
+        );
+        assert_eq!(
+            fx.read("target/.config/nvim/init.lua"),
+            ".config/nvim/init.lua"
+        );
+    }
+
+    #[test]
+    fn unfolds_a_directory_another_package_needs_and_folds_it_back() {
+        let mut fx = Fixture::new();
+        let options = Options::default();
+        fx.package("a", &[".config/nvim/init.lua"]);
+        fx.package("b", &[".config/git/config"]);
+        fx.stow(&options, &["a"]);
+
+        assert_eq!(
+            fx.stow(&options, &["b"]),
+            [
+                "UNLINK: target/.config",
+                "MKDIR: target/.config",
+                "LINK: target/.config/nvim => ../../stow/a/.config/nvim",
+                "LINK: target/.config/git => ../../stow/b/.config/git",
+            ]
+        );
+
+        assert_eq!(
+            fx.unstow(&options, &["b"]),
+            [
+                "UNLINK: target/.config/git",
+                "UNLINK: target/.config/nvim",
+                "RMDIR: target/.config",
+                "LINK: target/.config => ../stow/a/.config",
+            ]
+        );
+        assert_eq!(fx.link("target/.config"), Some("../stow/a/.config".into()));
+    }
+
+    #[test]
+    fn removes_directories_it_created_once_they_are_empty() {
+        let mut fx = Fixture::new();
+        let options = Options {
+            folding: false,
+            ..Options::default()
+        };
+        fx.package("a", &[".bashrc", ".config/nvim/init.lua"]);
+
+        assert_eq!(
+            fx.stow(&options, &["a"]),
+            [
+                "LINK: target/.bashrc => ../stow/a/.bashrc",
+                "MKDIR: target/.config",
+                "MKDIR: target/.config/nvim",
+                "LINK: target/.config/nvim/init.lua => ../../../stow/a/.config/nvim/init.lua",
+            ]
+        );
+        assert_eq!(
+            fx.unstow(&options, &["a"]),
+            [
+                "UNLINK: target/.bashrc",
+                "UNLINK: target/.config/nvim/init.lua",
+                "RMDIR: target/.config/nvim",
+                "RMDIR: target/.config",
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_directories_it_did_not_create() {
+        let mut fx = Fixture::new();
+        fx.package("a", &[".config/nvim/init.lua"]);
+        fx.write("target/.config/other", "not bow's");
+
+        assert_eq!(
+            fx.stow(&Options::default(), &["a"]),
+            ["LINK: target/.config/nvim => ../../stow/a/.config/nvim"]
+        );
+        assert_eq!(
+            fx.unstow(&Options::default(), &["a"]),
+            ["UNLINK: target/.config/nvim"]
+        );
+        assert_eq!(fx.read("target/.config/other"), "not bow's");
+    }
+
+    #[test]
+    fn restowing_changes_nothing() {
+        let mut fx = Fixture::new();
+        let options = Options::default();
+        fx.package("a", &[".bashrc", ".config/nvim/init.lua"]);
+        fx.stow(&options, &["a"]);
+
+        let mut planner = fx.planner(&options);
+        planner.unstow(&fx.farm, "a").unwrap();
+        planner.stow(&fx.farm, "a").unwrap();
+        assert_eq!(fx.finish(planner), (vec![], vec![]));
+    }
+
+    #[test]
+    fn reports_what_is_in_the_way() {
+        let mut fx = Fixture::new();
+        fx.package("a", &[".bashrc", ".config/nvim/init.lua", ".profile"]);
+        fx.package("b", &[".profile"]);
+        fx.stow(&Options::default(), &["b"]);
+        fx.write("target/.bashrc", "mine");
+        std::os::unix::fs::symlink("/elsewhere", fx.path("target/.config")).unwrap();
+
+        let mut planner = fx.planner(&Options::default());
+        planner.stow(&fx.farm, "a").unwrap();
+        let (_, conflicts) = fx.finish(planner);
+        assert_eq!(
+            conflicts,
+            [
+                "target/.bashrc: existing file is neither a link nor a directory",
+                "target/.config: existing link to /elsewhere is not owned by bow",
+                "target/.profile: existing link is owned by package b",
+            ]
+        );
+        assert_eq!(fx.read("target/.bashrc"), "mine");
+    }
+
+    #[test]
+    fn unlinks_links_to_files_a_package_no_longer_has() {
+        let mut fx = Fixture::new();
+        fx.package("a", &[".bashrc", ".profile"]);
+        fx.stow(&Options::default(), &["a"]);
+        std::fs::remove_file(fx.path("stow/a/.bashrc")).unwrap();
+
+        let mut planner = fx.planner(&Options::default());
+        let files = [fx.path("target/.bashrc"), fx.path("target/.profile")];
+        planner.unlink_stale(&fx.farm, "a", &files).unwrap();
+        assert_eq!(fx.apply(planner), ["UNLINK: target/.bashrc"]);
+        assert_eq!(
+            fx.link("target/.profile"),
+            Some("../stow/a/.profile".into())
+        );
+    }
+
+    #[test]
+    fn fails_on_missing_packages() {
+        let fx = Fixture::new();
+        let err = fx
+            .planner(&Options::default())
+            .stow(&fx.farm, "nope")
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    fn patterns(compile: fn(&[String]) -> Result<Vec<Regex>, regex::Error>, p: &str) -> Vec<Regex> {
+        compile(&[p.to_string()]).unwrap()
+    }
+
+    #[test]
+    fn ignore_option_matches_the_end_of_a_path() {
+        let mut fx = Fixture::new();
+        fx.package(
+            "a",
+            &[".bashrc", ".bashrc.orig", "bin/tool", "bin/tool.orig"],
+        );
+        let options = Options {
+            ignore: patterns(Options::suffix_patterns, r"\.orig"),
+            ..Options::default()
+        };
+
+        assert_eq!(
+            fx.stow(&options, &["a"]),
+            [
+                "LINK: target/.bashrc => ../stow/a/.bashrc",
+                "MKDIR: target/bin",
+                "LINK: target/bin/tool => ../../stow/a/bin/tool",
+            ]
+        );
+    }
+
+    #[test]
+    fn defer_leaves_paths_to_the_package_that_has_them() {
+        let mut fx = Fixture::new();
+        fx.package("a", &[".profile", "bin/a"]);
+        fx.package("b", &[".profile", "bin/b"]);
+        fx.stow(&Options::default(), &["a"]);
+
+        let options = Options {
+            defer: patterns(Options::prefix_patterns, r"\.profile"),
+            ..Options::default()
+        };
+        assert_eq!(
+            fx.stow(&options, &["b"]),
+            [
+                "UNLINK: target/bin",
+                "MKDIR: target/bin",
+                "LINK: target/bin/a => ../../stow/a/bin/a",
+                "LINK: target/bin/b => ../../stow/b/bin/b",
+            ]
+        );
+        assert_eq!(
+            fx.link("target/.profile"),
+            Some("../stow/a/.profile".into())
+        );
+    }
+
+    #[test]
+    fn override_takes_paths_from_the_package_that_has_them() {
+        let mut fx = Fixture::new();
+        fx.package("a", &[".profile"]);
+        fx.package("b", &[".profile"]);
+        fx.stow(&Options::default(), &["a"]);
+
+        let options = Options {
+            overrides: patterns(Options::prefix_patterns, r"\.prof"),
+            ..Options::default()
+        };
+        assert_eq!(
+            fx.stow(&options, &["b"]),
+            [
+                "UNLINK: target/.profile",
+                "LINK: target/.profile => ../stow/b/.profile",
+            ]
+        );
+    }
+
+    #[test]
+    fn defer_and_override_match_the_start_of_a_path() {
+        let defer = patterns(Options::prefix_patterns, "bin");
+        assert!(matches(&defer, Path::new("bin/tool")));
+        assert!(!matches(&defer, Path::new(".local/bin/tool")));
+
+        let ignore = patterns(Options::suffix_patterns, "~");
+        assert!(matches(&ignore, Path::new("dir/file~")));
+        assert!(!matches(&ignore, Path::new("dir~/file")));
+    }
+
+    #[test]
+    fn skips_ignored_files_and_does_not_fold_their_directories() {
+        let mut fx = Fixture::new();
+        fx.package(
+            "a",
+            &[
+                "README.md",
+                ".bashrc",
+                ".config/nvim/init.lua",
+                ".config/nvim/init.lua~",
+            ],
+        );
+        fx.write("stow/a/.stow-local-ignore", "^/README.*\n.+~\n");
+
+        assert_eq!(
+            fx.stow(&Options::default(), &["a"]),
+            [
+                "LINK: target/.bashrc => ../stow/a/.bashrc",
+                "MKDIR: target/.config",
+                "MKDIR: target/.config/nvim",
+                "LINK: target/.config/nvim/init.lua => ../../../stow/a/.config/nvim/init.lua",
+            ]
+        );
+    }
+
+    #[test]
+    fn configured_ignore_list_applies_on_top_of_the_package_one() {
+        let mut fx = Fixture::new();
+        fx.package("a", &[".bashrc", "scripts/setup.sh", "scripts/tool"]);
+        let options = Options {
+            ignore_list: IgnoreList::new(["scripts/setup\\.sh"]).unwrap(),
+            ..Options::default()
+        };
+
+        assert_eq!(
+            fx.stow(&options, &["a"]),
+            [
+                "LINK: target/.bashrc => ../stow/a/.bashrc",
+                "MKDIR: target/scripts",
+                "LINK: target/scripts/tool => ../../stow/a/scripts/tool",
+            ]
+        );
+    }
+
+    #[test]
+    fn unlinks_files_that_became_ignored() {
+        let mut fx = Fixture::new();
+        fx.package("a", &[".bashrc", ".profile"]);
+        fx.stow(&Options::default(), &["a"]);
+        fx.write("stow/a/.stow-local-ignore", "\\.profile\n");
+
+        let mut planner = fx.planner(&Options::default());
+        planner.unstow(&fx.farm, "a").unwrap();
+        let files = [fx.path("target/.bashrc"), fx.path("target/.profile")];
+        planner.unlink_stale(&fx.farm, "a", &files).unwrap();
+        planner.stow(&fx.farm, "a").unwrap();
+
+        assert_eq!(fx.apply(planner), ["UNLINK: target/.profile"]);
+    }
+
+    fn on_conflict(strategy: Strategy) -> Options {
+        Options {
+            on_conflict: strategy,
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn adopts_files_in_the_way() {
+        let mut fx = Fixture::new();
+        fx.package("a", &[".bashrc"]);
+        fx.write("target/.bashrc", "mine");
+
+        assert_eq!(
+            fx.stow(&on_conflict(Strategy::Adopt), &["a"]),
+            [
+                "MV: target/.bashrc => stow/a/.bashrc",
+                "LINK: target/.bashrc => ../stow/a/.bashrc",
+            ]
+        );
+        assert_eq!(fx.read("stow/a/.bashrc"), "mine");
+        assert_eq!(fx.read("target/.bashrc"), "mine");
+    }
+
+    #[test]
+    fn only_adopts_files() {
+        let fx = Fixture::new();
+        fx.package("a", &[".config/nvim/init.lua", ".profile"]);
+        fx.write("target/.config", "a file where a directory goes");
+        std::os::unix::fs::symlink("/elsewhere", fx.path("target/.profile")).unwrap();
+
+        let mut planner = fx.planner(&on_conflict(Strategy::Adopt));
+        planner.stow(&fx.farm, "a").unwrap();
+        assert_eq!(
+            fx.finish(planner),
+            (
+                vec![],
+                vec![
+                    "target/.config: existing file is neither a link nor a directory, only files can be adopted"
+                        .to_string(),
+                    "target/.profile: existing link to /elsewhere is not owned by bow, only files can be adopted"
+                        .to_string(),
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn backs_up_files_in_the_way_and_restores_them() {
+        let mut fx = Fixture::new();
+        fx.package("a", &[".bashrc"]);
+        fx.write("target/.bashrc", "mine");
+
+        let mut planner = fx.planner(&on_conflict(Strategy::Backup));
+        planner.stow(&fx.farm, "a").unwrap();
+        let backup = planner
+            .tasks
+            .iter()
+            .find_map(|task| match task {
+                Task::Backup { backup, .. } => Some(backup.clone()),
+                _ => None,
+            })
+            .unwrap();
+        let name = backup.file_name().unwrap().to_string_lossy().into_owned();
+        assert!(
+            name.starts_with(".bashrc.") && name.ends_with(".bow-bak"),
+            "{name}"
+        );
+
+        assert_eq!(
+            fx.apply(planner),
+            [
+                format!("BACKUP: target/.bashrc => target/{name}"),
+                "LINK: target/.bashrc => ../stow/a/.bashrc".to_string(),
+            ]
+        );
+        assert_eq!(std::fs::read_to_string(&backup).unwrap(), "mine");
+
+        let mut planner = fx.planner(&Options::default());
+        planner.unstow(&fx.farm, "a").unwrap();
+        planner.restore(&fx.path("target/.bashrc"), &backup);
+        assert_eq!(
+            fx.apply(planner),
+            [
+                "UNLINK: target/.bashrc".to_string(),
+                format!("RESTORE: target/{name} => target/.bashrc"),
+            ]
+        );
+        assert_eq!(fx.read("target/.bashrc"), "mine");
+    }
+
+    #[test]
+    fn backups_do_not_replace_earlier_ones() {
+        let fx = Fixture::new();
+        let path = fx.path("target/.bashrc");
+        let first = backup_path(&path);
+        std::fs::write(&first, "first").unwrap();
+
+        let second = backup_path(&path);
+        assert_ne!(first, second);
+        assert_eq!(real_node(&second), Node::Missing);
+    }
+
+    #[test]
+    fn overwrites_files_and_foreign_links_in_the_way() {
+        let mut fx = Fixture::new();
+        fx.package("a", &[".bashrc", ".profile"]);
+        fx.write("target/.bashrc", "mine");
+        std::os::unix::fs::symlink("/elsewhere", fx.path("target/.profile")).unwrap();
+
+        assert_eq!(
+            fx.stow(&on_conflict(Strategy::Overwrite), &["a"]),
+            [
+                "REMOVE: target/.bashrc",
+                "LINK: target/.bashrc => ../stow/a/.bashrc",
+                "REMOVE: target/.profile",
+                "LINK: target/.profile => ../stow/a/.profile",
+            ]
+        );
+        assert_eq!(fx.read("target/.bashrc"), ".bashrc");
+    }
+
+    #[test]
+    fn strategies_never_touch_links_of_other_packages() {
+        let mut fx = Fixture::new();
+        fx.package("a", &[".profile"]);
+        fx.package("b", &[".profile"]);
+        fx.stow(&Options::default(), &["a"]);
+
+        for strategy in [Strategy::Adopt, Strategy::Backup, Strategy::Overwrite] {
+            let mut planner = fx.planner(&on_conflict(strategy));
+            planner.stow(&fx.farm, "b").unwrap();
+            assert_eq!(
+                fx.finish(planner),
+                (
+                    vec![],
+                    vec!["target/.profile: existing link is owned by package a".to_string()]
+                )
+            );
+        }
+    }
+
+    fn templates() -> Options {
+        Options {
+            templates: true,
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn renders_templates_into_real_files() {
+        let mut fx = Fixture::new();
+        fx.package("git", &[".config/tool/other"]);
+        fx.write("stow/git/.gitconfig.tmpl", "[user]\n\tname = {{ name }}\n");
+        fx.write("stow/git/.config/tool/config.tmpl", "{{ os }}");
+
+        assert_eq!(
+            fx.stow(&templates(), &["git"]),
+            [
+                "MKDIR: target/.config",
+                "MKDIR: target/.config/tool",
+                "RENDER: target/.config/tool/config from stow/git/.config/tool/config.tmpl",
+                "LINK: target/.config/tool/other => ../../../stow/git/.config/tool/other",
+                "RENDER: target/.gitconfig from stow/git/.gitconfig.tmpl",
+            ]
+        );
+        assert_eq!(fx.read("target/.gitconfig"), "[user]\n\tname = git\n");
+        assert_eq!(fx.read("target/.config/tool/config"), std::env::consts::OS);
+
+        // Nothing changed, so nothing is rendered again
+        assert_eq!(fx.stow(&templates(), &["git"]), Vec::<String>::new());
+    }
+
+    #[test]
+    fn links_templates_when_they_are_disabled() {
+        let mut fx = Fixture::new();
+        fx.package("git", &[".gitconfig.tmpl"]);
+
+        assert_eq!(
+            fx.stow(&Options::default(), &["git"]),
+            ["LINK: target/.gitconfig.tmpl => ../stow/git/.gitconfig.tmpl"]
+        );
+    }
+
+    #[test]
+    fn rerenders_changed_templates() {
+        let mut fx = Fixture::new();
+        fx.package("git", &[]);
+        fx.write("stow/git/.gitconfig.tmpl", "v1");
+        fx.stow(&templates(), &["git"]);
+
+        fx.write("stow/git/.gitconfig.tmpl", "v2");
+        assert_eq!(
+            fx.stow(&templates(), &["git"]),
+            ["RENDER: target/.gitconfig from stow/git/.gitconfig.tmpl"]
+        );
+        assert_eq!(fx.read("target/.gitconfig"), "v2");
+    }
+
+    #[test]
+    fn detects_rendered_files_that_were_edited() {
+        let mut fx = Fixture::new();
+        fx.package("git", &[]);
+        fx.write("stow/git/.gitconfig.tmpl", "v1");
+        fx.stow(&templates(), &["git"]);
+        fx.write("target/.gitconfig", "edited");
+        fx.write("stow/git/.gitconfig.tmpl", "v2");
+
+        let mut planner = fx.planner(&templates());
+        planner.stow(&fx.farm, "git").unwrap();
+        assert_eq!(
+            fx.finish(planner),
+            (
+                vec![],
+                vec!["target/.gitconfig: existing file was edited since bow wrote it".to_string()]
+            )
+        );
+
+        // Unstowing leaves the edits alone as well
+        assert_eq!(fx.unstow(&templates(), &["git"]), Vec::<String>::new());
+        assert_eq!(fx.read("target/.gitconfig"), "edited");
+    }
+
+    #[test]
+    fn removes_untouched_rendered_files_when_unstowing() {
+        let mut fx = Fixture::new();
+        fx.package("git", &[]);
+        fx.write("stow/git/.gitconfig.tmpl", "v1");
+        fx.stow(&templates(), &["git"]);
+
+        assert_eq!(
+            fx.unstow(&templates(), &["git"]),
+            ["UNLINK: target/.gitconfig"]
+        );
+        assert_eq!(real_node(&fx.path("target/.gitconfig")), Node::Missing);
+    }
+
+    #[test]
+    fn templates_never_adopt_files() {
+        let fx = Fixture::new();
+        fx.package("git", &[]);
+        fx.write("stow/git/.gitconfig.tmpl", "v1");
+        fx.write("target/.gitconfig", "mine");
+
+        let mut planner = fx.planner(&Options {
+            on_conflict: Strategy::Adopt,
+            ..templates()
+        });
+        planner.stow(&fx.farm, "git").unwrap();
+        assert_eq!(
+            fx.finish(planner),
+            (
+                vec![],
+                vec![
+                    "target/.gitconfig: existing file was not written by bow, templates can't adopt files"
+                        .to_string()
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn reports_templates_that_fail_to_render() {
+        let fx = Fixture::new();
+        fx.package("git", &[]);
+        fx.write("stow/git/.gitconfig.tmpl", "{{ not_a_variable }}");
+
+        let err = fx.planner(&templates()).stow(&fx.farm, "git").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains(".gitconfig.tmpl"), "{err}");
+        assert!(err.to_string().contains("not_a_variable"), "{err}");
+    }
+
+    /// Options where `package` is put into the target the way `rules` says
+    fn rules(package: &str, rules: Rules) -> Options {
+        Options {
+            rules: BTreeMap::from([(package.to_string(), rules)]),
+            ..Options::default()
+        }
+    }
+
+    fn file_rules(mode: Option<Mode>, permissions: Option<u32>) -> FileRules {
+        FileRules {
+            mode,
+            permissions: permissions.map(Permissions),
+        }
+    }
+
+    fn copied_ssh_config() -> Options {
+        rules(
+            "ssh",
+            Rules {
+                mode: Mode::Copy,
+                files: BTreeMap::from([(".ssh/config".into(), file_rules(None, Some(0o600)))]),
+            },
+        )
+    }
+
+    #[test]
+    fn copies_files_with_their_permissions() {
+        let mut fx = Fixture::new();
+        let options = copied_ssh_config();
+        fx.package("ssh", &[".ssh/config"]);
+
+        assert_eq!(
+            fx.stow(&options, &["ssh"]),
+            [
+                "MKDIR: target/.ssh",
+                "COPY: target/.ssh/config from stow/ssh/.ssh/config",
+            ]
+        );
+        assert_eq!(real_node(&fx.path("target/.ssh/config")), Node::File);
+        assert_eq!(
+            disk_permissions(&fx.path("target/.ssh/config")),
+            Some(0o600)
+        );
+
+        assert_eq!(fx.stow(&options, &["ssh"]), Vec::<String>::new());
+    }
+
+    #[test]
+    fn keeps_copies_in_sync_with_the_package() {
+        let mut fx = Fixture::new();
+        let options = copied_ssh_config();
+        fx.package("ssh", &[".ssh/config"]);
+        fx.stow(&options, &["ssh"]);
+
+        fx.write("stow/ssh/.ssh/config", "Host *");
+        assert_eq!(
+            fx.stow(&options, &["ssh"]),
+            ["COPY: target/.ssh/config from stow/ssh/.ssh/config"]
+        );
+        assert_eq!(fx.read("target/.ssh/config"), "Host *");
+
+        std::fs::set_permissions(
+            fx.path("target/.ssh/config"),
+            std::fs::Permissions::from_mode(0o644),
+        )
+        .unwrap();
+        assert_eq!(
+            fx.stow(&options, &["ssh"]),
+            ["CHMOD: target/.ssh/config 0600"]
+        );
+    }
+
+    #[test]
+    fn detects_copies_that_drifted() {
+        let mut fx = Fixture::new();
+        let options = copied_ssh_config();
+        fx.package("ssh", &[".ssh/config"]);
+        fx.stow(&options, &["ssh"]);
+        fx.write("target/.ssh/config", "edited");
+
+        let mut planner = fx.planner(&options);
+        planner.stow(&fx.farm, "ssh").unwrap();
+        assert_eq!(
+            fx.finish(planner),
+            (
+                vec![],
+                vec!["target/.ssh/config: existing file was edited since bow wrote it".to_string()]
+            )
+        );
+
+        let mut planner = fx.planner(&Options {
+            on_conflict: Strategy::Overwrite,
+            ..options
+        });
+        planner.stow(&fx.farm, "ssh").unwrap();
+        assert_eq!(
+            fx.apply(planner),
+            [
+                "REMOVE: target/.ssh/config",
+                "COPY: target/.ssh/config from stow/ssh/.ssh/config",
+            ]
+        );
+    }
+
+    #[test]
+    fn copies_only_what_the_rules_say() {
+        let mut fx = Fixture::new();
+        let options = rules(
+            "a",
+            Rules {
+                mode: Mode::Link,
+                files: BTreeMap::from([(".local/bin".into(), file_rules(Some(Mode::Copy), None))]),
+            },
+        );
+        fx.package("a", &[".bashrc", ".local/bin/tool", ".local/share/a/data"]);
+
+        assert_eq!(
+            fx.stow(&options, &["a"]),
+            [
+                "LINK: target/.bashrc => ../stow/a/.bashrc",
+                "MKDIR: target/.local",
+                "MKDIR: target/.local/bin",
+                "COPY: target/.local/bin/tool from stow/a/.local/bin/tool",
+                "LINK: target/.local/share => ../../stow/a/.local/share",
+            ]
+        );
+    }
+
+    #[test]
+    fn sets_permissions_of_linked_files_in_the_package() {
+        let mut fx = Fixture::new();
+        let options = rules(
+            "a",
+            Rules {
+                mode: Mode::Link,
+                files: BTreeMap::from([(".netrc".into(), file_rules(None, Some(0o600)))]),
+            },
+        );
+        fx.package("a", &[".netrc"]);
+        std::fs::set_permissions(
+            fx.path("stow/a/.netrc"),
+            std::fs::Permissions::from_mode(0o644),
+        )
+        .unwrap();
+
+        assert_eq!(
+            fx.stow(&options, &["a"]),
+            [
+                "LINK: target/.netrc => ../stow/a/.netrc",
+                "CHMOD: stow/a/.netrc 0600",
+            ]
+        );
+        assert_eq!(disk_permissions(&fx.path("stow/a/.netrc")), Some(0o600));
+    }
+
+    #[test]
+    fn unlinks_copies_of_files_a_package_no_longer_has() {
+        let mut fx = Fixture::new();
+        let options = copied_ssh_config();
+        fx.package("ssh", &[".ssh/config", ".ssh/known_hosts"]);
+        fx.stow(&options, &["ssh"]);
+        std::fs::remove_file(fx.path("stow/ssh/.ssh/known_hosts")).unwrap();
+
+        let mut planner = fx.planner(&options);
+        planner.unstow(&fx.farm, "ssh").unwrap();
+        let files = [
+            fx.path("target/.ssh/config"),
+            fx.path("target/.ssh/known_hosts"),
+        ];
+        planner.unlink_stale(&fx.farm, "ssh", &files).unwrap();
+        planner.stow(&fx.farm, "ssh").unwrap();
+
+        assert_eq!(fx.apply(planner), ["UNLINK: target/.ssh/known_hosts"]);
+    }
+
+    #[test]
+    fn parses_octal_permissions() {
+        let parse = |yaml: &str| serde_yaml_bw::from_str::<Permissions>(yaml).map(|p| p.0);
+        assert_eq!(parse("'0600'").unwrap(), 0o600);
+        assert_eq!(parse("0600").unwrap(), 0o600);
+        assert_eq!(parse("755").unwrap(), 0o755);
+        assert_eq!(parse("'0o1777'").unwrap(), 0o1777);
+        assert!(parse("'0800'").is_err());
+        assert!(parse("'17777'").is_err());
+        assert_eq!(
+            serde_yaml_bw::to_string(&Permissions(0o600))
+                .unwrap()
+                .trim(),
+            "'0600'"
+        );
+    }
+
+    #[test]
+    fn the_closest_rule_sets_the_mode() {
+        let rules = Rules {
+            mode: Mode::Copy,
+            files: BTreeMap::from([
+                (".config".into(), file_rules(Some(Mode::Link), None)),
+                (".config/app/key".into(), file_rules(Some(Mode::Copy), None)),
+                (".config/app".into(), file_rules(None, Some(0o700))),
+            ]),
+        };
+
+        assert_eq!(rules.mode(Path::new(".bashrc")), Mode::Copy);
+        assert_eq!(rules.mode(Path::new(".config/nvim/init.lua")), Mode::Link);
+        assert_eq!(rules.mode(Path::new(".config/app/settings")), Mode::Link);
+        assert_eq!(rules.mode(Path::new(".config/app/key")), Mode::Copy);
+        assert!(rules.needs_real(Path::new(".config")));
+        assert!(rules.needs_real(Path::new(".config/app")));
+    }
+
+    fn dotfiles() -> Options {
+        Options {
+            dotfiles: true,
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn renames_dot_prefixed_files() {
+        let mut fx = Fixture::new();
+        fx.package("a", &["dot-", "dot-bashrc", "dot-config/dot-app/config"]);
+
+        assert_eq!(
+            fx.stow(&dotfiles(), &["a"]),
+            [
+                "LINK: target/dot- => ../stow/a/dot-",
+                "LINK: target/.bashrc => ../stow/a/dot-bashrc",
+                "LINK: target/.config => ../stow/a/dot-config",
+            ]
+        );
+        // A folded directory keeps the names of what's inside it
+        assert_eq!(
+            fx.read("target/.config/dot-app/config"),
+            "dot-config/dot-app/config"
+        );
+
+        let mut links = fx.farm.links_of("a", &dotfiles());
+        links.sort();
+        assert_eq!(
+            links,
+            [
+                fx.path("target/.bashrc"),
+                fx.path("target/.config"),
+                fx.path("target/dot-"),
+            ]
+        );
+    }
+
+    #[test]
+    fn renames_inside_unfolded_directories() {
+        let mut fx = Fixture::new();
+        let options = dotfiles();
+        fx.package("a", &["dot-config/dot-app/config"]);
+        fx.package("b", &["dot-config/git/config"]);
+        fx.stow(&options, &["a"]);
+
+        assert_eq!(
+            fx.stow(&options, &["b"]),
+            [
+                "UNLINK: target/.config",
+                "MKDIR: target/.config",
+                "LINK: target/.config/.app => ../../stow/a/dot-config/dot-app",
+                "LINK: target/.config/git => ../../stow/b/dot-config/git",
+            ]
+        );
+
+        assert_eq!(
+            fx.unstow(&options, &["b"]),
+            [
+                "UNLINK: target/.config/git",
+                "UNLINK: target/.config/.app",
+                "RMDIR: target/.config",
+                "LINK: target/.config => ../stow/a/dot-config",
+            ]
+        );
+    }
+
+    #[test]
+    fn renames_rendered_templates() {
+        let mut fx = Fixture::new();
+        fx.package("git", &[]);
+        fx.write("stow/git/dot-gitconfig.tmpl", "{{ name }}");
+
+        assert_eq!(
+            fx.stow(
+                &Options {
+                    templates: true,
+                    ..dotfiles()
+                },
+                &["git"]
+            ),
+            ["RENDER: target/.gitconfig from stow/git/dot-gitconfig.tmpl"]
+        );
+    }
+
+    #[test]
+    fn unlinks_dot_files_once_renaming_is_toggled() {
+        let mut fx = Fixture::new();
+        fx.package("a", &["dot-bashrc"]);
+        fx.stow(&dotfiles(), &["a"]);
+
+        let options = Options::default();
+        let mut planner = fx.planner(&options);
+        planner.unstow(&fx.farm, "a").unwrap();
+        planner
+            .unlink_stale(&fx.farm, "a", &[fx.path("target/.bashrc")])
+            .unwrap();
+        planner.stow(&fx.farm, "a").unwrap();
+
+        assert_eq!(
+            fx.apply(planner),
+            [
+                "UNLINK: target/.bashrc",
+                "LINK: target/dot-bashrc => ../stow/a/dot-bashrc",
+            ]
+        );
+    }
+}
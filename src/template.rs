@@ -0,0 +1,265 @@
+use std::{collections::BTreeMap, sync::OnceLock};
+
+/// The `vars` section of the config, set before the rest of it is parsed
+static VARS: OnceLock<BTreeMap<String, String>> = OnceLock::new();
+
+/// Where to look for the distro's identity, in order
+const OS_RELEASE_PATHS: &[&str] = &["/etc/os-release", "/usr/lib/os-release"];
+
+const BUILTINS: &[&str] = &[
+    "os",
+    "goos",
+    "arch",
+    "goarch",
+    "hostname",
+    "distro",
+    "distro_version",
+    "name",
+    "version",
+    "bare_version",
+    "env.<VAR>",
+];
+
+/// Make user-defined `vars` available to every template, they take precedence over the built-in variables
+pub fn set_vars(vars: BTreeMap<String, String>) {
+    let _ = VARS.set(vars);
+}
+
+/// What a template is being rendered for
+#[derive(Debug, Clone, Copy)]
+pub struct Context<'a> {
+    pub name: &'a str,
+    pub version: Option<&'a str>,
+}
+
+impl Context<'_> {
+    fn lookup(&self, var: &str) -> Result<String, String> {
+        if let Some(value) = VARS.get().and_then(|vars| vars.get(var)) {
+            return Ok(value.clone());
+        }
+
+        if let Some(name) = var.strip_prefix("env.") {
+            return std::env::var(name)
+                .map_err(|_| format!("`{{{{ {var} }}}}` is used but ${name} isn't set"));
+        }
+
+        let version = || {
+            self.version
+                .ok_or_else(|| format!("`{{{{ {var} }}}}` is used but `version` isn't set"))
+        };
+
+        Ok(match var {
+            "os" => std::env::consts::OS.to_string(),
+            "goos" => goos().to_string(),
+            "arch" => std::env::consts::ARCH.to_string(),
+            "goarch" => goarch().to_string(),
+            "hostname" => hostname()?,
+            "distro" => os_release_field("ID")?,
+            "distro_version" => os_release_field("VERSION_ID")?,
+            "name" => self.name.to_string(),
+            "version" => version()?.to_string(),
+            "bare_version" => version()?.trim_start_matches('v').to_string(),
+            _ => {
+                let mut known: Vec<&str> = BUILTINS.to_vec();
+                known.extend(
+                    VARS.get()
+                        .into_iter()
+                        .flat_map(|v| v.keys().map(String::as_str)),
+                );
+                return Err(format!(
+                    "unknown template variable `{var}`, expected one of {}",
+                    known.join(", ")
+                ));
+            }
+        })
+    }
+}
+
+/// Replace every `{{ var }}` in `template`, failing on unknown variables and unterminated tags
+pub fn render(template: &str, cx: Context) -> Result<String, String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+
+        let after = &rest[start + 2..];
+        let end = after
+            .find("}}")
+            .ok_or_else(|| format!("unterminated `{{{{` in `{template}`"))?;
+
+        let var = after[..end].trim();
+        let name = var.strip_prefix("env.").unwrap_or(var);
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("invalid template variable `{var}` in `{template}`"));
+        }
+
+        rendered.push_str(&cx.lookup(var)?);
+        rest = &after[end + 2..];
+    }
+
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// Whether `template` refers to `var`
+pub fn uses(template: &str, var: &str) -> bool {
+    template
+        .split("{{")
+        .skip(1)
+        .filter_map(|tag| tag.split_once("}}"))
+        .any(|(tag, _)| tag.trim() == var)
+}
+
+/// The path and contents of the distro's os-release file, if it has one
+pub fn os_release() -> Option<(&'static str, String)> {
+    OS_RELEASE_PATHS
+        .iter()
+        .find_map(|path| Some((*path, std::fs::read_to_string(path).ok()?)))
+}
+
+/// The value of `key` in an os-release file, with quotes removed
+pub fn os_release_value(contents: &str, key: &str) -> Option<String> {
+    contents
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+        .map(|value| value.trim().trim_matches(['"', '\'']).to_string())
+}
+
+fn os_release_field(key: &str) -> Result<String, String> {
+    let (path, contents) =
+        os_release().ok_or_else(|| String::from("failed to read /etc/os-release"))?;
+    os_release_value(&contents, key).ok_or_else(|| format!("{path} has no `{key}`"))
+}
+
+fn hostname() -> Result<String, String> {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .find_map(|path| std::fs::read_to_string(path).ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| String::from("failed to determine the hostname"))
+}
+
+/// The OS as Go (and so most release tooling) names it
+fn goos() -> &'static str {
+    match std::env::consts::OS {
+        "macos" => "darwin",
+        os => os,
+    }
+}
+
+/// The architecture as Go (and so most release tooling) names it
+fn goarch() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "x86" => "386",
+        "powerpc64" => "ppc64",
+        arch => arch,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CX: Context = Context {
+        name: "tool",
+        version: Some("v1.2.3"),
+    };
+
+    /// `vars` can only be set once, so every test sees the same ones
+    fn set_test_vars() {
+        set_vars(BTreeMap::from([
+            ("greeting".to_string(), "hello".to_string()),
+            ("hostname".to_string(), "overridden".to_string()),
+        ]));
+    }
+
+    #[test]
+    fn renders_builtin_variables() {
+        assert_eq!(
+            render("{{ name }}-{{version}}-{{ bare_version }}", CX).unwrap(),
+            "tool-v1.2.3-1.2.3"
+        );
+        assert_eq!(
+            render("{{ os }}/{{ arch }}", CX).unwrap(),
+            format!("{}/{}", std::env::consts::OS, std::env::consts::ARCH)
+        );
+        assert_eq!(
+            render("{{ goos }}_{{ goarch }}", CX).unwrap(),
+            format!("{}_{}", goos(), goarch())
+        );
+        assert_eq!(render("no tags", CX).unwrap(), "no tags");
+    }
+
+    #[test]
+    fn user_vars_take_precedence_over_builtins() {
+        set_test_vars();
+        assert_eq!(
+            render("{{ greeting }} from {{ hostname }}", CX).unwrap(),
+            "hello from overridden"
+        );
+    }
+
+    #[test]
+    fn reads_environment_variables() {
+        let path = std::env::var("PATH").unwrap();
+        assert_eq!(render("{{ env.PATH }}", CX).unwrap(), path);
+
+        let err = render("{{ env.BOW_TEST_NEVER_SET }}", CX).unwrap_err();
+        assert!(err.contains("$BOW_TEST_NEVER_SET isn't set"), "{err}");
+    }
+
+    #[test]
+    fn rejects_unknown_variables() {
+        set_test_vars();
+        let err = render("{{ nope }}", CX).unwrap_err();
+        assert!(err.contains("unknown template variable `nope`"), "{err}");
+        assert!(err.contains("bare_version"), "{err}");
+        assert!(err.contains("greeting"), "{err}");
+    }
+
+    #[test]
+    fn version_is_required_when_used() {
+        let cx = Context {
+            name: "tool",
+            version: None,
+        };
+        assert_eq!(render("{{ name }}", cx).unwrap(), "tool");
+        let err = render("{{ version }}", cx).unwrap_err();
+        assert!(err.contains("`version` isn't set"), "{err}");
+    }
+
+    #[test]
+    fn rejects_malformed_tags() {
+        assert!(render("{{ name", CX).unwrap_err().contains("unterminated"));
+        assert!(render("{{ }}", CX).unwrap_err().contains("invalid"));
+        assert!(render("{{ na-me }}", CX).unwrap_err().contains("invalid"));
+        assert!(render("{{ env. }}", CX).unwrap_err().contains("invalid"));
+    }
+
+    #[test]
+    fn finds_uses_of_a_variable() {
+        assert!(uses("https://x/{{ version }}/y", "version"));
+        assert!(uses("{{version}}", "version"));
+        assert!(!uses("{{ bare_version }}", "version"));
+        assert!(!uses("version", "version"));
+    }
+
+    #[test]
+    fn reads_os_release_values() {
+        let contents = "NAME=\"Fedora Linux\"\nID=fedora\nVERSION_ID='40'\nID_LIKE=rhel\n";
+        assert_eq!(os_release_value(contents, "ID").as_deref(), Some("fedora"));
+        assert_eq!(
+            os_release_value(contents, "VERSION_ID").as_deref(),
+            Some("40")
+        );
+        assert_eq!(
+            os_release_value(contents, "NAME").as_deref(),
+            Some("Fedora Linux")
+        );
+        assert_eq!(os_release_value(contents, "VERSION"), None);
+    }
+}